@@ -0,0 +1,188 @@
+/// What the machine's sound hardware is doing this frame, from `Machine::sound_state`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoundState {
+    /// The sound timer is running
+    pub active: bool,
+    /// XO-CHIP plays this 1-bit pattern instead of the fixed tone
+    pub pattern: Option<AudioPattern>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioPattern {
+    /// 128 samples, most significant bit of the first byte first
+    pub bits: [u8; 16],
+    /// Pattern bits played per second
+    pub rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SynthSettings {
+    /// Pitch of the tone played when there is no pattern, in Hz
+    pub frequency: f32,
+    /// Peak amplitude, 0 to 1
+    pub volume: f32,
+    /// Seconds to fade in when the sound timer starts
+    pub attack: f32,
+    /// Seconds to fade out when it stops
+    pub release: f32,
+}
+
+impl Default for SynthSettings {
+    fn default() -> SynthSettings {
+        SynthSettings { frequency: 440.0, volume: 0.25, attack: 0.002, release: 0.01 }
+    }
+}
+
+/// One high bit and one low bit, so a tone is just a 2 bit pattern played at twice its frequency.
+const TONE_BITS: [u8; 16] = [0b1000_0000, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Turns `SoundState`s into mono PCM samples.
+///
+/// Edges are smoothed with PolyBLEP so the square wave doesn't alias, and starts and stops go through an
+/// attack/release envelope so they don't click. The waveform phase carries over between calls, so buffers
+/// can be filled in any size. Fill one frame's worth of samples (`sample_rate / 60`) per emulated frame to
+/// follow the sound timer exactly.
+#[derive(Debug, Clone)]
+pub struct Synth {
+    pub settings: SynthSettings,
+    sample_rate: f32,
+    /// Position in the current pattern, in bits
+    phase: f64,
+    envelope: f32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32, settings: SynthSettings) -> Synth {
+        Synth { settings, sample_rate: sample_rate as f32, phase: 0.0, envelope: 0.0 }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    /// Samples needed to cover one 60 Hz frame.
+    pub fn samples_per_frame(&self) -> usize {
+        (self.sample_rate / 60.0).round() as usize
+    }
+
+    pub fn fill_f32(&mut self, sound: &SoundState, out: &mut [f32]) {
+        let (bits, len, rate) = match &sound.pattern {
+            Some(pattern) => (pattern.bits, 128, pattern.rate),
+            None => (TONE_BITS, 2, self.settings.frequency as f64 * 2.0),
+        };
+        //bits advanced per sample. Past one bit per sample, several edges can fall in a sample and only the nearest
+        //ones get smoothed, so those patterns alias, but they still play at the right pitch
+        let step = rate / self.sample_rate as f64;
+        let attack_step = 1.0 / (self.settings.attack * self.sample_rate).max(1.0);
+        let release_step = 1.0 / (self.settings.release * self.sample_rate).max(1.0);
+        let level = |index: usize| {
+            let index = index % len;
+            if bits[index / 8] >> (7 - index % 8) & 1 != 0 { 1.0 } else { -1.0 }
+        };
+
+        for sample in out.iter_mut() {
+            self.envelope = if sound.active {
+                (self.envelope + attack_step).min(1.0)
+            } else {
+                (self.envelope - release_step).max(0.0)
+            };
+            if self.envelope == 0.0 {
+                *sample = 0.0;
+                continue;
+            }
+            let index = self.phase as usize;
+            let t = self.phase.fract();
+            let current = level(index);
+            let mut value = current;
+            //smooth the edge at the start of this bit and the one coming up at its end
+            value += (current - level(index + len - 1)) / 2.0 * poly_blep(t, step);
+            value += (level(index + 1) - current) / 2.0 * poly_blep(t - 1.0, step);
+            *sample = value as f32 * self.envelope * self.settings.volume;
+            self.phase = (self.phase + step) % len as f64;
+        }
+    }
+
+    pub fn fill_i16(&mut self, sound: &SoundState, out: &mut [i16]) {
+        let mut buf = vec![0.0; out.len()];
+        self.fill_f32(sound, &mut buf);
+        for (sample, value) in out.iter_mut().zip(buf) {
+            *sample = (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        }
+    }
+}
+
+/// Correction for a unit step at t = 0, for `t` in bits relative to the step and `dt` bits per sample.
+fn poly_blep(t: f64, dt: f64) -> f64 {
+    if (0.0..dt).contains(&t) {
+        let x = t / dt;
+        return x + x - x * x - 1.0;
+    }
+    if (-dt..0.0).contains(&t) {
+        let x = t / dt;
+        return x * x + x + x + 1.0;
+    }
+    return 0.0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// No fades, full volume.
+    fn synth(sample_rate: u32) -> Synth {
+        Synth::new(sample_rate, SynthSettings { frequency: 440.0, volume: 1.0, attack: 0.0, release: 0.0 })
+    }
+
+    fn pattern(bits: [u8; 16], rate: f64) -> SoundState {
+        SoundState { active: true, pattern: Some(AudioPattern { bits, rate }) }
+    }
+
+    #[test]
+    fn a_frame_is_a_sixtieth_of_a_second() {
+        assert_eq!(synth(48000).samples_per_frame(), 800);
+        assert_eq!(synth(44100).samples_per_frame(), 735);
+        assert_eq!(synth(22050).samples_per_frame(), 368);
+    }
+
+    #[test]
+    fn inactive_sound_is_silent() {
+        let mut synth = Synth::new(44100, SynthSettings::default());
+        let silent = SoundState { active: false, pattern: None };
+        let mut out = vec![1.0; 735];
+        synth.fill_f32(&silent, &mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+
+        //after a beep, the release fades back to silence
+        synth.fill_f32(&SoundState { active: true, pattern: None }, &mut out);
+        assert!(out.iter().any(|&s| s != 0.0));
+        synth.fill_f32(&silent, &mut out);
+        assert_eq!(out[out.len() - 1], 0.0);
+        let mut out = vec![1i16; 100];
+        synth.fill_i16(&silent, &mut out);
+        assert!(out.iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn patterns_play_most_significant_bit_first() {
+        let mut bits = [0; 16];
+        bits[0] = 0b1010_0000;
+        bits[1] = 0b0000_0001;
+        //8 samples per bit, so the middle of each bit is clear of the edge smoothing
+        let mut synth = synth(8000);
+        let mut out = vec![0.0; 16 * 8];
+        synth.fill_f32(&pattern(bits, 1000.0), &mut out);
+        let high: Vec<usize> = (0..16).filter(|bit| out[bit * 8 + 4] > 0.0).collect();
+        assert_eq!(high, [0, 2, 15]);
+        assert!((0..16).all(|bit| (out[bit * 8 + 4].abs() - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn fast_patterns_keep_their_pitch() {
+        for rate in [0.75, 1.5, 3.0] {
+            let mut synth = synth(22050);
+            let mut out = vec![0.0; 100];
+            synth.fill_f32(&pattern([0x55; 16], 22050.0 * rate), &mut out);
+            assert!((synth.phase - (100.0 * rate) % 128.0).abs() < 1e-6, "rate {}", rate);
+        }
+    }
+}
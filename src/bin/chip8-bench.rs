@@ -0,0 +1,67 @@
+//! Runs a ROM headless as fast as possible and reports instructions per second,
+//! once with the decode cache off and once with it on.
+//!
+//! Usage: chip8-bench <rom> [cycles] [config.json]
+
+use std::{env, fs, process, time::Instant};
+
+use emu_chip8_core::{config::Chip8Config, machine::Machine};
+
+const DEFAULT_CYCLES: u64 = 10_000_000;
+
+fn bench(program: &[u8], mut config: Chip8Config, cycles: u64) -> Result<(u64, f64), String> {
+    //a fixed seed so both runs execute the same instructions
+    config.rng_seed = Some(config.rng_seed.unwrap_or(0));
+    let mut machine = Machine::new(program, config).map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    while machine.cycle_count() < cycles && !machine.has_exited() {
+        if let Err(err) = machine.step_instruction() {
+            eprintln!("stopped early: {}", err);
+            break;
+        }
+    }
+    return Ok((machine.cycle_count(), start.elapsed().as_secs_f64()));
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <rom> [cycles] [config.json]", args[0]);
+        process::exit(2);
+    }
+    let program = fs::read(&args[1]).unwrap_or_else(|e| {
+        eprintln!("couldn't read {}: {}", args[1], e);
+        process::exit(1);
+    });
+    let cycles = match args.get(2) {
+        Some(s) => s.parse().unwrap_or_else(|_| {
+            eprintln!("cycles must be a number, got {}", s);
+            process::exit(2);
+        }),
+        None => DEFAULT_CYCLES,
+    };
+    let config = match args.get(3) {
+        Some(path) => Chip8Config::from_file(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        }),
+        None => Chip8Config::default(),
+    };
+
+    let mut results = Vec::new();
+    for decode_cache in [false, true] {
+        let config = Chip8Config { decode_cache, ..config.clone() };
+        match bench(&program, config, cycles) {
+            Ok((ran, secs)) => {
+                let ips = ran as f64 / secs;
+                println!("decode cache {:<3}: {} cycles in {:.3}s, {:.0} instr/s", if decode_cache { "on" } else { "off" }, ran, secs, ips);
+                results.push(ips);
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+    println!("speedup: {:.2}x", results[1] / results[0]);
+}
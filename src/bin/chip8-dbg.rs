@@ -0,0 +1,431 @@
+//! An interactive, gdb-like debugger for the terminal.
+//!
+//! Usage: chip8-dbg [--profile NAME] [--config FILE] <rom>
+//!
+//! Type `help` at the prompt for the commands. An empty line repeats the last command. Numbers are
+//! decimal or 0x-prefixed hex; conditions and values can be expressions like `V3 + 1` or `mem[I] & 0x80`.
+//! Ctrl-C stops a running program and goes back to the prompt.
+
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use emu_chip8_core::{
+    breakpoints::{BreakpointHit, BreakpointKind, Condition},
+    config::Chip8Config,
+    decode::{Instruction, OpcodePattern},
+    disassembler::disassemble_memory_at,
+    machine::Machine,
+};
+
+const HELP: &str = "\
+s, step [N]               run N instructions (default 1)
+n, next                   like step, but runs a CALL until it returns
+finish                    run until the current subroutine returns
+c, continue [FRAMES]      run until a breakpoint, fault or exit, or for FRAMES frames
+b, break ADDR [if COND]   stop before the instruction at ADDR
+break opcode PAT [if COND]  stop before opcodes matching PAT, e.g. Dxyn or Fx0A
+break if COND             stop before any instruction while COND holds
+watch START[-END] [if COND]   stop before writes to the range
+rwatch START[-END] [if COND]  stop before reads from the range
+d, delete [ID]            delete a breakpoint, or all of them
+enable ID, disable ID
+info breakpoints          list breakpoints
+r, regs                   show the registers
+p, print EXPR             evaluate an expression
+x ADDR [LEN]              hex dump memory
+disas [ADDR] [COUNT]      disassemble, around the PC by default
+set REG = EXPR            set V0-VF, I, PC, SP, DT or ST
+set mem[ADDR] = EXPR      set a byte of memory
+bt, backtrace             show the call stack
+display                   show the screen as text
+press KEY, release KEY    hold or let go of a key (0-F)
+profile start|stop        count where cycles go while running
+profile report [N]        show the N busiest addresses and subroutines (default 10)
+q, quit
+
+Ctrl-C stops step, next, finish and continue at the next cycle.";
+
+/// Set by the SIGINT handler, checked every cycle while running.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn install_interrupt_handler() {
+    extern "C" fn on_sigint(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    //SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_interrupt_handler() {}
+
+/// Why a run command gave control back.
+enum Stop {
+    Done,
+    Breakpoint(BreakpointHit),
+    Exited,
+    Fault(String),
+    Interrupted,
+}
+
+struct Debugger {
+    machine: Machine,
+}
+
+fn parse_num(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    return parsed.map_err(|_| format!("expected a number, got '{}'", s));
+}
+
+fn parse_addr(s: &str) -> Result<u16, String> {
+    let addr = parse_num(s)?;
+    return u16::try_from(addr).map_err(|_| format!("address {:X} doesn't fit in 16 bits", addr));
+}
+
+fn parse_key(s: &str) -> Result<u8, String> {
+    match u8::from_str_radix(s, 16) {
+        Ok(key) if key < 0x10 => Ok(key),
+        _ => Err(format!("expected a key from 0 to F, got '{}'", s)),
+    }
+}
+
+/// Splits "SPEC if COND" into its parts.
+fn split_condition(rest: &str) -> (&str, Option<&str>) {
+    if let Some(cond) = rest.strip_prefix("if ") {
+        return ("", Some(cond));
+    }
+    match rest.split_once(" if ") {
+        Some((spec, cond)) => (spec.trim(), Some(cond)),
+        None => (rest.trim(), None),
+    }
+}
+
+fn parse_range(s: &str) -> Result<(u16, u16), String> {
+    match s.split_once('-') {
+        Some((start, end)) => Ok((parse_addr(start.trim())?, parse_addr(end.trim())?)),
+        None => {
+            let addr = parse_addr(s)?;
+            Ok((addr, addr))
+        }
+    }
+}
+
+impl Debugger {
+    fn location(&self) -> String {
+        let cpu = self.machine.cpu_state();
+        let (text, _) = disassemble_memory_at(&cpu.mem, cpu.pc as usize);
+        return format!("-> {:03X}: {}", cpu.pc, text);
+    }
+
+    /// Runs the current instruction to completion, ignoring breakpoints. A DRW waiting for vblank or an Fx0A
+    /// waiting for a key takes several cycles, or forever if no key comes, so this stops on Ctrl-C too.
+    fn step_off(&mut self) -> Stop {
+        self.machine.clear_fault();
+        while !self.machine.has_exited() {
+            if INTERRUPTED.swap(false, Ordering::SeqCst) {
+                return Stop::Interrupted;
+            }
+            match self.machine.step_instruction() {
+                Ok(true) => return Stop::Done,
+                Ok(false) => {}
+                Err(err) => return Stop::Fault(err.to_string()),
+            }
+        }
+        return Stop::Exited;
+    }
+
+    /// Runs until `done` is true, a breakpoint hits, the machine exits or faults, or Ctrl-C is pressed.
+    fn resume(&mut self, done: &dyn Fn(&Machine) -> bool) -> Stop {
+        //a Ctrl-C pressed at the prompt isn't meant for this run
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        //step off the current instruction first, so a breakpoint on it doesn't stop us straight away
+        match self.step_off() {
+            Stop::Done => {}
+            stop => return stop,
+        }
+        loop {
+            if self.machine.has_exited() {
+                return Stop::Exited;
+            }
+            if done(&self.machine) {
+                return Stop::Done;
+            }
+            if INTERRUPTED.swap(false, Ordering::SeqCst) {
+                return Stop::Interrupted;
+            }
+            match self.machine.run_cycles(1) {
+                Ok(Some(hit)) => return Stop::Breakpoint(hit),
+                Ok(None) => {}
+                Err(err) => return Stop::Fault(err.to_string()),
+            }
+        }
+    }
+
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint(hit) => println!("Breakpoint {}, {}", hit.id, hit.kind),
+            Stop::Exited => {
+                println!("Program exited");
+                return;
+            }
+            Stop::Fault(err) => println!("Fault: {}", err),
+            Stop::Interrupted => println!("Interrupted"),
+        }
+        println!("{}", self.location());
+    }
+
+    fn add_breakpoint(&mut self, kind: BreakpointKind, condition: Option<&str>) -> Result<(), String> {
+        let id = self.machine.breakpoints_mut().add(kind, condition)?;
+        println!("Breakpoint {}, {}", id, kind);
+        return Ok(());
+    }
+
+    fn eval(&self, expr: &str) -> Result<i64, String> {
+        return Ok(Condition::parse(expr)?.eval(self.machine.cpu_state()));
+    }
+
+    fn set(&mut self, target: &str, value: i64) -> Result<(), String> {
+        let upper = target.to_ascii_uppercase();
+        let cpu = self.machine.cpu_state_mut();
+        if let Some(addr) = upper.strip_prefix("MEM[").and_then(|rest| rest.strip_suffix(']')) {
+            let addr = parse_num(addr)? as usize;
+            return cpu.mem.write(addr, value as u8).map_err(|addr| format!("address {:X} is out of memory", addr));
+        }
+        match upper.as_str() {
+            "I" => cpu.i = value as u16,
+            "PC" => cpu.pc = value as u16,
+            "SP" => cpu.sp = value as u8,
+            "DT" => cpu.dt = value as u8,
+            "ST" => cpu.st = value as u8,
+            reg if reg.len() == 2 && reg.starts_with('V') => cpu.v[parse_key(&reg[1..])? as usize] = value as u8,
+            _ => return Err(format!("unknown register '{}'", target)),
+        }
+        return Ok(());
+    }
+
+    /// Runs one command. Returns Ok(false) to quit.
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        match (cmd, args.as_slice()) {
+            ("q" | "quit", _) => return Ok(false),
+            ("help" | "h", _) => println!("{}", HELP),
+            ("s" | "step", _) => {
+                let n = args.first().map(|n| parse_num(n)).transpose()?.unwrap_or(1);
+                INTERRUPTED.store(false, Ordering::SeqCst);
+                for _ in 0..n {
+                    match self.step_off() {
+                        Stop::Done => {}
+                        stop => {
+                            self.report(stop);
+                            return Ok(true);
+                        }
+                    }
+                }
+                if n == 1 {
+                    println!("{}", self.machine.debug_state());
+                } else {
+                    println!("{}", self.location());
+                }
+            }
+            ("n" | "next", _) => {
+                let cpu = self.machine.cpu_state();
+                let stop = match cpu.fetch() {
+                    Ok(Instruction::Call(_)) => {
+                        let (ret, depth) = (cpu.pc.wrapping_add(2), cpu.stack_len());
+                        self.resume(&|m| m.cpu_state().pc == ret && m.cpu_state().stack_len() == depth)
+                    }
+                    _ => self.resume(&|_| true),
+                };
+                self.report(stop);
+            }
+            ("finish", _) => {
+                let depth = self.machine.cpu_state().stack_len();
+                if depth == 0 {
+                    return Err("not in a subroutine".into());
+                }
+                let stop = self.resume(&|m| m.cpu_state().stack_len() < depth);
+                self.report(stop);
+            }
+            ("c" | "continue", _) => {
+                let stop = match args.first() {
+                    Some(frames) => {
+                        let until = self.machine.frame_count() + parse_num(frames)?;
+                        self.resume(&|m| m.frame_count() >= until)
+                    }
+                    None => self.resume(&|_| false),
+                };
+                self.report(stop);
+            }
+            ("b" | "break", _) => {
+                let (spec, condition) = split_condition(rest);
+                let kind = match spec.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                    [] if condition.is_some() => BreakpointKind::Always,
+                    ["opcode", pattern] => BreakpointKind::Opcode(OpcodePattern::parse(pattern)?),
+                    [addr] => BreakpointKind::Pc(parse_addr(addr)?),
+                    _ => return Err("usage: break ADDR | break opcode PATTERN | break if COND".into()),
+                };
+                self.add_breakpoint(kind, condition)?;
+            }
+            ("watch" | "rwatch", _) => {
+                let (spec, condition) = split_condition(rest);
+                let (start, end) = parse_range(spec)?;
+                let kind = if cmd == "watch" {
+                    BreakpointKind::Write { start, end }
+                } else {
+                    BreakpointKind::Read { start, end }
+                };
+                self.add_breakpoint(kind, condition)?;
+            }
+            ("d" | "delete", []) => self.machine.breakpoints_mut().clear(),
+            ("d" | "delete", [id]) => {
+                if !self.machine.breakpoints_mut().remove(parse_num(id)? as usize) {
+                    return Err(format!("no breakpoint {}", id));
+                }
+            }
+            ("enable" | "disable", [id]) => {
+                if !self.machine.breakpoints_mut().set_enabled(parse_num(id)? as usize, cmd == "enable") {
+                    return Err(format!("no breakpoint {}", id));
+                }
+            }
+            ("info" | "i", ["breakpoints" | "b"]) => {
+                let list = self.machine.breakpoints().list();
+                if list.is_empty() {
+                    println!("No breakpoints");
+                }
+                for bp in list {
+                    let condition = bp.condition.as_ref().map(|c| format!(" if {}", c)).unwrap_or_default();
+                    let disabled = if bp.enabled { "" } else { " (disabled)" };
+                    println!("{:<3} {}{}{}", bp.id, bp.kind, condition, disabled);
+                }
+            }
+            ("r" | "regs", _) | ("info" | "i", ["registers" | "r"]) => {
+                println!("{}", self.machine.cpu_state().debug_state());
+            }
+            ("p" | "print", _) => {
+                let value = self.eval(rest)?;
+                println!("{} = {:#X}", value, value);
+            }
+            ("x", [addr, len @ ..]) => {
+                let start = parse_num(addr)? as usize;
+                let len = len.first().map(|len| parse_num(len)).transpose()?.unwrap_or(64) as usize;
+                let bytes = self.machine.cpu_state().mem
+                    .read_range(start, len)
+                    .map_err(|addr| format!("address {:X} is out of memory", addr))?;
+                for (row, chunk) in bytes.chunks(16).enumerate() {
+                    let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                    println!("{:04X}: {}", start + row * 16, hex.join(" "));
+                }
+            }
+            ("disas", _) => {
+                let cpu = self.machine.cpu_state();
+                let mut addr = match args.first() {
+                    Some(addr) => parse_num(addr)? as usize,
+                    None => (cpu.pc as usize).saturating_sub(8),
+                };
+                let count = args.get(1).map(|n| parse_num(n)).transpose()?.unwrap_or(10);
+                for _ in 0..count {
+                    let (text, size) = disassemble_memory_at(&cpu.mem, addr);
+                    let marker = if addr == cpu.pc as usize { "->" } else { "  " };
+                    println!("{} {:03X}: {}", marker, addr, text);
+                    addr += size;
+                }
+            }
+            ("set", _) => {
+                let (target, value) = rest.split_once('=').ok_or("usage: set REG = EXPR")?;
+                let value = self.eval(value)?;
+                self.set(target.trim(), value)?;
+            }
+            ("bt" | "backtrace", _) => {
+                let stack = self.machine.call_stack();
+                if stack.is_empty() {
+                    println!("Not in a subroutine");
+                }
+                //innermost first, like gdb
+                for (frame, ret) in stack.iter().rev().enumerate() {
+                    println!("#{} returns to {:03X}", frame, ret);
+                }
+            }
+            ("display", _) => self.machine.display_data().debug_print(),
+            ("profile", ["start"]) => self.machine.start_profiling(),
+            ("profile", ["stop"]) => {
+                self.machine.stop_profiling();
+            }
+            ("profile", ["report", top @ ..]) => {
+                let top = top.first().map(|n| parse_num(n)).transpose()?.unwrap_or(10) as usize;
+                let report = self.machine.profile_report(top).ok_or("not profiling, try 'profile start'")?;
+                print!("{}", report);
+            }
+            ("press", [key]) => self.machine.press_key(parse_key(key)?),
+            ("release", [key]) => self.machine.release_key(parse_key(key)?),
+            _ => return Err(format!("don't know how to '{}', try 'help'", line)),
+        }
+        return Ok(true);
+    }
+}
+
+fn fail_usage(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    eprintln!("usage: chip8-dbg [--profile NAME] [--config FILE] <rom>");
+    process::exit(2);
+}
+
+fn main() {
+    let mut config = Chip8Config::default();
+    let mut rom_path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => {
+                let name = args.next().unwrap_or_else(|| fail_usage("--profile needs a name"));
+                config.apply_profile(name.parse().unwrap_or_else(|e: String| fail_usage(&e)));
+            }
+            "--config" => {
+                let path = args.next().unwrap_or_else(|| fail_usage("--config needs a file"));
+                config = Chip8Config::from_file(&path).unwrap_or_else(|e| fail_usage(&e));
+            }
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| fail_usage("expected a ROM"));
+    let program = fs::read(&rom_path).unwrap_or_else(|e| fail_usage(&format!("couldn't read {}: {}", rom_path, e)));
+    let machine = Machine::new(&program, config).unwrap_or_else(|e| fail_usage(&e.to_string()));
+    let mut debugger = Debugger { machine };
+    install_interrupt_handler();
+    println!("{}", debugger.location());
+
+    let mut last_command = String::new();
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("(chip8) ");
+        let _ = io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        let line = line.trim();
+        if !line.is_empty() {
+            last_command = line.to_string();
+        }
+        if last_command.is_empty() {
+            continue;
+        }
+        match debugger.command(&last_command.clone()) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => println!("{}", err),
+        }
+    }
+}
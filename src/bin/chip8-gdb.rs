@@ -0,0 +1,70 @@
+//! Serves a ROM to GDB over the remote serial protocol.
+//!
+//! Usage: chip8-gdb [--profile NAME] [--config FILE] [--port PORT | --stdio] [--fast] <rom>
+//!
+//! Listens on 127.0.0.1:1234 by default and serves one client, e.g. `target remote :1234`. With --stdio
+//! the protocol runs over stdin and stdout instead, for `target remote | chip8-gdb --stdio rom.ch8`.
+//! With --fast, `continue` runs as fast as possible instead of at 60 frames per second.
+
+use std::{env, fs, io, net::TcpListener, process};
+
+use emu_chip8_core::{config::Chip8Config, gdb::GdbStub, machine::Machine};
+
+const DEFAULT_PORT: u16 = 1234;
+
+fn fail_usage(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    eprintln!("usage: chip8-gdb [--profile NAME] [--config FILE] [--port PORT | --stdio] [--fast] <rom>");
+    process::exit(2);
+}
+
+fn main() {
+    let mut config = Chip8Config::default();
+    let mut port = DEFAULT_PORT;
+    let mut stdio = false;
+    let mut fast = false;
+    let mut rom_path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => {
+                let name = args.next().unwrap_or_else(|| fail_usage("--profile needs a name"));
+                config.apply_profile(name.parse().unwrap_or_else(|e: String| fail_usage(&e)));
+            }
+            "--config" => {
+                let path = args.next().unwrap_or_else(|| fail_usage("--config needs a file"));
+                config = Chip8Config::from_file(&path).unwrap_or_else(|e| fail_usage(&e));
+            }
+            "--port" => {
+                let value = args.next().unwrap_or_else(|| fail_usage("--port needs a number"));
+                port = value.parse().unwrap_or_else(|_| fail_usage(&format!("bad port '{}'", value)));
+            }
+            "--stdio" => stdio = true,
+            "--fast" => fast = true,
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| fail_usage("expected a ROM"));
+    let program = fs::read(&rom_path).unwrap_or_else(|e| fail_usage(&format!("couldn't read {}: {}", rom_path, e)));
+    let machine = Machine::new(&program, config).unwrap_or_else(|e| fail_usage(&e.to_string()));
+    let mut stub = GdbStub::new(machine);
+    stub.set_realtime(!fast);
+
+    let result = if stdio {
+        stub.serve(io::stdin(), io::stdout())
+    } else {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .unwrap_or_else(|e| fail_usage(&format!("couldn't listen on port {}: {}", port, e)));
+        eprintln!("waiting for GDB on 127.0.0.1:{}", port);
+        listener.accept().and_then(|(stream, addr)| {
+            eprintln!("connected to {}", addr);
+            //packets are small and answered one at a time, so don't let Nagle hold them back
+            stream.set_nodelay(true)?;
+            stub.serve(stream.try_clone()?, stream)
+        })
+    };
+    if let Err(err) = result {
+        eprintln!("connection error: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,207 @@
+//! Runs a ROM without a window, driven by a test script.
+//!
+//! Usage: chip8-headless [--profile NAME] [--config FILE] [--update-snapshots] [--trace FILE] <rom> <script>
+//!
+//! One step per line, `#` starts a comment. Numbers are decimal or 0x-prefixed hex, keys are hex digits.
+//!
+//!     run 120 frames
+//!     run 500 cycles
+//!     press key 5 for 3 frames
+//!     press key 5
+//!     release key 5
+//!     assert pixel (10,4) set
+//!     assert pixel (10,5) clear
+//!     assert V3 == 0x20
+//!     assert I != 0
+//!     assert mem[0x300] == 7
+//!     assert display matches title.txt
+//!     dump memory 0x200 32
+//!
+//! Registers are V0-VF, I, PC, SP, DT and ST. Snapshot files hold one line per row, `.` for an unlit pixel
+//! and `#`, `+` or `@` for colors 1 to 3. Paths are relative to the script. With --update-snapshots,
+//! snapshot assertions write the current display instead of comparing. With --trace, every instruction
+//! run is logged to FILE.
+//!
+//! Exits with 1 if an assertion failed, 2 if the script or ROM couldn't be run.
+
+use std::{env, fs, io::BufWriter, path::{Path, PathBuf}, process};
+
+use emu_chip8_core::{
+    machine::Machine,
+    script::{self, Options, Step, Value},
+    trace::{TraceFilter, TraceFormat, Tracer},
+};
+
+struct Runner {
+    machine: Machine,
+    script_dir: PathBuf,
+    update_snapshots: bool,
+    failures: usize,
+}
+
+fn display_text(machine: &Machine) -> String {
+    let disp = machine.display_data();
+    let mut s = String::new();
+    for y in 0..disp.height {
+        for x in 0..disp.width {
+            s.push(match disp.get_pixel_color(x, y) {
+                0 => '.',
+                1 => '#',
+                2 => '+',
+                _ => '@',
+            });
+        }
+        s.push('\n');
+    }
+    return s;
+}
+
+impl Runner {
+    fn run_frames(&mut self, frames: u64) -> Result<(), String> {
+        for _ in 0..frames {
+            if self.machine.has_exited() {
+                break;
+            }
+            self.machine.step_frame().map_err(|e| e.to_string())?;
+        }
+        return Ok(());
+    }
+
+    fn read_value(&self, value: Value) -> Result<u64, String> {
+        let cpu = self.machine.cpu_state();
+        let value = match value {
+            Value::V(reg) => cpu.v[reg as usize] as u64,
+            Value::I => cpu.i as u64,
+            Value::Pc => cpu.pc as u64,
+            Value::Sp => cpu.sp as u64,
+            Value::Dt => cpu.dt as u64,
+            Value::St => cpu.st as u64,
+            Value::Mem(addr) => {
+                return cpu.mem.read(addr).map(u64::from).map_err(|addr| format!("address {:X} is out of memory", addr));
+            }
+        };
+        return Ok(value);
+    }
+
+    /// Runs one script step. Returns Ok(false) if it was an assertion that failed.
+    fn step(&mut self, step: &Step) -> Result<bool, String> {
+        match step {
+            Step::RunFrames(frames) => self.run_frames(*frames)?,
+            Step::RunCycles(cycles) => {
+                for _ in 0..*cycles {
+                    if self.machine.has_exited() {
+                        break;
+                    }
+                    self.machine.step_instruction().map_err(|e| e.to_string())?;
+                }
+            }
+            Step::PressKeyFor { key, frames } => {
+                self.machine.press_key(*key);
+                self.run_frames(*frames)?;
+                self.machine.release_key(*key);
+            }
+            Step::PressKey(key) => self.machine.press_key(*key),
+            Step::ReleaseKey(key) => self.machine.release_key(*key),
+            Step::AssertPixel { x, y, set: expect_set } => {
+                let (x, y) = (*x, *y);
+                let disp = self.machine.display_data();
+                if x >= disp.width || y >= disp.height {
+                    return Err(format!("pixel ({},{}) is off the {}x{} screen", x, y, disp.width, disp.height));
+                }
+                let set = disp.get_pixel(x, y);
+                if set != *expect_set {
+                    println!("  pixel ({},{}) is {}", x, y, if set { "set" } else { "clear" });
+                    return Ok(false);
+                }
+            }
+            Step::AssertDisplay(file) => {
+                let path = self.script_dir.join(file);
+                let current = display_text(&self.machine);
+                if self.update_snapshots {
+                    fs::write(&path, &current).map_err(|e| format!("couldn't write {}: {}", path.display(), e))?;
+                    return Ok(true);
+                }
+                let expected = fs::read_to_string(&path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+                if expected.trim_end() != current.trim_end() {
+                    println!("  display doesn't match {}, it is:\n{}", path.display(), current);
+                    return Ok(false);
+                }
+            }
+            Step::AssertValue { value, equal, expected } => {
+                let actual = self.read_value(*value)?;
+                if (actual == *expected) != *equal {
+                    println!("  {} is {:#X}", value, actual);
+                    return Ok(false);
+                }
+            }
+            Step::DumpMemory { start, len } => {
+                let bytes = self.machine.cpu_state().mem
+                    .read_range(*start, *len)
+                    .map_err(|addr| format!("address {:X} is out of memory", addr))?;
+                for (row, chunk) in bytes.chunks(16).enumerate() {
+                    let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                    println!("{:04X}: {}", start + row * 16, hex.join(" "));
+                }
+            }
+        }
+        return Ok(true);
+    }
+}
+
+fn fail_usage(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    eprintln!("usage: chip8-headless [--profile NAME] [--config FILE] [--update-snapshots] [--trace FILE] <rom> <script>");
+    process::exit(2);
+}
+
+fn main() {
+    let Options { mut config, update_snapshots, trace_path, rom_path, script_path } =
+        Options::parse(env::args().skip(1)).unwrap_or_else(|e| fail_usage(&e));
+    //fix the seed so RND gives the same results on every run
+    config.rng_seed.get_or_insert(0);
+
+    let program = fs::read(&rom_path)
+        .unwrap_or_else(|e| fail_usage(&format!("couldn't read {}: {}", rom_path.display(), e)));
+    let script = fs::read_to_string(&script_path)
+        .unwrap_or_else(|e| fail_usage(&format!("couldn't read {}: {}", script_path.display(), e)));
+    let steps = script::parse_script(&script).unwrap_or_else(|(line_no, err)| {
+        eprintln!("{}:{}: {}", script_path.display(), line_no, err);
+        process::exit(2);
+    });
+    let mut machine = Machine::new(&program, config).unwrap_or_else(|e| fail_usage(&e.to_string()));
+    if let Some(path) = &trace_path {
+        let file = fs::File::create(path)
+            .unwrap_or_else(|e| fail_usage(&format!("couldn't create {}: {}", path.display(), e)));
+        machine.start_trace(Tracer::new(Box::new(BufWriter::new(file)), TraceFormat::default(), TraceFilter::default()));
+    }
+    let mut runner = Runner {
+        machine,
+        script_dir: script_path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        update_snapshots,
+        failures: 0,
+    };
+
+    let lines: Vec<&str> = script.lines().collect();
+    for (line_no, step) in &steps {
+        let line = lines[line_no - 1].split('#').next().unwrap_or("").trim();
+        match runner.step(step) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("{}:{}: FAILED: {}", script_path.display(), line_no, line);
+                runner.failures += 1;
+            }
+            Err(err) => {
+                eprintln!("{}:{}: {}", script_path.display(), line_no, err);
+                process::exit(2);
+            }
+        }
+    }
+    if let Err(err) = runner.machine.stop_trace() {
+        eprintln!("couldn't write the trace: {}", err);
+    }
+    if runner.failures > 0 {
+        println!("{} assertion(s) failed", runner.failures);
+        process::exit(1);
+    }
+    println!("ok");
+}
@@ -0,0 +1,464 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::config::{InstructionSet, StackLayout};
+use crate::cpu::CPUState;
+use crate::decode::{Instruction, OpcodePattern};
+
+/// What a breakpoint watches for. Address ranges are inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// The instruction at this address is about to run
+    Pc(u16),
+    /// The next instruction reads a byte in the range, not counting the fetch itself
+    Read { start: u16, end: u16 },
+    /// The next instruction writes a byte in the range
+    Write { start: u16, end: u16 },
+    /// The next instruction's opcode matches the pattern
+    Opcode(OpcodePattern),
+    /// Every instruction, so the breakpoint hits whenever its condition holds
+    Always,
+}
+
+impl fmt::Display for BreakpointKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakpointKind::Pc(addr) => write!(f, "pc {:03X}", addr),
+            BreakpointKind::Read { start, end } => write!(f, "read {:03X}-{:03X}", start, end),
+            BreakpointKind::Write { start, end } => write!(f, "write {:03X}-{:03X}", start, end),
+            BreakpointKind::Opcode(pattern) => write!(f, "opcode {}", pattern),
+            BreakpointKind::Always => write!(f, "always"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub kind: BreakpointKind,
+    /// Only hits while this evaluates to nonzero
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+}
+
+/// Which breakpoint stopped the machine, and the instruction it stopped before.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakpointHit {
+    pub id: usize,
+    pub kind: BreakpointKind,
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_id: usize,
+    /// PC of the instruction the machine last stopped before. Resuming runs it once without stopping again.
+    resume_pc: Option<u16>,
+}
+
+impl Breakpoints {
+    /// Adds a breakpoint, with an optional condition like `V3 == 0x20 && mem[I] != 0`. Returns its id.
+    pub fn add(&mut self, kind: BreakpointKind, condition: Option<&str>) -> Result<usize, String> {
+        let condition = condition.map(Condition::parse).transpose()?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Breakpoint { id, kind, condition, enabled: true });
+        return Ok(id);
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|bp| bp.id != id);
+        return self.list.len() != len;
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        match self.list.iter_mut().find(|bp| bp.id == id) {
+            Some(bp) => {
+                bp.enabled = enabled;
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// The first enabled breakpoint that `instr`, about to run at `cpu.pc`, hits.
+    pub fn find_hit(&self, cpu: &CPUState, instr: Instruction) -> Option<BreakpointHit> {
+        let opcode = instr.encode();
+        let (reads, writes) = memory_accesses(cpu, instr);
+        let overlaps = |range: &Option<Range<usize>>, start: u16, end: u16| {
+            range.as_ref().is_some_and(|r| r.start <= end as usize && (start as usize) < r.end)
+        };
+        let bp = self.list.iter().find(|bp| {
+            let triggered = match bp.kind {
+                BreakpointKind::Pc(addr) => cpu.pc == addr,
+                BreakpointKind::Read { start, end } => overlaps(&reads, start, end),
+                BreakpointKind::Write { start, end } => overlaps(&writes, start, end),
+                BreakpointKind::Opcode(pattern) => pattern.matches(opcode),
+                BreakpointKind::Always => true,
+            };
+            bp.enabled && triggered && bp.condition.as_ref().is_none_or(|c| c.eval(cpu) != 0)
+        })?;
+        return Some(BreakpointHit { id: bp.id, kind: bp.kind, pc: cpu.pc, opcode });
+    }
+
+    /// `find_hit`, except right after stopping, when the instruction stopped on gets to run.
+    pub(crate) fn check(&mut self, cpu: &CPUState, instr: Instruction) -> Option<BreakpointHit> {
+        if self.resume_pc.take() == Some(cpu.pc) {
+            return None;
+        }
+        let hit = self.find_hit(cpu, instr)?;
+        self.resume_pc = Some(cpu.pc);
+        return Some(hit);
+    }
+
+    /// Called once an instruction ran without being checked, e.g. by single stepping.
+    pub(crate) fn stepped(&mut self) {
+        self.resume_pc = None;
+    }
+}
+
+/// Bytes `instr` will read and write when it runs, besides fetching itself.
+fn memory_accesses(cpu: &CPUState, instr: Instruction) -> (Option<Range<usize>>, Option<Range<usize>>) {
+    let i = cpu.i as usize;
+    let from_i = |len: usize| Some(i..i + len);
+    let range_len = |x: u8, y: u8| x.abs_diff(y) as usize + 1;
+    let in_memory_stack = cpu.config.stack_layout == StackLayout::InMemory;
+    match instr {
+        Instruction::Draw { n, .. } => {
+            let wide = n == 0 && cpu.config.instruction_set >= InstructionSet::SuperChip;
+            let len = if wide { 32 } else { n as usize } * cpu.disp.selected_plane_count();
+            return (from_i(len), None);
+        }
+        Instruction::Bcd { .. } => return (None, from_i(3)),
+        Instruction::Store { x } => return (None, from_i(x as usize + 1)),
+        Instruction::Load { x } => return (from_i(x as usize + 1), None),
+        Instruction::SaveRange { x, y } => return (None, from_i(range_len(x, y))),
+        Instruction::LoadRange { x, y } => return (from_i(range_len(x, y)), None),
+        Instruction::Audio => return (from_i(cpu.audio_pattern.len()), None),
+        Instruction::Call(_) if in_memory_stack => {
+            let sp = cpu.sp as usize;
+            return (None, Some(sp..sp + 2));
+        }
+        Instruction::Ret if in_memory_stack => {
+            let sp = cpu.sp as usize;
+            return (Some(sp.saturating_sub(2)..sp), None);
+        }
+        _ => return (None, None),
+    }
+}
+
+/// A breakpoint condition, e.g. `V3 == 0x20 && (mem[I] & 0x80) != 0`.
+///
+/// Values are V0-VF, I, PC, SP, DT, ST, `mem[expr]` and decimal or 0x-prefixed numbers. Operators bind as in C,
+/// loosest first: `||`, `&&`, `|`, `^`, `&`, `== !=`, `< <= > >=`, `+ -`, and unary `!`, so `V0 & 0xF + 1` is
+/// `V0 & (0xF + 1)`. Comparisons and logic give 1 or 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Num(i64),
+    Value(Value),
+    Mem(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.binary(0)?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("Unexpected '{}' in condition '{}'", token, source));
+        }
+        return Ok(Condition { source: source.to_string(), expr });
+    }
+
+    pub fn eval(&self, cpu: &CPUState) -> i64 {
+        eval(&self.expr, cpu)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn eval(expr: &Expr, cpu: &CPUState) -> i64 {
+    match expr {
+        Expr::Num(n) => *n,
+        Expr::Value(value) => match *value {
+            Value::V(reg) => cpu.v[reg] as i64,
+            Value::I => cpu.i as i64,
+            Value::Pc => cpu.pc as i64,
+            Value::Sp => cpu.sp as i64,
+            Value::Dt => cpu.dt as i64,
+            Value::St => cpu.st as i64,
+        },
+        //out of bounds reads give 0 rather than stopping on a fault
+        Expr::Mem(addr) => cpu.mem.read(eval(addr, cpu).max(0) as usize).unwrap_or(0) as i64,
+        Expr::Not(inner) => (eval(inner, cpu) == 0) as i64,
+        Expr::Binary(op, lhs, rhs) => {
+            let (a, b) = (eval(lhs, cpu), eval(rhs, cpu));
+            match *op {
+                "||" => (a != 0 || b != 0) as i64,
+                "&&" => (a != 0 && b != 0) as i64,
+                "==" => (a == b) as i64,
+                "!=" => (a != b) as i64,
+                "<" => (a < b) as i64,
+                "<=" => (a <= b) as i64,
+                ">" => (a > b) as i64,
+                ">=" => (a >= b) as i64,
+                "+" => a.wrapping_add(b),
+                "-" => a.wrapping_sub(b),
+                "&" => a & b,
+                "|" => a | b,
+                "^" => a ^ b,
+                _ => unreachable!("operator {} isn't in BINARY_OPS", op),
+            }
+        }
+    }
+}
+
+/// Binary operators by precedence level, loosest first, following C.
+const BINARY_OPS: [&[&str]; 8] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["+", "-"],
+];
+
+fn tokenize(source: &str) -> Result<Vec<String>, String> {
+    const SYMBOLS: [&str; 18] = [
+        "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "&", "|", "^", "!", "(", ")", "[", "]",
+    ];
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while !rest.is_empty() {
+        if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+            tokens.push(symbol.to_string());
+            rest = &rest[symbol.len()..];
+        } else if rest.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            tokens.push(rest[..len].to_string());
+            rest = &rest[len..];
+        } else {
+            return Err(format!("Unexpected '{}' in condition '{}'", rest.chars().next().unwrap(), source));
+        }
+        rest = rest.trim_start();
+    }
+    return Ok(tokens);
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("Condition ended too soon")?;
+        self.pos += 1;
+        return Ok(token);
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("Expected '{}' in condition, found '{}'", expected, token)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == BINARY_OPS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.peek().and_then(|t| BINARY_OPS[level].iter().find(|op| **op == t)) {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        return Ok(lhs);
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let token = self.next()?;
+        match token.as_str() {
+            "!" => return Ok(Expr::Not(Box::new(self.unary()?))),
+            "(" => {
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                return Ok(expr);
+            }
+            _ => {}
+        }
+        let upper = token.to_ascii_uppercase();
+        let value = match upper.as_str() {
+            "MEM" => {
+                self.expect("[")?;
+                let addr = self.binary(0)?;
+                self.expect("]")?;
+                return Ok(Expr::Mem(Box::new(addr)));
+            }
+            "I" => Value::I,
+            "PC" => Value::Pc,
+            "SP" => Value::Sp,
+            "DT" => Value::Dt,
+            "ST" => Value::St,
+            reg if reg.len() == 2 && reg.starts_with('V') => match u8::from_str_radix(&reg[1..], 16) {
+                Ok(n) => Value::V(n as usize),
+                Err(_) => return Err(format!("Unknown register '{}' in condition", token)),
+            },
+            _ => return parse_number(&token).map(Expr::Num),
+        };
+        return Ok(Expr::Value(value));
+    }
+}
+
+fn parse_number(token: &str) -> Result<i64, String> {
+    let parsed = match token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => token.parse(),
+    };
+    return parsed.map_err(|_| format!("Expected a number or register in condition, found '{}'", token));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Chip8Config;
+    use crate::display::DisplayData;
+    use crate::memory::{Memory, MEMSIZE};
+
+    fn cpu() -> CPUState {
+        let config = Chip8Config::default();
+        let rng = config.rng.build(1);
+        let mut cpu = CPUState::new(Memory::with_prog(&[], MEMSIZE).unwrap(), DisplayData::new_64x32(), config, rng);
+        cpu.v[0] = 0x35;
+        cpu.v[3] = 0x20;
+        cpu.i = 0x300;
+        cpu.mem.write(0x300, 0x80).unwrap();
+        return cpu;
+    }
+
+    fn eval(source: &str) -> i64 {
+        Condition::parse(source).unwrap().eval(&cpu())
+    }
+
+    #[test]
+    fn conditions_read_registers_and_memory() {
+        assert_eq!(eval("V0"), 0x35);
+        assert_eq!(eval("v3 == 0x20"), 1);
+        assert_eq!(eval("I"), 0x300);
+        assert_eq!(eval("PC"), 0x200);
+        assert_eq!(eval("mem[I]"), 0x80);
+        assert_eq!(eval("mem[I + 1]"), 0);
+        assert_eq!(eval("mem[0xFFFFF]"), 0);
+        assert_eq!(eval("!V1"), 1);
+        assert_eq!(eval("V3 == 0x20 && (mem[I] & 0x80) != 0"), 1);
+        assert_eq!(eval("V1 || 0"), 0);
+    }
+
+    #[test]
+    fn operators_bind_as_in_c() {
+        //& is looser than +, and == is tighter than &
+        assert_eq!(eval("V0 & 0xF + 1"), 0x35 & 0x10);
+        assert_eq!(eval("V0 & 0x30 == 0x30"), 0x35 & 1);
+        assert_eq!(eval("(V0 & 0x30) == 0x30"), 1);
+        //1 | (2 ^ (3 & 6)), where reading left to right would give 0
+        assert_eq!(eval("1 | 2 ^ 3 & 6"), 1);
+        assert_eq!(eval("1 < 2 == 1"), 1);
+        assert_eq!(eval("0 && 1 || 1"), 1);
+        assert_eq!(eval("10 - 3 - 2"), 5);
+    }
+
+    #[test]
+    fn malformed_conditions_are_errors() {
+        for source in ["", "V0 ==", "VG == 1", "V10", "(V0", "mem[I", "V0 1", "V0 = 1", "0xZZ", "V0 $ 1"] {
+            assert!(Condition::parse(source).is_err(), "{:?}", source);
+        }
+        let mut breakpoints = Breakpoints::default();
+        assert!(breakpoints.add(BreakpointKind::Always, Some("V0 ==")).is_err());
+        assert!(breakpoints.is_empty());
+    }
+
+    #[test]
+    fn watchpoints_cover_every_byte_accessed() {
+        let cpu = cpu();
+        let mut breakpoints = Breakpoints::default();
+        let write = breakpoints.add(BreakpointKind::Write { start: 0x303, end: 0x310 }, None).unwrap();
+        let read = breakpoints.add(BreakpointKind::Read { start: 0x2FF, end: 0x300 }, None).unwrap();
+
+        //Fx55 with x = 3 writes 300-303, so touches the last byte but not one past it
+        assert_eq!(breakpoints.find_hit(&cpu, Instruction::Store { x: 3 }).unwrap().id, write);
+        assert_eq!(breakpoints.find_hit(&cpu, Instruction::Store { x: 2 }), None);
+        assert_eq!(breakpoints.find_hit(&cpu, Instruction::Load { x: 0 }).unwrap().id, read);
+        assert_eq!(breakpoints.find_hit(&cpu, Instruction::Draw { x: 0, y: 0, n: 1 }).unwrap().id, read);
+        //the fetch itself isn't a read
+        assert_eq!(breakpoints.find_hit(&cpu, Instruction::LoadI(0x2FF)), None);
+
+        breakpoints.set_enabled(read, false);
+        assert_eq!(breakpoints.find_hit(&cpu, Instruction::Load { x: 0 }), None);
+        assert!(breakpoints.remove(write));
+        assert!(!breakpoints.remove(write));
+    }
+
+    #[test]
+    fn a_breakpoint_hits_once_per_visit() {
+        let mut cpu = cpu();
+        let mut breakpoints = Breakpoints::default();
+        breakpoints.add(BreakpointKind::Pc(0x200), Some("V0 == 0x35")).unwrap();
+        let instr = Instruction::Jump(0x200);
+        let hit = breakpoints.check(&cpu, instr).unwrap();
+        assert_eq!((hit.pc, hit.opcode), (0x200, 0x1200));
+        //resuming runs the instruction stopped on, then the next visit stops again
+        assert_eq!(breakpoints.check(&cpu, instr), None);
+        assert!(breakpoints.check(&cpu, instr).is_some());
+        //single stepping past it also counts as the visit
+        breakpoints.stepped();
+        assert!(breakpoints.check(&cpu, instr).is_some());
+
+        cpu.v[0] = 0;
+        breakpoints.stepped();
+        assert_eq!(breakpoints.check(&cpu, instr), None);
+    }
+}
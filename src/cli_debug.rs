@@ -3,8 +3,8 @@ use crate::{cpu::CPUState, disassembler::disassemble_opcode};
 
 pub fn debug_state(cpu: &CPUState) -> String {
     let opcode1 = fmt_opcode(cpu.pc, cpu.get_opcode());
-    let opcode2 = fmt_opcode(cpu.pc+2, cpu.mem.read_opcode(cpu.pc+2));
-    let opcode3 = fmt_opcode(cpu.pc+4, cpu.mem.read_opcode(cpu.pc+4));
+    let opcode2 = fmt_opcode(cpu.pc.wrapping_add(2), cpu.mem.read_opcode(cpu.pc as usize + 2).unwrap_or(0));
+    let opcode3 = fmt_opcode(cpu.pc.wrapping_add(4), cpu.mem.read_opcode(cpu.pc as usize + 4).unwrap_or(0));
     let width = opcode1.len().max(opcode2.len()).max(opcode3.len());
     let mut s = format!("-> |{:width$}|\n   |{:width$}|\n   |{:width$}|",
         opcode1,
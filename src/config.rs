@@ -1,43 +1,288 @@
 #![allow(non_snake_case)]
 
-use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
-use std::{fs::write, path::Path};
+use crate::random::RngKind;
 
-#[derive(Serialize, Deserialize)]
+use std::{fs, path::Path, str::FromStr};
+
+pub const DEFAULT_CONFIG_PATH: &str = "emu-chip8-core-config.json";
+/// Deepest stack `stack_depth` can ask for, as SP is a byte.
+pub const MAX_STACK_DEPTH: usize = 0xFF;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Chip8Config {
+    /// Which extensions to the original instruction set are decoded.
+    pub instruction_set: InstructionSet,
     pub clock_speed_hz: u64,
+    /// Cycles per 60 Hz frame when stepping with `Machine::step_frame` and friends.
+    pub instructions_per_frame: u32,
+    /// Keep decoded instructions around between cycles instead of decoding every fetch.
+    pub decode_cache: bool,
+    /// 8xy6/8xyE shift Vy into Vx instead of shifting Vx in place.
     pub shifting_with_Vy: bool,
+    /// Sprites are clipped at the screen edges instead of wrapping around.
     pub sprite_clipping: bool,
+    /// DRW waits for the next vblank before drawing.
     pub emulate_draw_vblank_delay: bool,
+    /// 8xy1/8xy2/8xy3 reset VF to 0.
+    pub vf_reset_on_logic: bool,
+    /// How Fx55/Fx65 leave I after storing or loading registers.
+    pub load_store_increment: IndexIncrement,
+    /// Bnnn is read as Bxnn and jumps to xnn + Vx instead of nnn + V0.
+    pub jump_with_Vx: bool,
+    /// Where CALL keeps its return addresses.
+    pub stack_layout: StackLayout,
+    /// Nested CALLs allowed before a stack overflow, up to `MAX_STACK_DEPTH`. The in-memory stack holds at most 16.
+    pub stack_depth: usize,
+    /// What happens when an instruction or fetch addresses past the end of memory.
+    pub address_policy: AddressPolicy,
+    /// Frames of rewind history to keep. 0 turns rewinding off.
+    pub rewind_frames: u32,
+    /// Frames between rewind snapshots.
+    pub rewind_interval: u32,
+    pub rng: RngKind,
+    /// Seed for the RND generator. `None` picks a fresh seed for every machine.
+    pub rng_seed: Option<u64>,
 }
 
-impl Chip8Config {
+impl Default for Chip8Config {
     fn default() -> Chip8Config {
         Chip8Config {
+            instruction_set: InstructionSet::Chip8,
             clock_speed_hz: 500,
+            instructions_per_frame: 8,
+            decode_cache: true,
             shifting_with_Vy: true,
             sprite_clipping: true,
             emulate_draw_vblank_delay: false,
+            vf_reset_on_logic: true,
+            load_store_increment: IndexIncrement::ByXPlusOne,
+            jump_with_Vx: false,
+            stack_layout: StackLayout::InMemory,
+            stack_depth: 16,
+            address_policy: AddressPolicy::Fault,
+            rewind_frames: 0,
+            rewind_interval: 4,
+            rng: RngKind::XorShift,
+            rng_seed: None,
         }
     }
 }
 
-lazy_static! {
-    pub static ref CHIP8_CONFIG: Chip8Config = build_config();
+/// Each instruction set is a superset of the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum InstructionSet {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IndexIncrement {
+    /// I ends up pointing after the last register, like on the COSMAC VIP.
+    ByXPlusOne,
+    /// I ends up pointing at the last register, like on the HP48 interpreters.
+    ByX,
+    Unchanged,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StackLayout {
+    /// Return addresses are stored big-endian in memory from `STACK_START_ADDR`, visible to the program.
+    InMemory,
+    /// Return addresses are kept outside of memory, like on the HP48 interpreters.
+    Separate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddressPolicy {
+    /// Addresses wrap around at the memory size, and so does PC.
+    Wrap,
+    /// Addresses past the end all refer to the last byte.
+    Clamp,
+    /// The access fails with `EmulationError::MemoryOutOfBounds`.
+    Fault,
+}
+
+/// Quirk presets matching the interpreters ROMs were written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuirkProfile {
+    CosmacVip,
+    Chip48,
+    SuperChip10,
+    SuperChip11,
+    XoChip,
+    /// What most present-day interpreters and the ROMs written for them assume.
+    Modern,
+}
+
+impl QuirkProfile {
+    pub fn config(self) -> Chip8Config {
+        let mut config = Chip8Config::default();
+        config.apply_profile(self);
+        return config;
+    }
+}
+
+impl FromStr for QuirkProfile {
+    type Err = String;
+
+    /// Parses the short names used on command lines, like "vip", "schip" or "xochip".
+    fn from_str(name: &str) -> Result<QuirkProfile, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "vip" | "cosmacvip" => Ok(QuirkProfile::CosmacVip),
+            "chip48" => Ok(QuirkProfile::Chip48),
+            "schip10" | "superchip10" => Ok(QuirkProfile::SuperChip10),
+            "schip" | "schip11" | "superchip11" => Ok(QuirkProfile::SuperChip11),
+            "xo" | "xochip" => Ok(QuirkProfile::XoChip),
+            "modern" => Ok(QuirkProfile::Modern),
+            _ => Err(format!("unknown profile '{}'", name)),
+        }
+    }
 }
 
-fn build_config() -> Chip8Config {
-    let path = Path::new("emu-chip8-core-config.json");
-    if !path.exists() {
-        write(
-            path,
-            serde_json::to_string(&Chip8Config::default()).unwrap(),
-        )
-        .expect("Couldn't create JSON config file:");
+impl Chip8Config {
+    /// Sets the instruction set, every quirk and the RND generator to the profile's behavior, leaving the other
+    /// settings alone.
+    pub fn apply_profile(&mut self, profile: QuirkProfile) {
+        self.instruction_set = match profile {
+            QuirkProfile::CosmacVip | QuirkProfile::Chip48 | QuirkProfile::Modern => InstructionSet::Chip8,
+            QuirkProfile::SuperChip10 | QuirkProfile::SuperChip11 => InstructionSet::SuperChip,
+            QuirkProfile::XoChip => InstructionSet::XoChip,
+        };
+        let (vf_reset, increment, draw_wait, clipping, shift_Vy, jump_Vx) = match profile {
+            QuirkProfile::CosmacVip => (true, IndexIncrement::ByXPlusOne, true, true, true, false),
+            QuirkProfile::Chip48 => (false, IndexIncrement::ByX, false, true, false, true),
+            QuirkProfile::SuperChip10 => (false, IndexIncrement::ByX, false, true, false, true),
+            QuirkProfile::SuperChip11 => (false, IndexIncrement::Unchanged, false, true, false, true),
+            QuirkProfile::XoChip => (false, IndexIncrement::ByXPlusOne, false, false, true, false),
+            QuirkProfile::Modern => (false, IndexIncrement::Unchanged, false, true, false, false),
+        };
+        self.vf_reset_on_logic = vf_reset;
+        self.load_store_increment = increment;
+        self.emulate_draw_vblank_delay = draw_wait;
+        self.sprite_clipping = clipping;
+        self.shifting_with_Vy = shift_Vy;
+        self.jump_with_Vx = jump_Vx;
+        (self.stack_layout, self.stack_depth) = match profile {
+            QuirkProfile::CosmacVip => (StackLayout::InMemory, 12),
+            _ => (StackLayout::Separate, 16),
+        };
+        self.rng = match profile {
+            QuirkProfile::CosmacVip => RngKind::CosmacVip,
+            _ => RngKind::XorShift,
+        };
+    }
+
+    /// Reads a JSON config file. Fields missing from the file keep their default values.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Chip8Config, String> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read config file {}: {}", path.display(), e))?;
+        let config: Chip8Config = serde_json::from_str(&json)
+            .map_err(|e| format!("Couldn't parse config file {}: {}", path.display(), e))?;
+        config.validate().map_err(|e| format!("Bad config file {}: {}", path.display(), e))?;
+        return Ok(config);
     }
 
-    serde_json::from_str(&std::fs::read_to_string(path).unwrap())
-        .expect("Couldn't read JSON config:")
+    /// Checks for settings that are out of range.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.stack_depth > MAX_STACK_DEPTH {
+            return Err(format!("stack_depth is {}, the most is {}", self.stack_depth, MAX_STACK_DEPTH));
+        }
+        return Ok(());
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Couldn't serialize config: {}", e))?;
+        fs::write(path, json)
+            .map_err(|e| format!("Couldn't write config file {}: {}", path.display(), e))
+    }
+
+    /// Loads the config at `path`, writing the default config there first if the file doesn't exist.
+    pub fn load_or_create<P: AsRef<Path>>(path: P) -> Result<Chip8Config, String> {
+        let path = path.as_ref();
+        if !path.exists() {
+            Chip8Config::default().write_to_file(path)?;
+        }
+        Chip8Config::from_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+
+    #[test]
+    fn stack_depth_fits_in_sp() {
+        let mut config = Chip8Config { stack_layout: StackLayout::Separate, stack_depth: MAX_STACK_DEPTH, ..Chip8Config::default() };
+        assert!(config.validate().is_ok());
+        config.stack_depth = MAX_STACK_DEPTH + 1;
+        assert!(config.validate().is_err());
+
+        let path = std::env::temp_dir().join(format!("chip8-deep-stack-{}.json", std::process::id()));
+        config.write_to_file(&path).unwrap();
+        let loaded = Chip8Config::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.unwrap_err().contains("stack_depth"));
+
+        //built in code without validating, the stack still stops at a depth SP can hold
+        config.stack_depth = 1000;
+        //CALL 200
+        let mut machine = Machine::new(&[0x22, 0x00], config).unwrap();
+        let err = machine.run_cycles(1000).unwrap_err();
+        assert!(matches!(err, crate::error::EmulationError::StackOverflow { .. }));
+        assert_eq!(machine.cpu_state().sp as usize, MAX_STACK_DEPTH);
+        assert_eq!(machine.call_stack().len(), MAX_STACK_DEPTH);
+    }
+
+    #[test]
+    fn profiles_set_their_interpreters_quirks() {
+        let vip = QuirkProfile::CosmacVip.config();
+        assert_eq!(vip.instruction_set, InstructionSet::Chip8);
+        assert!(vip.vf_reset_on_logic && vip.emulate_draw_vblank_delay && vip.shifting_with_Vy && vip.sprite_clipping);
+        assert!(!vip.jump_with_Vx);
+        assert_eq!(vip.load_store_increment, IndexIncrement::ByXPlusOne);
+        assert_eq!((vip.stack_layout, vip.stack_depth), (StackLayout::InMemory, 12));
+
+        let chip48 = QuirkProfile::Chip48.config();
+        assert_eq!(chip48.load_store_increment, IndexIncrement::ByX);
+        assert!(chip48.jump_with_Vx && !chip48.shifting_with_Vy && !chip48.vf_reset_on_logic);
+
+        let schip10 = QuirkProfile::SuperChip10.config();
+        assert_eq!(schip10.instruction_set, InstructionSet::SuperChip);
+        assert_eq!(schip10.load_store_increment, IndexIncrement::ByX);
+        let schip11 = QuirkProfile::SuperChip11.config();
+        assert_eq!(schip11.instruction_set, InstructionSet::SuperChip);
+        assert_eq!(schip11.load_store_increment, IndexIncrement::Unchanged);
+        assert!(schip11.jump_with_Vx && !schip11.emulate_draw_vblank_delay);
+
+        let xo = QuirkProfile::XoChip.config();
+        assert_eq!((xo.instruction_set, xo.load_store_increment), (InstructionSet::XoChip, IndexIncrement::ByXPlusOne));
+        assert!(!xo.sprite_clipping && xo.shifting_with_Vy && !xo.jump_with_Vx);
+
+        let modern = QuirkProfile::Modern.config();
+        assert_eq!((modern.instruction_set, modern.load_store_increment), (InstructionSet::Chip8, IndexIncrement::Unchanged));
+        assert!(!modern.vf_reset_on_logic && !modern.shifting_with_Vy && !modern.jump_with_Vx);
+        assert!(modern.sprite_clipping && !modern.emulate_draw_vblank_delay);
+        assert_eq!((modern.stack_layout, modern.stack_depth), (StackLayout::Separate, 16));
+    }
+
+    #[test]
+    fn applying_a_profile_keeps_the_other_settings() {
+        let mut config = Chip8Config::default();
+        (config.clock_speed_hz, config.rewind_frames, config.rng_seed) = (1000, 60, Some(3));
+        config.apply_profile(QuirkProfile::XoChip);
+        config.apply_profile(QuirkProfile::CosmacVip);
+        let mut expected = QuirkProfile::CosmacVip.config();
+        (expected.clock_speed_hz, expected.rewind_frames, expected.rng_seed) = (1000, 60, Some(3));
+        assert_eq!(config, expected);
+        assert_eq!("schip".parse(), Ok(QuirkProfile::SuperChip11));
+        assert!("gameboy".parse::<QuirkProfile>().is_err());
+    }
 }
@@ -1,44 +1,76 @@
+use serde::{Deserialize, Serialize};
+
 use crate::{
+    config::{AddressPolicy, Chip8Config, StackLayout, MAX_STACK_DEPTH},
+    error::EmulationError,
     keyboard::KeyboardState,
-    memory::{PROG_START_ADDR, STACK_START_ADDR, Memory}, instructions::{DRW, Fx0AHandler, OUTER_FUNC_TABLE}, display::DisplayData,
+    random::{RandomSource, RngKind},
+    memory::{PROG_START_ADDR, STACK_SIZE, STACK_START_ADDR, Memory}, instructions::{DRW, Fx0AHandler, execute}, display::DisplayData,
+    decode::Instruction,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CPUState {
     pub pc: u16,
     pub i: u16,
     pub v: [u8; 0x10],
+    /// Address of the next free stack slot for the in-memory stack, or the number of entries in `stack` otherwise
     pub sp: u8,
+    /// Return addresses when `config.stack_layout` is `Separate`
+    pub stack: Vec<u16>,
     pub dt: u8,
     pub st: u8,
+    /// SUPER-CHIP RPL user flags, saved and loaded by Fx75/Fx85
+    pub rpl: [u8; 0x10],
+    /// XO-CHIP audio pattern buffer, loaded by F002
+    pub audio_pattern: [u8; 0x10],
+    /// XO-CHIP playback pitch, set by Fx3A
+    pub pitch: u8,
 
     pub mem: Memory,
     pub disp: DisplayData,
     pub kbstate: KeyboardState,
+    pub config: Chip8Config,
+    /// Not serialized, save states carry the generator's `RandomSource::state` instead
+    #[serde(skip, default = "placeholder_rng")]
+    pub rng: Box<dyn RandomSource>,
 
     pub halt_status: HaltStatus
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HaltStatus {
     NotHalted,
     WaitingVblank,
     WaitingFx0A,
-    ExecutingDRW
+    ExecutingDRW,
+    /// The program ran 00FD (EXIT)
+    Exited
+}
+
+fn placeholder_rng() -> Box<dyn RandomSource> {
+    RngKind::default().build(0)
 }
 
 impl CPUState {
-    pub fn new(mem: Memory, disp: DisplayData) -> CPUState {
+    pub fn new(mut mem: Memory, disp: DisplayData, config: Chip8Config, rng: Box<dyn RandomSource>) -> CPUState {
+        mem.set_address_policy(config.address_policy);
         CPUState {
             pc: PROG_START_ADDR as u16,
             i: 0,
             v: [0; 0x10],
             sp: STACK_START_ADDR as u8,
+            stack: Vec::new(),
             dt: 0,
             st: 0,
+            rpl: [0; 0x10],
+            audio_pattern: [0; 0x10],
+            pitch: 64,
             mem,
             disp,
             kbstate: KeyboardState::new(),
+            config,
+            rng,
             halt_status: HaltStatus::NotHalted
         }
     }
@@ -55,28 +87,105 @@ impl CPUState {
         return s;
     }
 
-    pub fn get_opcode(&self) -> u16 {
-        u16::from_be_bytes([self.mem.read(self.pc), self.mem.read(self.pc + 1)])
+    /// Most nested CALLs the stack can hold with the current config.
+    pub fn stack_capacity(&self) -> usize {
+        match self.config.stack_layout {
+            StackLayout::InMemory => self.config.stack_depth.min(STACK_SIZE / 2),
+            //a config that skipped `Chip8Config::validate` still can't push SP past a byte
+            StackLayout::Separate => self.config.stack_depth.min(MAX_STACK_DEPTH),
+        }
+    }
+
+    /// Number of CALLs that haven't returned yet.
+    pub fn stack_len(&self) -> usize {
+        match self.config.stack_layout {
+            StackLayout::InMemory => (self.sp as usize).saturating_sub(STACK_START_ADDR) / 2,
+            StackLayout::Separate => self.stack.len(),
+        }
+    }
+
+    /// Saves the address of a CALL, returning false if the stack is full.
+    pub fn push_stack(&mut self, addr: u16) -> bool {
+        if self.stack_len() >= self.stack_capacity() {
+            return false;
+        }
+        match self.config.stack_layout {
+            StackLayout::InMemory => {
+                //the stack area is at the start of memory, so this can't fault
+                let _ = self.mem.write_range(self.sp as usize, &addr.to_be_bytes());
+                self.sp += 2;
+            }
+            StackLayout::Separate => {
+                self.stack.push(addr);
+                self.sp = self.stack.len() as u8;
+            }
+        }
+        return true;
+    }
+
+    /// Takes the address of the innermost CALL, or `None` if the stack is empty.
+    pub fn pop_stack(&mut self) -> Option<u16> {
+        if self.stack_len() == 0 {
+            return None;
+        }
+        match self.config.stack_layout {
+            StackLayout::InMemory => {
+                self.sp -= 2;
+                return self.mem.read_opcode(self.sp as usize).ok();
+            }
+            StackLayout::Separate => {
+                let addr = self.stack.pop();
+                self.sp = self.stack.len() as u8;
+                return addr;
+            }
+        }
     }
 
-    pub fn d_addr(&self) -> u16 {
-        nnn(self.get_opcode())
+    /// Addresses the pending RETs will return to, outermost call first.
+    pub fn call_stack(&self) -> Vec<u16> {
+        let calls: Vec<u16> = match self.config.stack_layout {
+            StackLayout::InMemory => (0..self.stack_len())
+                .map(|n| self.mem.read_opcode(STACK_START_ADDR + n * 2).unwrap_or(0))
+                .collect(),
+            StackLayout::Separate => self.stack.clone(),
+        };
+        return calls.into_iter().map(|addr| addr.wrapping_add(2)).collect();
     }
 
-    pub fn d_n(&self) -> u8 {
-        n(self.get_opcode())
+    /// The opcode at PC, or 0 if reading it would fault.
+    pub fn get_opcode(&self) -> u16 {
+        self.mem.read_opcode(self.pc as usize).unwrap_or(0)
     }
 
-    pub fn d_x(&self) -> usize {
-        x(self.get_opcode()) as usize
+    /// Reads and decodes the instruction at PC, including the second word of F000 nnnn.
+    pub fn fetch(&self) -> Result<Instruction, EmulationError> {
+        let out_of_bounds = |addr: usize| EmulationError::MemoryOutOfBounds { pc: self.pc, opcode: 0, addr };
+        let opcode = self.mem.read_opcode(self.pc as usize).map_err(out_of_bounds)?;
+        let instr = Instruction::decode(opcode)
+            .map_err(|_| EmulationError::UnknownOpcode { pc: self.pc, opcode })?;
+        if let Instruction::LoadILong(_) = instr {
+            let addr = self.mem.read_opcode(self.pc as usize + 2).map_err(out_of_bounds)?;
+            return Ok(Instruction::LoadILong(addr));
+        }
+        return Ok(instr);
     }
 
-    pub fn d_y(&self) -> usize {
-        y(self.get_opcode()) as usize
+    /// `fetch`, but reusing earlier decodes when `config.decode_cache` is on.
+    pub fn fetch_cached(&mut self) -> Result<Instruction, EmulationError> {
+        if !self.config.decode_cache {
+            return self.fetch();
+        }
+        if let Some(instr) = self.mem.cached_instruction(self.pc) {
+            return Ok(instr);
+        }
+        let instr = self.fetch()?;
+        self.mem.cache_instruction(self.pc, instr);
+        return Ok(instr);
     }
 
-    pub fn d_kk(&self) -> u8 {
-        kk(self.get_opcode())
+    pub fn tick_timers(&mut self) {
+        self.dt = self.dt.saturating_sub(1);
+        self.st = self.st.saturating_sub(1);
     }
 
     pub fn enter_vblank(&mut self) {
@@ -85,48 +194,33 @@ impl CPUState {
         }
     }
 
-    pub fn run_cycle(&mut self) -> bool {
+    pub fn run_cycle(&mut self) -> Result<bool, EmulationError> {
+        if self.mem.address_policy() == AddressPolicy::Wrap {
+            self.pc = (self.pc as usize % self.mem.size()) as u16;
+        }
         match self.halt_status {
-            HaltStatus::WaitingVblank => {
-                return false;
+            HaltStatus::WaitingVblank | HaltStatus::Exited => {
+                return Ok(false);
             },
             HaltStatus::ExecutingDRW => {
-                DRW(self);
                 self.halt_status = HaltStatus::NotHalted;
-                return true;
+                if let Instruction::Draw { x, y, n } = self.fetch()? {
+                    DRW(self, x.into(), y.into(), n)?;
+                }
+                return Ok(true);
             },
             HaltStatus::WaitingFx0A => {
                 let finished = Fx0AHandler(self);
                 if finished {
                     self.halt_status = HaltStatus::NotHalted;
                 }
-                return true; //if this is false, control never gets passed back to frontend event handler
+                return Ok(true); //if this is false, control never gets passed back to frontend event handler
             },
             HaltStatus::NotHalted => {
-                let highest_nibble = (self.get_opcode() & 0xF000) >> 12;
-                OUTER_FUNC_TABLE[highest_nibble as usize](self);
-                return matches!(self.halt_status, HaltStatus::NotHalted);
+                let instr = self.fetch_cached()?;
+                execute(self, instr)?;
+                return Ok(matches!(self.halt_status, HaltStatus::NotHalted));
             },
         }
     }
 }
-
-pub fn nnn(opcode: u16) -> u16 {
-    opcode & 0x0FFF
-}
-
-pub fn x(opcode: u16) -> u8 {
-    ((opcode & 0x0F00) >> 8) as u8
-}
-
-pub fn y(opcode: u16) -> u8 {
-    ((opcode & 0x00F0) >> 4) as u8
-}
-
-pub fn n(opcode: u16) -> u8 {
-    (opcode & 0x000F) as u8
-}
-
-pub fn kk(opcode: u16) -> u8 {
-    (opcode & 0x00FF) as u8
-}
@@ -0,0 +1,517 @@
+use std::fmt;
+
+use crate::config::InstructionSet;
+
+/// A decoded instruction. Register operands are register numbers, not register values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    /// 0nnn
+    Sys(u16),
+    /// 00E0
+    Cls,
+    /// 00EE
+    Ret,
+    /// 00Cn
+    ScrollDown(u8),
+    /// 00Dn
+    ScrollUp(u8),
+    /// 00FB
+    ScrollRight,
+    /// 00FC
+    ScrollLeft,
+    /// 00FD
+    Exit,
+    /// 00FE
+    Lores,
+    /// 00FF
+    Hires,
+    /// 1nnn
+    Jump(u16),
+    /// 2nnn
+    Call(u16),
+    /// 3xkk
+    SkipEqImm { x: u8, kk: u8 },
+    /// 4xkk
+    SkipNeImm { x: u8, kk: u8 },
+    /// 5xy0
+    SkipEqReg { x: u8, y: u8 },
+    /// 5xy2
+    SaveRange { x: u8, y: u8 },
+    /// 5xy3
+    LoadRange { x: u8, y: u8 },
+    /// 6xkk
+    LoadImm { x: u8, kk: u8 },
+    /// 7xkk
+    AddImm { x: u8, kk: u8 },
+    /// 8xy0
+    Move { x: u8, y: u8 },
+    /// 8xy1
+    Or { x: u8, y: u8 },
+    /// 8xy2
+    And { x: u8, y: u8 },
+    /// 8xy3
+    Xor { x: u8, y: u8 },
+    /// 8xy4
+    Add { x: u8, y: u8 },
+    /// 8xy5
+    Sub { x: u8, y: u8 },
+    /// 8xy6
+    Shr { x: u8, y: u8 },
+    /// 8xy7
+    SubN { x: u8, y: u8 },
+    /// 8xyE
+    Shl { x: u8, y: u8 },
+    /// 9xy0
+    SkipNeReg { x: u8, y: u8 },
+    /// Annn
+    LoadI(u16),
+    /// Bnnn
+    JumpOffset(u16),
+    /// Cxkk
+    Rand { x: u8, kk: u8 },
+    /// Dxyn
+    Draw { x: u8, y: u8, n: u8 },
+    /// Ex9E
+    SkipKey { x: u8 },
+    /// ExA1
+    SkipNotKey { x: u8 },
+    /// F000 nnnn. The address is the word after the opcode, so `decode` leaves it at 0; `decode_long` fills it in.
+    LoadILong(u16),
+    /// Fn01
+    Plane(u8),
+    /// F002
+    Audio,
+    /// Fx07
+    LoadDelay { x: u8 },
+    /// Fx0A
+    WaitKey { x: u8 },
+    /// Fx15
+    SetDelay { x: u8 },
+    /// Fx18
+    SetSound { x: u8 },
+    /// Fx1E
+    AddI { x: u8 },
+    /// Fx29
+    Font { x: u8 },
+    /// Fx30
+    BigFont { x: u8 },
+    /// Fx33
+    Bcd { x: u8 },
+    /// Fx3A
+    Pitch { x: u8 },
+    /// Fx55
+    Store { x: u8 },
+    /// Fx65
+    Load { x: u8 },
+    /// Fx75
+    SaveFlags { x: u8 },
+    /// Fx85
+    LoadFlags { x: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DecodeError {
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown opcode {:04X}", self.opcode)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Instruction {
+    /// Decodes an opcode from any of the supported instruction sets.
+    pub fn decode(opcode: u16) -> Result<Instruction, DecodeError> {
+        use Instruction::*;
+        let (x, y, n, kk, nnn) = (x(opcode), y(opcode), n(opcode), kk(opcode), nnn(opcode));
+        let instr = match opcode >> 12 {
+            0x0 => match nnn {
+                0x0E0 => Cls,
+                0x0EE => Ret,
+                0x0C0..=0x0CF => ScrollDown(n),
+                0x0D0..=0x0DF => ScrollUp(n),
+                0x0FB => ScrollRight,
+                0x0FC => ScrollLeft,
+                0x0FD => Exit,
+                0x0FE => Lores,
+                0x0FF => Hires,
+                _ => Sys(nnn),
+            },
+            0x1 => Jump(nnn),
+            0x2 => Call(nnn),
+            0x3 => SkipEqImm { x, kk },
+            0x4 => SkipNeImm { x, kk },
+            0x5 => match n {
+                0x0 => SkipEqReg { x, y },
+                0x2 => SaveRange { x, y },
+                0x3 => LoadRange { x, y },
+                _ => return Err(DecodeError { opcode }),
+            },
+            0x6 => LoadImm { x, kk },
+            0x7 => AddImm { x, kk },
+            0x8 => match n {
+                0x0 => Move { x, y },
+                0x1 => Or { x, y },
+                0x2 => And { x, y },
+                0x3 => Xor { x, y },
+                0x4 => Add { x, y },
+                0x5 => Sub { x, y },
+                0x6 => Shr { x, y },
+                0x7 => SubN { x, y },
+                0xE => Shl { x, y },
+                _ => return Err(DecodeError { opcode }),
+            },
+            0x9 if n == 0 => SkipNeReg { x, y },
+            0xA => LoadI(nnn),
+            0xB => JumpOffset(nnn),
+            0xC => Rand { x, kk },
+            0xD => Draw { x, y, n },
+            0xE => match kk {
+                0x9E => SkipKey { x },
+                0xA1 => SkipNotKey { x },
+                _ => return Err(DecodeError { opcode }),
+            },
+            0xF => match kk {
+                0x00 if x == 0 => LoadILong(0),
+                0x01 => Plane(x),
+                0x02 if x == 0 => Audio,
+                0x07 => LoadDelay { x },
+                0x0A => WaitKey { x },
+                0x15 => SetDelay { x },
+                0x18 => SetSound { x },
+                0x1E => AddI { x },
+                0x29 => Font { x },
+                0x30 => BigFont { x },
+                0x33 => Bcd { x },
+                0x3A => Pitch { x },
+                0x55 => Store { x },
+                0x65 => Load { x },
+                0x75 => SaveFlags { x },
+                0x85 => LoadFlags { x },
+                _ => return Err(DecodeError { opcode }),
+            },
+            _ => return Err(DecodeError { opcode }),
+        };
+        return Ok(instr);
+    }
+
+    /// Decodes an opcode along with the word after it, which only F000 nnnn uses.
+    pub fn decode_long(opcode: u16, next: u16) -> Result<Instruction, DecodeError> {
+        match Instruction::decode(opcode)? {
+            Instruction::LoadILong(_) => Ok(Instruction::LoadILong(next)),
+            instr => Ok(instr),
+        }
+    }
+
+    /// The opcode this instruction decodes from. For F000 nnnn this is the first word; the address is the second.
+    pub fn encode(&self) -> u16 {
+        use Instruction::*;
+        fn xkk(prefix: u16, x: u8, kk: u8) -> u16 {
+            prefix << 12 | (x as u16) << 8 | kk as u16
+        }
+        fn xyn(prefix: u16, x: u8, y: u8, n: u8) -> u16 {
+            prefix << 12 | (x as u16) << 8 | (y as u16) << 4 | n as u16
+        }
+        match *self {
+            Sys(nnn) => nnn & 0x0FFF,
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            ScrollDown(n) => 0x00C0 | n as u16,
+            ScrollUp(n) => 0x00D0 | n as u16,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Lores => 0x00FE,
+            Hires => 0x00FF,
+            Jump(nnn) => 0x1000 | nnn,
+            Call(nnn) => 0x2000 | nnn,
+            SkipEqImm { x, kk } => xkk(0x3, x, kk),
+            SkipNeImm { x, kk } => xkk(0x4, x, kk),
+            SkipEqReg { x, y } => xyn(0x5, x, y, 0x0),
+            SaveRange { x, y } => xyn(0x5, x, y, 0x2),
+            LoadRange { x, y } => xyn(0x5, x, y, 0x3),
+            LoadImm { x, kk } => xkk(0x6, x, kk),
+            AddImm { x, kk } => xkk(0x7, x, kk),
+            Move { x, y } => xyn(0x8, x, y, 0x0),
+            Or { x, y } => xyn(0x8, x, y, 0x1),
+            And { x, y } => xyn(0x8, x, y, 0x2),
+            Xor { x, y } => xyn(0x8, x, y, 0x3),
+            Add { x, y } => xyn(0x8, x, y, 0x4),
+            Sub { x, y } => xyn(0x8, x, y, 0x5),
+            Shr { x, y } => xyn(0x8, x, y, 0x6),
+            SubN { x, y } => xyn(0x8, x, y, 0x7),
+            Shl { x, y } => xyn(0x8, x, y, 0xE),
+            SkipNeReg { x, y } => xyn(0x9, x, y, 0x0),
+            LoadI(nnn) => 0xA000 | nnn,
+            JumpOffset(nnn) => 0xB000 | nnn,
+            Rand { x, kk } => xkk(0xC, x, kk),
+            Draw { x, y, n } => xyn(0xD, x, y, n),
+            SkipKey { x } => xkk(0xE, x, 0x9E),
+            SkipNotKey { x } => xkk(0xE, x, 0xA1),
+            LoadILong(_) => 0xF000,
+            Plane(n) => xkk(0xF, n, 0x01),
+            Audio => 0xF002,
+            LoadDelay { x } => xkk(0xF, x, 0x07),
+            WaitKey { x } => xkk(0xF, x, 0x0A),
+            SetDelay { x } => xkk(0xF, x, 0x15),
+            SetSound { x } => xkk(0xF, x, 0x18),
+            AddI { x } => xkk(0xF, x, 0x1E),
+            Font { x } => xkk(0xF, x, 0x29),
+            BigFont { x } => xkk(0xF, x, 0x30),
+            Bcd { x } => xkk(0xF, x, 0x33),
+            Pitch { x } => xkk(0xF, x, 0x3A),
+            Store { x } => xkk(0xF, x, 0x55),
+            Load { x } => xkk(0xF, x, 0x65),
+            SaveFlags { x } => xkk(0xF, x, 0x75),
+            LoadFlags { x } => xkk(0xF, x, 0x85),
+        }
+    }
+
+    /// Size of the instruction in bytes.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// The oldest instruction set that has this instruction.
+    pub fn instruction_set(&self) -> InstructionSet {
+        use Instruction::*;
+        match self {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | Lores | Hires | BigFont { .. } | SaveFlags { .. }
+            | LoadFlags { .. } => InstructionSet::SuperChip,
+            ScrollUp(_) | SaveRange { .. } | LoadRange { .. } | LoadILong(_) | Plane(_) | Audio | Pitch { .. } => {
+                InstructionSet::XoChip
+            }
+            _ => InstructionSet::Chip8,
+        }
+    }
+
+    /// The opcode table entry this instruction comes from, like "Dxyn". It parses as an `OpcodePattern`.
+    pub fn class(&self) -> &'static str {
+        use Instruction::*;
+        match self {
+            Sys(_) => "0nnn",
+            Cls => "00E0",
+            Ret => "00EE",
+            ScrollDown(_) => "00Cn",
+            ScrollUp(_) => "00Dn",
+            ScrollRight => "00FB",
+            ScrollLeft => "00FC",
+            Exit => "00FD",
+            Lores => "00FE",
+            Hires => "00FF",
+            Jump(_) => "1nnn",
+            Call(_) => "2nnn",
+            SkipEqImm { .. } => "3xkk",
+            SkipNeImm { .. } => "4xkk",
+            SkipEqReg { .. } => "5xy0",
+            SaveRange { .. } => "5xy2",
+            LoadRange { .. } => "5xy3",
+            LoadImm { .. } => "6xkk",
+            AddImm { .. } => "7xkk",
+            Move { .. } => "8xy0",
+            Or { .. } => "8xy1",
+            And { .. } => "8xy2",
+            Xor { .. } => "8xy3",
+            Add { .. } => "8xy4",
+            Sub { .. } => "8xy5",
+            Shr { .. } => "8xy6",
+            SubN { .. } => "8xy7",
+            Shl { .. } => "8xyE",
+            SkipNeReg { .. } => "9xy0",
+            LoadI(_) => "Annn",
+            JumpOffset(_) => "Bnnn",
+            Rand { .. } => "Cxkk",
+            Draw { .. } => "Dxyn",
+            SkipKey { .. } => "Ex9E",
+            SkipNotKey { .. } => "ExA1",
+            LoadILong(_) => "F000",
+            Plane(_) => "Fn01",
+            Audio => "F002",
+            LoadDelay { .. } => "Fx07",
+            WaitKey { .. } => "Fx0A",
+            SetDelay { .. } => "Fx15",
+            SetSound { .. } => "Fx18",
+            AddI { .. } => "Fx1E",
+            Font { .. } => "Fx29",
+            BigFont { .. } => "Fx30",
+            Bcd { .. } => "Fx33",
+            Pitch { .. } => "Fx3A",
+            Store { .. } => "Fx55",
+            Load { .. } => "Fx65",
+            SaveFlags { .. } => "Fx75",
+            LoadFlags { .. } => "Fx85",
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Instruction::*;
+        match *self {
+            Sys(nnn) => write!(f, "SYS {:X}", nnn),
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            ScrollDown(n) => write!(f, "SCD {:X}", n),
+            ScrollUp(n) => write!(f, "SCU {:X}", n),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            Lores => write!(f, "LOW"),
+            Hires => write!(f, "HIGH"),
+            Jump(nnn) => write!(f, "JP {:X}", nnn),
+            Call(nnn) => write!(f, "CALL {:X}", nnn),
+            SkipEqImm { x, kk } => write!(f, "SE V{:X} {:X}", x, kk),
+            SkipNeImm { x, kk } => write!(f, "SNE V{:X} {:X}", x, kk),
+            SkipEqReg { x, y } => write!(f, "SE V{:X} V{:X}", x, y),
+            SaveRange { x, y } => write!(f, "LD [I] V{:X}-V{:X}", x, y),
+            LoadRange { x, y } => write!(f, "LD V{:X}-V{:X} [I]", x, y),
+            LoadImm { x, kk } => write!(f, "LD V{:X} {:X}", x, kk),
+            AddImm { x, kk } => write!(f, "ADD V{:X} {:X}", x, kk),
+            Move { x, y } => write!(f, "LD V{:X} V{:X}", x, y),
+            Or { x, y } => write!(f, "OR V{:X} V{:X}", x, y),
+            And { x, y } => write!(f, "AND V{:X} V{:X}", x, y),
+            Xor { x, y } => write!(f, "XOR V{:X} V{:X}", x, y),
+            Add { x, y } => write!(f, "ADD V{:X} V{:X}", x, y),
+            Sub { x, y } => write!(f, "SUB V{:X} V{:X}", x, y),
+            Shr { x, y } => write!(f, "SHR V{:X} (V{:X})", x, y),
+            SubN { x, y } => write!(f, "SUBN V{:X} V{:X}", x, y),
+            Shl { x, y } => write!(f, "SHL V{:X} (V{:X})", x, y),
+            SkipNeReg { x, y } => write!(f, "SNE V{:X} V{:X}", x, y),
+            LoadI(nnn) => write!(f, "LD I {:X}", nnn),
+            JumpOffset(nnn) => write!(f, "JP V0 {:X}", nnn),
+            Rand { x, kk } => write!(f, "RND V{:X} {:X}", x, kk),
+            Draw { x, y, n } => write!(f, "DRW V{:X} V{:X} {:X}", x, y, n),
+            SkipKey { x } => write!(f, "SKP {:X}", x),
+            SkipNotKey { x } => write!(f, "SKNP {:X}", x),
+            LoadILong(nnnn) => write!(f, "LD I {:X}", nnnn),
+            Plane(n) => write!(f, "PLANE {:X}", n),
+            Audio => write!(f, "AUDIO"),
+            LoadDelay { x } => write!(f, "LD V{:X} DT", x),
+            WaitKey { x } => write!(f, "LD V{:X} K", x),
+            SetDelay { x } => write!(f, "LD DT V{:X}", x),
+            SetSound { x } => write!(f, "LD ST V{:X}", x),
+            AddI { x } => write!(f, "ADD I V{:X}", x),
+            Font { x } => write!(f, "LD F V{:X}", x),
+            BigFont { x } => write!(f, "LD HF V{:X}", x),
+            Bcd { x } => write!(f, "LD B V{:X}", x),
+            Pitch { x } => write!(f, "PITCH V{:X}", x),
+            Store { x } => write!(f, "LD [I] V{:X}", x),
+            Load { x } => write!(f, "LD V{:X} [I]", x),
+            SaveFlags { x } => write!(f, "LD R V{:X}", x),
+            LoadFlags { x } => write!(f, "LD V{:X} R", x),
+        }
+    }
+}
+
+pub fn nnn(opcode: u16) -> u16 {
+    opcode & 0x0FFF
+}
+
+pub fn x(opcode: u16) -> u8 {
+    ((opcode & 0x0F00) >> 8) as u8
+}
+
+pub fn y(opcode: u16) -> u8 {
+    ((opcode & 0x00F0) >> 4) as u8
+}
+
+pub fn n(opcode: u16) -> u8 {
+    (opcode & 0x000F) as u8
+}
+
+pub fn kk(opcode: u16) -> u8 {
+    (opcode & 0x00FF) as u8
+}
+
+/// An opcode with some nibbles left open, written like the opcode tables do: "Dxyn" matches every DRW and
+/// "Fx0A" every wait for key. Hex digits must match exactly, other letters and `_` match any nibble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OpcodePattern {
+    mask: u16,
+    value: u16,
+}
+
+impl OpcodePattern {
+    pub fn parse(pattern: &str) -> Result<OpcodePattern, String> {
+        if pattern.len() != 4 {
+            return Err(format!("Opcode pattern '{}' should be 4 characters long", pattern));
+        }
+        let mut mask = 0;
+        let mut value = 0;
+        for c in pattern.chars() {
+            mask <<= 4;
+            value <<= 4;
+            if let Some(digit) = c.to_digit(16) {
+                mask |= 0xF;
+                value |= digit as u16;
+            } else if !c.is_ascii_alphabetic() && c != '_' {
+                return Err(format!("Opcode pattern '{}' has '{}', expected a hex digit or a letter", pattern, c));
+            }
+        }
+        return Ok(OpcodePattern { mask, value });
+    }
+
+    pub fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+impl fmt::Display for OpcodePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for shift in [12, 8, 4, 0] {
+            if (self.mask >> shift) & 0xF == 0 {
+                write!(f, "_")?;
+            } else {
+                write!(f, "{:X}", (self.value >> shift) & 0xF)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn every_opcode_round_trips() {
+        let mut shown: HashMap<String, u16> = HashMap::new();
+        let mut decoded = 0;
+        for opcode in 0..=0xFFFF {
+            let instr = match Instruction::decode(opcode) {
+                Ok(instr) => instr,
+                Err(err) => {
+                    assert_eq!(err.opcode, opcode);
+                    continue;
+                }
+            };
+            decoded += 1;
+            assert_eq!(instr.encode(), opcode, "{:04X} decodes to {:?}", opcode, instr);
+            assert_eq!(Instruction::decode(instr.encode()), Ok(instr));
+            //F000 nnnn shows its second word, which this loop doesn't have
+            if let Instruction::LoadILong(_) = instr {
+                continue;
+            }
+            if let Some(other) = shown.insert(instr.to_string(), opcode) {
+                panic!("{:04X} and {:04X} both show as '{}'", other, opcode, instr);
+            }
+        }
+        //0nnn, 1nnn, 2nnn, Annn, Bnnn, Cxkk, Dxyn and 3xkk-7xkk cover most of the space
+        assert!(decoded > 0xB000, "only {} opcodes decode", decoded);
+    }
+
+    #[test]
+    fn long_loads_take_the_next_word() {
+        assert_eq!(Instruction::decode_long(0xF000, 0x1234), Ok(Instruction::LoadILong(0x1234)));
+        assert_eq!(Instruction::LoadILong(0x1234).size(), 4);
+        assert_eq!(Instruction::decode_long(0x00E0, 0x1234), Ok(Instruction::Cls));
+        assert!(Instruction::decode(0xF100).is_err());
+    }
+}
@@ -1,8 +1,10 @@
 use crate::{
-    cpu::{kk, n, nnn, x, y},
-    memory::PROG_START_ADDR,
+    decode::Instruction,
+    memory::{Memory, PROG_START_ADDR},
 };
 
+/// Lists the program from `start` until it runs out, or until an opcode that doesn't decode or a 0nnn machine code
+/// call. Neither runs on this interpreter, so both usually mean the listing has walked into sprites or other data.
 pub fn disassemble_program_at(program: &[u8], start: usize) -> String {
     let mut s = String::new();
     let mut i = start;
@@ -16,13 +18,20 @@ pub fn disassemble_program_at(program: &[u8], start: usize) -> String {
             break;
         }
         let opcode = u16::from_be_bytes([program[i], program[i + 1]]);
+        if let (Ok(Instruction::LoadILong(_)), true) = (Instruction::decode(opcode), i + 3 < program.len()) {
+            let addr = u16::from_be_bytes([program[i + 2], program[i + 3]]);
+            let instr = Instruction::LoadILong(addr);
+            s.push_str(&format!("{:X} | {:X} {:04X} | {}\n", i + PROG_START_ADDR, opcode, addr, instr));
+            i += 4;
+            continue;
+        }
         match disassemble_opcode(opcode) {
-            Ok(s_opcode) => s.push_str(&format!(
-                "{:X} | {:X} | {}\n",
-                i + PROG_START_ADDR,
-                opcode,
-                s_opcode
-            )),
+            Ok(s_opcode) => {
+                s.push_str(&format!("{:X} | {:X} | {}\n", i + PROG_START_ADDR, opcode, s_opcode));
+                if let Ok(Instruction::Sys(_)) = Instruction::decode(opcode) {
+                    break;
+                }
+            }
             Err(s_opcode) => {
                 s.push_str(&format!("{:X} | {}\n", i + PROG_START_ADDR, s_opcode));
                 break;
@@ -34,63 +43,50 @@ pub fn disassemble_program_at(program: &[u8], start: usize) -> String {
 }
 
 pub fn disassemble_opcode(opcode: u16) -> Result<String, String> {
-    match opcode {
-        0x00E0 => Ok("CLS".into()),
-        0x00EE => Ok("RET".into()),
-        _ if matches_opcode(opcode, 0x1, None, None, None) => Ok(format!("JP {:X}", nnn(opcode))),
-        _ if matches_opcode(opcode, 0x2, None, None, None) => Ok(format!("CALL {:X}", nnn(opcode))),
-        _ if matches_opcode(opcode, 0x3, None, None, None) => Ok(format!("SE V{:X} {:X}", x(opcode), kk(opcode))),
-        _ if matches_opcode(opcode, 0x4, None, None, None) => Ok(format!("SNE V{:X} {:X}", x(opcode), kk(opcode))),
-        _ if matches_opcode(opcode, 0x5, None, None, Some(0x0)) => Ok(format!("SE V{:X} V{:X}", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x6, None, None, None) => Ok(format!("LD V{:X} {:X}", x(opcode), kk(opcode))),
-        _ if matches_opcode(opcode, 0x7, None, None, None) => Ok(format!("ADD V{:X} {:X}", x(opcode), kk(opcode))),
-        _ if matches_opcode(opcode, 0x8, None, None, Some(0x0)) => Ok(format!("LD V{:X} V{:X}", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x8, None, None, Some(0x1)) => Ok(format!("OR V{:X} V{:X}", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x8, None, None, Some(0x2)) => Ok(format!("AND V{:X} V{:X}", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x8, None, None, Some(0x3)) => Ok(format!("XOR V{:X} V{:X}", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x8, None, None, Some(0x4)) => Ok(format!("ADD V{:X} V{:X}", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x8, None, None, Some(0x5)) => Ok(format!("SUB V{:X} V{:X}", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x8, None, None, Some(0x6)) => Ok(format!("SHR V{:X} (V{:X})", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x8, None, None, Some(0x7)) => Ok(format!("SUBN V{:X} V{:X}", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x8, None, None, Some(0xE)) => Ok(format!("SHL V{:X} (V{:X})", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0x9, None, None, Some(0x0)) => Ok(format!("SNE V{:X} V{:X}", x(opcode), y(opcode))),
-        _ if matches_opcode(opcode, 0xA, None, None, None) => Ok(format!("LD I {:X}", nnn(opcode))),
-        _ if matches_opcode(opcode, 0xB, None, None, None) => Ok(format!("JP V0 {:X}", nnn(opcode))),
-        _ if matches_opcode(opcode, 0xC, None, None, None) => Ok(format!("RND V{:X} {:X}", x(opcode), kk(opcode))),
-        _ if matches_opcode(opcode, 0xD, None, None, None) => Ok(format!("DRW V{:X} V{:X} {:X}",x(opcode), y(opcode), n(opcode))),
-        _ if matches_opcode(opcode, 0xE, None, Some(0x9), Some(0xE)) => Ok(format!("SKP {:X}", x(opcode))),
-        _ if matches_opcode(opcode, 0xE, None, Some(0xA), Some(0x1)) => Ok(format!("SKNP {:X}", x(opcode))),
-        _ if matches_opcode(opcode, 0xF, None, Some(0x0), Some(0x7)) => Ok(format!("LD V{:X} DT", x(opcode))),
-        _ if matches_opcode(opcode, 0xF, None, Some(0x0), Some(0xA)) => Ok(format!("LD V{:X} K", x(opcode))),
-        _ if matches_opcode(opcode, 0xF, None, Some(0x1), Some(0x5)) => Ok(format!("LD DT V{:X}", x(opcode))),
-        _ if matches_opcode(opcode, 0xF, None, Some(0x1), Some(0x8)) => Ok(format!("LD ST V{:X}", x(opcode))),
-        _ if matches_opcode(opcode, 0xF, None, Some(0x1), Some(0xE)) => Ok(format!("ADD I V{:X}", x(opcode))),
-        _ if matches_opcode(opcode, 0xF, None, Some(0x2), Some(0x9)) => Ok(format!("LD F V{:X}", x(opcode))),
-        _ if matches_opcode(opcode, 0xF, None, Some(0x3), Some(0x3)) => Ok(format!("LD B V{:X}", x(opcode))),
-        _ if matches_opcode(opcode, 0xF, None, Some(0x5), Some(0x5)) => Ok(format!("LD [I] V{:X}", x(opcode))),
-        _ if matches_opcode(opcode, 0xF, None, Some(0x6), Some(0x5)) => Ok(format!("LD V{:X} [I]", x(opcode))),
-        _ => Err(format!("{:X} | UNK", opcode)),
+    match Instruction::decode(opcode) {
+        Ok(Instruction::LoadILong(_)) => Ok("LD I LONG".into()),
+        Ok(instr) => Ok(instr.to_string()),
+        Err(_) => Err(format!("{:X} | UNK", opcode)),
     }
 }
 
-fn matches_opcode(opcode: u16, n1: u8, n2: Option<u8>, n3: Option<u8>, n4: Option<u8>) -> bool {
-    if (opcode >> 12) as u8 != n1 {
-        return false;
-    }
-    if let Some(n) = n2 {
-        if n as u16 != ((opcode & 0x0F00) >> 8) {
-            return false;
+/// Disassembles the instruction at `addr` in memory, as "opcode | instruction". Also returns the
+/// instruction's size in bytes, so a listing can move on to the next one.
+pub fn disassemble_memory_at(mem: &Memory, addr: usize) -> (String, usize) {
+    let opcode = match mem.read_opcode(addr) {
+        Ok(opcode) => opcode,
+        Err(_) => return ("---- | out of memory".into(), 2),
+    };
+    match Instruction::decode(opcode) {
+        Ok(Instruction::LoadILong(_)) => {
+            let long_addr = mem.read_opcode(addr + 2).unwrap_or(0);
+            return (format!("{:04X} {:04X} | {}", opcode, long_addr, Instruction::LoadILong(long_addr)), 4);
         }
+        Ok(instr) => return (format!("{:04X} | {}", opcode, instr), 2),
+        Err(_) => return (format!("{:04X} | UNK", opcode), 2),
     }
-    if let Some(n) = n3 {
-        if n as u16 != ((opcode & 0x00F0) >> 4) {
-            return false;
-        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listings_stop_at_data() {
+        //LD V0 5, JP 202, then sprite rows that happen to read as 0nnn
+        let program = [0x60, 0x05, 0x12, 0x02, 0x01, 0x80, 0x60, 0x01];
+        assert_eq!(disassemble_program_at(&program, 0), "200 | 6005 | LD V0 5\n202 | 1202 | JP 202\n204 | 180 | SYS 180\n");
+        //E0 00 doesn't decode at all
+        assert_eq!(disassemble_program_at(&[0x00, 0xE0, 0xE0, 0x00, 0x00, 0xE0], 0), "200 | E0 | CLS\n202 | E000 | UNK\n");
+        assert_eq!(disassemble_program_at(&[0x00, 0xE0, 0x12], 0), "200 | E0 | CLS\n202 | Standalone byte 12\n");
     }
-    if let Some(n) = n4 {
-        if n as u16 != (opcode & 0x000F) {
-            return false;
-        }
+
+    #[test]
+    fn long_loads_take_both_words() {
+        let program = [0xF0, 0x00, 0x12, 0x34, 0x00, 0xE0];
+        assert_eq!(disassemble_program_at(&program, 0), "200 | F000 1234 | LD I 1234\n204 | E0 | CLS\n");
+        let mem = Memory::with_prog(&program, crate::memory::XO_MEMSIZE).unwrap();
+        assert_eq!(disassemble_memory_at(&mem, PROG_START_ADDR), ("F000 1234 | LD I 1234".to_string(), 4));
+        assert_eq!(disassemble_memory_at(&mem, PROG_START_ADDR + 4), ("00E0 | CLS".to_string(), 2));
     }
-    return true;
 }
@@ -1,82 +1,513 @@
-use std::iter::repeat;
+use serde::{Deserialize, Serialize};
 
-use crate::config::CHIP8_CONFIG;
+use crate::config::Chip8Config;
 
-#[derive(Debug, Clone)]
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+/// XO-CHIP has two bitplanes, so each pixel is one of four colors
+pub const NUM_PLANES: usize = 2;
+
+/// Pixels of one plane packed into words, `stride` words per row. The leftmost pixel of a row is the most
+/// significant bit of its first word.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DisplayData {
     pub width: usize,
     pub height: usize,
-    backing_arr: Vec<bool>,
+    /// Packed rows for each plane
+    planes: [Vec<u64>; NUM_PLANES],
+    /// Planes that drawing, clearing and scrolling act on (XO-CHIP Fn01)
+    plane_mask: u8,
+    /// Area changed since the last `take_changes`
+    #[serde(skip)]
+    dirty: Option<DirtyRect>,
+}
+
+/// A rectangle of pixels, in the display's current resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
 }
 
 impl DisplayData {
     fn new(width: usize, height: usize) -> DisplayData {
+        let words = width / 64 * height;
         DisplayData {
             width,
             height,
-            backing_arr: vec![false; width * height],
+            planes: [vec![0; words], vec![0; words]],
+            plane_mask: 1,
+            //so the first frame gets drawn in full
+            dirty: Some(DirtyRect { x: 0, y: 0, width, height }),
         }
     }
 
     pub fn new_64x32() -> DisplayData {
-        DisplayData::new(64, 32)
+        DisplayData::new(LORES_WIDTH, LORES_HEIGHT)
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.width == HIRES_WIDTH
+    }
+
+    /// Switches between the 64x32 and the SUPER-CHIP 128x64 mode. Switching clears the screen.
+    pub fn set_hires(&mut self, hires: bool) {
+        let (width, height) = if hires {
+            (HIRES_WIDTH, HIRES_HEIGHT)
+        } else {
+            (LORES_WIDTH, LORES_HEIGHT)
+        };
+        let plane_mask = self.plane_mask;
+        *self = DisplayData::new(width, height);
+        self.plane_mask = plane_mask;
+    }
+
+    /// Checks a display that didn't come from `new_64x32`, e.g. one from a save state, for a size the
+    /// drawing code can't handle.
+    pub fn validate(&self) -> Result<(), String> {
+        if (self.width, self.height) != (LORES_WIDTH, LORES_HEIGHT) && (self.width, self.height) != (HIRES_WIDTH, HIRES_HEIGHT) {
+            return Err(format!("Display is {}x{}, expected 64x32 or 128x64", self.width, self.height));
+        }
+        let words = self.stride() * self.height;
+        if let Some(plane) = self.planes.iter().position(|plane| plane.len() != words) {
+            return Err(format!("Display plane {} holds {} words, expected {}", plane, self.planes[plane].len(), words));
+        }
+        return Ok(());
+    }
+
+    /// Words per row in `rows` and `plane_rows`: 1 in 64 pixel wide modes, 2 in 128 pixel wide ones.
+    pub fn stride(&self) -> usize {
+        self.width / 64
+    }
+
+    /// Plane 0, the only plane outside of XO-CHIP, as packed rows.
+    pub fn rows(&self) -> &[u64] {
+        &self.planes[0]
+    }
+
+    pub fn plane_rows(&self, plane: usize) -> &[u64] {
+        &self.planes[plane]
+    }
+
+    /// A row of a plane, left-aligned in a u128 so that both widths can be handled the same way.
+    fn get_row(&self, plane: usize, y: usize) -> u128 {
+        let stride = self.stride();
+        let words = &self.planes[plane][y * stride..(y + 1) * stride];
+        return words.iter().enumerate().fold(0, |row, (i, &word)| row | (word as u128) << (64 * (1 - i)));
+    }
+
+    fn set_row(&mut self, plane: usize, y: usize, row: u128) {
+        let stride = self.stride();
+        let words = &mut self.planes[plane][y * stride..(y + 1) * stride];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = (row >> (64 * (1 - i))) as u64;
+        }
+    }
+
+    /// Bits of a left-aligned row that are on screen.
+    fn screen_mask(&self) -> u128 {
+        !0u128 << (128 - self.width)
+    }
+
+    /// Pixel colors row by row, as returned by `get_pixel_color`.
+    pub fn pixels(&self) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            pixels.extend((0..self.width).map(|x| self.get_pixel_color(x, y)));
+        }
+        return pixels;
+    }
+
+    /// Replaces the whole screen, e.g. from a snapshot. `pixels` holds `width * height` colors.
+    pub fn set_pixels(&mut self, width: usize, height: usize, pixels: Vec<u8>) {
+        assert_eq!(pixels.len(), width * height);
+        let plane_mask = self.plane_mask;
+        *self = DisplayData::new(width, height);
+        self.plane_mask = plane_mask;
+        for (y, row_pixels) in pixels.chunks_exact(width).enumerate() {
+            for plane in 0..NUM_PLANES {
+                let row = row_pixels
+                    .iter()
+                    .enumerate()
+                    .fold(0u128, |row, (x, &color)| row | ((color >> plane & 1) as u128) << (127 - x));
+                self.set_row(plane, y, row);
+            }
+        }
+    }
+
+    /// The smallest rectangle holding every pixel changed since the last call, or `None` if nothing changed.
+    pub fn take_changes(&mut self) -> Option<DirtyRect> {
+        self.dirty.take()
+    }
+
+    /// Reports the whole screen as changed on the next `take_changes`.
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty = Some(DirtyRect { x: 0, y: 0, width: self.width, height: self.height });
+    }
+
+    /// Grows the dirty rectangle to cover row `y` from `x` to `right`, exclusive.
+    fn mark_dirty(&mut self, x: usize, right: usize, y: usize) {
+        self.dirty = Some(match self.dirty {
+            None => DirtyRect { x, y, width: right - x, height: 1 },
+            Some(rect) => {
+                let left = rect.x.min(x);
+                let top = rect.y.min(y);
+                let right = (rect.x + rect.width).max(right);
+                let bottom = (rect.y + rect.height).max(y + 1);
+                DirtyRect { x: left, y: top, width: right - left, height: bottom - top }
+            }
+        });
+    }
+
+    pub fn plane_mask(&self) -> u8 {
+        self.plane_mask
+    }
+
+    pub fn select_planes(&mut self, mask: u8) {
+        self.plane_mask = mask & ((1 << NUM_PLANES) - 1);
     }
 
+    /// True if the pixel is lit on any plane.
     pub fn get_pixel(&self, x: usize, y: usize) -> bool {
-        self.backing_arr[x + y * self.width]
+        self.get_pixel_color(x, y) != 0
     }
 
-    fn set_pixel(&mut self, x: usize, y: usize, val: bool) {
-        self.backing_arr[x + y * self.width] = val
+    /// The pixel's color index, 0 to 3. Bit N is set if the pixel is lit on plane N.
+    pub fn get_pixel_color(&self, x: usize, y: usize) -> u8 {
+        let index = y * self.stride() + x / 64;
+        let shift = 63 - x % 64;
+        return (0..NUM_PLANES).fold(0, |color, plane| color | (((self.planes[plane][index] >> shift) & 1) as u8) << plane);
     }
 
-    pub fn clear(&mut self) {
-        self.backing_arr.iter_mut().for_each(|e| *e = false)
+    fn selected_planes(&self) -> impl Iterator<Item = usize> {
+        let mask = self.plane_mask;
+        (0..NUM_PLANES).filter(move |plane| mask & (1 << plane) != 0)
     }
 
-    pub fn draw(&mut self, sprite: &[u8], mut x: usize, mut y: usize) -> bool {
-        fn get_sprite_pixel(sprite: &[u8], sprite_x: usize, sprite_y: usize) -> bool {
-            let byte = sprite[sprite_y];
-            return (byte >> (7 - sprite_x)) & 1 != 0;
+    /// Clears the selected planes.
+    pub fn clear(&mut self) {
+        for plane in self.selected_planes() {
+            self.planes[plane].iter_mut().for_each(|w| *w = 0);
         }
+        self.mark_all_dirty();
+    }
 
-        x = x % self.width;
-        y = y % self.height;
-        let mut collision = false;
+    /// Draws an 8 pixel wide sprite, one byte per row, on every selected plane.
+    ///
+    /// With more than one plane selected, the sprite data for each plane follows the previous plane's.
+    /// Returns the number of rows that collided.
+    pub fn draw(&mut self, sprite: &[u8], x: usize, y: usize, config: &Chip8Config) -> usize {
+        self.draw_planes(sprite, 1, x, y, config)
+    }
 
-        for y_offset in 0..sprite.len() {
-            for x_offset in 0..8 {
-                let pixel_val = get_sprite_pixel(sprite, x_offset, y_offset);
-                let mut pixel_x = x + x_offset;
-                let mut pixel_y = y + y_offset;
+    /// Draws a 16 pixel wide SUPER-CHIP sprite, two bytes per row. Otherwise the same as `draw`.
+    pub fn draw_wide(&mut self, sprite: &[u8], x: usize, y: usize, config: &Chip8Config) -> usize {
+        self.draw_planes(sprite, 2, x, y, config)
+    }
 
-                if !CHIP8_CONFIG.sprite_clipping {
-                    pixel_x = pixel_x % self.width;
-                    pixel_y = pixel_y % self.height;
-                }
+    pub fn selected_plane_count(&self) -> usize {
+        self.plane_mask.count_ones() as usize
+    }
 
-                if pixel_x < self.width && pixel_y < self.height {
-                    let old_data = self.get_pixel(pixel_x, pixel_y);
-                    let new_data = old_data ^ pixel_val;
-                    if old_data && !new_data {
-                        collision = true;
-                    }
-                    self.set_pixel(pixel_x, pixel_y, new_data);
+    fn draw_planes(&mut self, sprite: &[u8], bytes_per_row: usize, x: usize, y: usize, config: &Chip8Config) -> usize {
+        let planes = self.selected_plane_count();
+        if planes == 0 {
+            return 0;
+        }
+        let plane_len = sprite.len() / planes;
+        let mut collided_rows = 0;
+        let mut plane_sprites = sprite.chunks_exact(plane_len.max(1));
+        for plane in self.selected_planes().collect::<Vec<usize>>() {
+            let plane_sprite = plane_sprites.next().unwrap_or(&[]);
+            collided_rows = collided_rows.max(self.draw_sprite(plane_sprite, bytes_per_row, x, y, plane, config));
+        }
+        return collided_rows;
+    }
+
+    fn draw_sprite(&mut self, sprite: &[u8], bytes_per_row: usize, x: usize, y: usize, plane: usize, config: &Chip8Config) -> usize {
+        let x = x % self.width;
+        let y = y % self.height;
+        let sprite_width = bytes_per_row * 8;
+        let screen_mask = self.screen_mask();
+        let mut collided_rows = 0;
+
+        for (y_offset, sprite_row) in sprite.chunks_exact(bytes_per_row).enumerate() {
+            let mut row_y = y + y_offset;
+            if row_y >= self.height {
+                if config.sprite_clipping {
+                    break;
                 }
+                row_y %= self.height;
+            }
+            //line the sprite row up with the left edge of the screen, then move it to x
+            let bits = sprite_row.iter().fold(0u128, |bits, &byte| bits << 8 | byte as u128) << (128 - sprite_width);
+            let mut placed = bits >> x;
+            if !config.sprite_clipping {
+                //the part that went past the right edge comes back in on the left
+                placed |= bits.checked_shl((self.width - x) as u32).unwrap_or(0);
+            }
+            placed &= screen_mask;
+            if placed == 0 {
+                continue;
+            }
+            let row = self.get_row(plane, row_y);
+            collided_rows += (row & placed != 0) as usize;
+            self.set_row(plane, row_y, row ^ placed);
+            self.mark_dirty(placed.leading_zeros() as usize, 128 - placed.trailing_zeros() as usize, row_y);
+        }
+        return collided_rows;
+    }
+
+    /// Moves the selected planes by (dx, dy) pixels. Pixels moved off screen are lost, and the gap is left blank.
+    /// The public scrolls cap the amount at the hires screen size, as anything more just clears the screen.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let screen_mask = self.screen_mask();
+        for plane in self.selected_planes().collect::<Vec<usize>>() {
+            let old: Vec<u128> = (0..self.height).map(|y| self.get_row(plane, y)).collect();
+            for y in 0..self.height {
+                let src_y = y as isize - dy;
+                let row = match old.get(src_y as usize) {
+                    Some(&row) if src_y >= 0 => row,
+                    _ => 0,
+                };
+                //a shift by the whole row width or more moves every pixel off screen
+                let moved = if dx >= 0 {
+                    row.checked_shr(dx as u32).unwrap_or(0)
+                } else {
+                    row.checked_shl(dx.unsigned_abs() as u32).unwrap_or(0)
+                };
+                self.set_row(plane, y, moved & screen_mask);
             }
         }
-        return collision;
+        self.mark_all_dirty();
+    }
+
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n.min(HIRES_HEIGHT) as isize);
+    }
+
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n.min(HIRES_HEIGHT) as isize));
+    }
+
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n.min(HIRES_WIDTH) as isize, 0);
+    }
+
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n.min(HIRES_WIDTH) as isize), 0);
     }
 
     pub fn debug_print(&self) {
-        let line: String = repeat('_').take(self.width).collect();
+        let line = "_".repeat(self.width);
         println!("{}", line);
         for j in 0..self.height {
             for i in 0..self.width {
-                print!("{}", if self.get_pixel(i, j) { '#' } else { '.' });
+                let c = match self.get_pixel_color(i, j) {
+                    0 => '.',
+                    1 => '#',
+                    2 => '+',
+                    _ => '@',
+                };
+                print!("{}", c);
             }
-            print!("\n");
+            println!();
         }
         println!("{}", line);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One bool per pixel, drawn and scrolled the slow and obvious way.
+    struct Reference {
+        width: usize,
+        height: usize,
+        pixels: Vec<bool>,
+    }
+
+    impl Reference {
+        fn new(width: usize, height: usize) -> Reference {
+            Reference { width, height, pixels: vec![false; width * height] }
+        }
+
+        fn draw(&mut self, sprite: &[u8], bytes_per_row: usize, x: usize, y: usize, clipping: bool) -> usize {
+            let (x, y) = (x % self.width, y % self.height);
+            let mut collided_rows = 0;
+            for (row, bytes) in sprite.chunks_exact(bytes_per_row).enumerate() {
+                let mut collided = false;
+                for col in 0..bytes_per_row * 8 {
+                    if bytes[col / 8] & (0x80 >> (col % 8)) == 0 {
+                        continue;
+                    }
+                    let (mut px, mut py) = (x + col, y + row);
+                    if clipping && (px >= self.width || py >= self.height) {
+                        continue;
+                    }
+                    px %= self.width;
+                    py %= self.height;
+                    let pixel = &mut self.pixels[py * self.width + px];
+                    collided |= *pixel;
+                    *pixel = !*pixel;
+                }
+                collided_rows += collided as usize;
+            }
+            return collided_rows;
+        }
+
+        fn scroll(&mut self, dx: isize, dy: isize) {
+            let old = self.pixels.clone();
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    let (src_x, src_y) = (x as isize - dx, y as isize - dy);
+                    let inside = (0..self.width as isize).contains(&src_x) && (0..self.height as isize).contains(&src_y);
+                    self.pixels[y * self.width + x] = inside && old[src_y as usize * self.width + src_x as usize];
+                }
+            }
+        }
+    }
+
+    fn display(hires: bool) -> (DisplayData, Reference) {
+        let mut disp = DisplayData::new_64x32();
+        disp.set_hires(hires);
+        let reference = Reference::new(disp.width, disp.height);
+        return (disp, reference);
+    }
+
+    fn assert_same(disp: &DisplayData, reference: &Reference) {
+        for y in 0..disp.height {
+            for x in 0..disp.width {
+                assert_eq!(disp.get_pixel(x, y), reference.pixels[y * disp.width + x], "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    /// Rows lit at either edge, in the middle, and in lopsided patterns, so a bit landing in the wrong column shows up.
+    const SPRITE: [u8; 15] = [0x80, 0x01, 0xFF, 0x81, 0xA5, 0x5A, 0x3C, 0xC3, 0x18, 0x0F, 0xF0, 0x99, 0x42, 0x07, 0xE0];
+
+    /// The same for 16 pixel wide sprites, two bytes per row.
+    const WIDE_SPRITE: [u8; 32] = [
+        0x80, 0x01, 0xFF, 0xFF, 0x80, 0x00, 0x00, 0x01, 0xA5, 0xA5, 0x5A, 0x5A, 0xF0, 0x0F, 0x0F, 0xF0,
+        0x12, 0x34, 0x84, 0x21, 0xC0, 0x03, 0x3F, 0xFC, 0x01, 0x80, 0x40, 0x02, 0x20, 0x04, 0xFE, 0x7F,
+    ];
+
+    fn config(clipping: bool) -> Chip8Config {
+        Chip8Config { sprite_clipping: clipping, ..Chip8Config::default() }
+    }
+
+    #[test]
+    fn sprites_match_the_reference_everywhere() {
+        for hires in [false, true] {
+            for clipping in [false, true] {
+                let (mut disp, mut reference) = display(hires);
+                let config = config(clipping);
+                let mut n = 0;
+                for y in (0..disp.height + 4).step_by(3) {
+                    for x in (0..disp.width + 8).step_by(5) {
+                        //every height from 1 to 15 rows
+                        n = n % SPRITE.len() + 1;
+                        let data = &SPRITE[..n];
+                        let expected = reference.draw(data, 1, x, y, clipping);
+                        assert_eq!(disp.draw(data, x, y, &config), expected, "collisions at ({}, {})", x, y);
+                    }
+                }
+                assert_same(&disp, &reference);
+            }
+        }
+    }
+
+    #[test]
+    fn sprites_wrap_at_the_right_edge() {
+        for (hires, x) in [(false, 60), (true, 124)] {
+            let (mut disp, mut reference) = display(hires);
+            let data = [0xFF, 0x81, 0xA5, 0xFF];
+            reference.draw(&data, 1, x, 2, false);
+            disp.draw(&data, x, 2, &config(false));
+            assert_same(&disp, &reference);
+            assert!(disp.get_pixel(0, 2) && disp.get_pixel(3, 2) && !disp.get_pixel(4, 2));
+        }
+    }
+
+    #[test]
+    fn sprites_clip_at_the_edges() {
+        let (mut disp, mut reference) = display(false);
+        let data = [0xFF; 8];
+        reference.draw(&data, 1, 60, 28, true);
+        disp.draw(&data, 60, 28, &config(true));
+        assert_same(&disp, &reference);
+        assert!(!disp.get_pixel(0, 28) && !disp.get_pixel(60, 0));
+    }
+
+    #[test]
+    fn collisions_count_rows() {
+        let (mut disp, _) = display(false);
+        let config = config(false);
+        assert_eq!(disp.draw(&[0x80, 0x00, 0x01, 0x10], 10, 10, &config), 0);
+        //rows 0 and 3 land on lit pixels, row 1 on an empty row
+        assert_eq!(disp.draw(&[0x80, 0xFF, 0x00, 0x10], 10, 10, &config), 2);
+        //wrapped back in on the left, onto pixels lit by nothing
+        assert_eq!(disp.draw(&[0xFF], 60, 20, &config), 0);
+        assert_eq!(disp.draw(&[0x0F], 60, 20, &config), 1);
+    }
+
+    #[test]
+    fn wide_sprites_match_the_reference_in_hires() {
+        for clipping in [false, true] {
+            let (mut disp, mut reference) = display(true);
+            let config = config(clipping);
+            for (x, y) in [(0, 0), (7, 3), (56, 24), (112, 48), (120, 56), (127, 63), (130, 70), (5, 2)] {
+                let expected = reference.draw(&WIDE_SPRITE, 2, x, y, clipping);
+                assert_eq!(disp.draw_wide(&WIDE_SPRITE, x, y, &config), expected, "collisions at ({}, {})", x, y);
+            }
+            assert_same(&disp, &reference);
+        }
+    }
+
+    #[test]
+    fn scrolling_matches_the_reference() {
+        for hires in [false, true] {
+            let (mut disp, mut reference) = display(hires);
+            let config = config(false);
+            for i in 0..40 {
+                let (x, y) = (i * 13, i * 7);
+                reference.draw(&SPRITE, 1, x, y, false);
+                disp.draw(&SPRITE, x, y, &config);
+            }
+            for (dx, dy) in [(0, 4), (4, 0), (-4, 0), (0, -1), (0, 1), (0, 63)] {
+                match (dx, dy) {
+                    (0, dy) if dy > 0 => disp.scroll_down(dy as usize),
+                    (0, dy) => disp.scroll_up(-dy as usize),
+                    (dx, _) if dx > 0 => disp.scroll_right(dx as usize),
+                    (dx, _) => disp.scroll_left(-dx as usize),
+                }
+                reference.scroll(dx, dy);
+                assert_same(&disp, &reference);
+            }
+        }
+    }
+
+    #[test]
+    fn scrolling_a_whole_screen_or_more_clears_it() {
+        let scrolls: [fn(&mut DisplayData); 6] = [
+            |disp| disp.scroll_right(128),
+            |disp| disp.scroll_left(128),
+            |disp| disp.scroll_right(usize::MAX),
+            |disp| disp.scroll_left(1000),
+            |disp| disp.scroll_down(64),
+            |disp| disp.scroll_up(usize::MAX),
+        ];
+        for scroll in scrolls {
+            let mut disp = DisplayData::new_64x32();
+            disp.set_hires(true);
+            disp.draw_wide(&[0xFF; 32], 60, 20, &config(true));
+            scroll(&mut disp);
+            assert!(disp.pixels().iter().all(|&pixel| pixel == 0));
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+
+/// A fault raised while loading or executing a program.
+///
+/// Faults raised by an instruction carry the address and opcode of the instruction that caused them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmulationError {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    ZeroOpcode { pc: u16 },
+    StackOverflow { pc: u16, opcode: u16 },
+    StackUnderflow { pc: u16, opcode: u16 },
+    MemoryOutOfBounds { pc: u16, opcode: u16, addr: usize },
+    RomTooLarge { size: usize, max: usize },
+}
+
+impl EmulationError {
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            EmulationError::UnknownOpcode { pc, .. }
+            | EmulationError::ZeroOpcode { pc }
+            | EmulationError::StackOverflow { pc, .. }
+            | EmulationError::StackUnderflow { pc, .. }
+            | EmulationError::MemoryOutOfBounds { pc, .. } => Some(pc),
+            EmulationError::RomTooLarge { .. } => None,
+        }
+    }
+
+    pub fn opcode(&self) -> Option<u16> {
+        match *self {
+            EmulationError::UnknownOpcode { opcode, .. }
+            | EmulationError::StackOverflow { opcode, .. }
+            | EmulationError::StackUnderflow { opcode, .. }
+            | EmulationError::MemoryOutOfBounds { opcode, .. } => Some(opcode),
+            EmulationError::ZeroOpcode { .. } => Some(0x0000),
+            EmulationError::RomTooLarge { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulationError::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown opcode {:04X} at addr {:X}", opcode, pc)
+            }
+            EmulationError::ZeroOpcode { pc } => {
+                write!(f, "Tried to execute 0000 (probably uninit memory) at addr {:X}", pc)
+            }
+            EmulationError::StackOverflow { pc, opcode } => {
+                write!(f, "Stack overflow on {:04X} at addr {:X}", opcode, pc)
+            }
+            EmulationError::StackUnderflow { pc, opcode } => {
+                write!(f, "Stack underflow on {:04X} at addr {:X}", opcode, pc)
+            }
+            EmulationError::MemoryOutOfBounds { pc, opcode, addr } => write!(
+                f,
+                "Memory access out of bounds ({:X}) on {:04X} at addr {:X}",
+                addr, opcode, pc
+            ),
+            EmulationError::RomTooLarge { size, max } => write!(
+                f,
+                "Program is too big to fit in memory! Size: {} Space: {}",
+                size, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EmulationError {}
@@ -0,0 +1,470 @@
+use std::io::{self, BufReader, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::breakpoints::{BreakpointHit, BreakpointKind};
+use crate::error::EmulationError;
+use crate::machine::Machine;
+
+/// Size in bytes of each register in `g` and `p` packets, in GDB register number order:
+/// V0-VF, I, PC, SP, DT, ST. Multi-byte registers are big-endian, like CHIP-8 memory.
+const REG_SIZES: [usize; 21] = [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1];
+const REG_NAMES: [&str; 21] = [
+    "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7", "v8", "v9", "va", "vb", "vc", "vd", "ve", "vf", "i", "pc", "sp",
+    "dt", "st",
+];
+
+/// Largest packet we accept, advertised in qSupported
+const PACKET_SIZE: usize = 0x4000;
+/// Most bytes an m or M packet may cover; each byte takes two hex digits
+const MAX_MEM_LEN: usize = PACKET_SIZE / 2 - 16;
+
+/// Sent by GDB to stop a running target (Ctrl-C)
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Why the target stopped, as reported to GDB.
+enum Stop {
+    Signal(u8),
+    Breakpoint(BreakpointHit),
+    Exited,
+}
+
+/// A GDB remote serial protocol server for one machine.
+///
+/// Serves registers through a target description, memory reads and writes, software and hardware
+/// breakpoints, watchpoints, single-step and continue. Connect with `target remote` over TCP, or run
+/// the stub as a pipe with `target remote | chip8-gdb --stdio rom.ch8`.
+pub struct GdbStub {
+    machine: Machine,
+    /// Paces `continue` to 60 frames per second, otherwise it runs as fast as it can
+    realtime: bool,
+    /// Machine breakpoint ids for GDB's Z packets, by (type, address)
+    inserted: Vec<(u8, u16, usize)>,
+}
+
+/// Packets coming in from the client, read on their own thread so `continue` can poll for interrupts.
+struct Connection<W: Write> {
+    incoming: Receiver<u8>,
+    writer: W,
+    no_ack: bool,
+}
+
+impl<W: Write> Connection<W> {
+    fn new<R: Read + Send + 'static>(reader: R, writer: W) -> Connection<W> {
+        let (tx, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in BufReader::new(reader).bytes() {
+                match byte {
+                    Ok(byte) if tx.send(byte).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        Connection { incoming, writer, no_ack: false }
+    }
+
+    /// Waits for the next packet. Returns `None` once the client disconnects.
+    fn read_packet(&mut self) -> Option<Vec<u8>> {
+        loop {
+            //acks and interrupts while already stopped are ignored
+            while self.incoming.recv().ok()? != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.incoming.recv().ok()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.incoming.recv().ok()?, self.incoming.recv().ok()?];
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            if self.no_ack {
+                return Some(data);
+            }
+            if expected == Some(checksum_of(&data)) {
+                self.writer.write_all(b"+").ok()?;
+                self.writer.flush().ok()?;
+                return Some(data);
+            }
+            self.writer.write_all(b"-").ok()?;
+            self.writer.flush().ok()?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.writer, "${}#{:02x}", data, checksum_of(data.as_bytes()))?;
+        self.writer.flush()?;
+        if self.no_ack {
+            return Ok(());
+        }
+        //wait for the ack, and resend if the client asks for it
+        loop {
+            match self.incoming.recv() {
+                Ok(b'+') => return Ok(()),
+                Ok(b'-') => return self.send(data),
+                Ok(_) => {}
+                Err(_) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+
+    /// True if the client sent an interrupt since the last call.
+    fn interrupted(&mut self) -> bool {
+        loop {
+            match self.incoming.try_recv() {
+                Ok(INTERRUPT) => return true,
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => return false,
+                //a disconnected client can't resume us, so stop
+                Err(TryRecvError::Disconnected) => return true,
+            }
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect();
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// "addr,len" as used by m, M and Z packets.
+fn parse_addr_len(s: &str) -> Option<(usize, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    return Some((parse_hex(addr)?, parse_hex(len)?));
+}
+
+/// "addr,len" of an m or M packet, if it lies in the 16 bit address space and fits in a packet.
+fn parse_mem_range(s: &str) -> Option<(usize, usize)> {
+    let (addr, len) = parse_addr_len(s)?;
+    if addr > 0xFFFF || len > MAX_MEM_LEN {
+        return None;
+    }
+    return Some((addr, len));
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n<feature name=\"org.chip8.core\">\n",
+    );
+    for (name, size) in REG_NAMES.iter().zip(REG_SIZES) {
+        let kind = match *name {
+            "pc" => "code_ptr",
+            "i" => "data_ptr",
+            _ if size == 2 => "uint16",
+            _ => "uint8",
+        };
+        xml += &format!("<reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>\n", name, size * 8, kind);
+    }
+    xml += "</feature>\n</target>\n";
+    return xml;
+}
+
+impl GdbStub {
+    pub fn new(machine: Machine) -> GdbStub {
+        GdbStub { machine, realtime: true, inserted: Vec::new() }
+    }
+
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn into_machine(self) -> Machine {
+        self.machine
+    }
+
+    /// Talks to one client until it detaches, kills the target or disconnects.
+    pub fn serve<R: Read + Send + 'static, W: Write>(&mut self, reader: R, writer: W) -> io::Result<()> {
+        let mut conn = Connection::new(reader, writer);
+        while let Some(packet) = conn.read_packet() {
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            if !self.handle(&mut conn, &packet)? {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Answers one packet. Returns Ok(false) when the session is over.
+    fn handle<W: Write>(&mut self, conn: &mut Connection<W>, packet: &str) -> io::Result<bool> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop_reply(&Stop::Signal(SIGTRAP)),
+            Some(b'g') => to_hex(&self.read_registers()),
+            Some(b'G') => match from_hex(&packet[1..]) {
+                Some(bytes) if bytes.len() == REG_SIZES.iter().sum::<usize>() => {
+                    let mut offset = 0;
+                    for (reg, size) in REG_SIZES.iter().enumerate() {
+                        self.write_register(reg, &bytes[offset..offset + size]);
+                        offset += size;
+                    }
+                    "OK".into()
+                }
+                _ => "E01".into(),
+            },
+            Some(b'p') => match parse_hex(&packet[1..]).filter(|&reg| reg < REG_SIZES.len()) {
+                Some(reg) => {
+                    let regs = self.read_registers();
+                    let offset: usize = REG_SIZES[..reg].iter().sum();
+                    to_hex(&regs[offset..offset + REG_SIZES[reg]])
+                }
+                None => "E01".into(),
+            },
+            Some(b'P') => {
+                let parsed = packet[1..]
+                    .split_once('=')
+                    .and_then(|(reg, value)| Some((parse_hex(reg)?, from_hex(value)?)));
+                match parsed {
+                    Some((reg, value)) if reg < REG_SIZES.len() && value.len() == REG_SIZES[reg] => {
+                        self.write_register(reg, &value);
+                        "OK".into()
+                    }
+                    _ => "E01".into(),
+                }
+            }
+            Some(b'm') => {
+                let read = parse_mem_range(&packet[1..])
+                    .and_then(|(addr, len)| self.machine.cpu_state().mem.read_range(addr, len).ok());
+                match read {
+                    Some(bytes) => to_hex(&bytes),
+                    None => "E01".into(),
+                }
+            }
+            Some(b'M') => {
+                let parsed = packet[1..]
+                    .split_once(':')
+                    .and_then(|(addr_len, data)| Some((parse_mem_range(addr_len)?, from_hex(data)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len => {
+                        match self.machine.cpu_state_mut().mem.write_range(addr, &data) {
+                            Ok(()) => "OK".into(),
+                            Err(_) => "E01".into(),
+                        }
+                    }
+                    _ => "E01".into(),
+                }
+            }
+            Some(b'Z') => self.insert_breakpoint(&packet[1..]),
+            Some(b'z') => self.remove_breakpoint(&packet[1..]),
+            Some(b'c') => {
+                let stop = self.resume(conn, false);
+                self.stop_reply(&stop)
+            }
+            Some(b's') => {
+                let stop = self.resume(conn, true);
+                self.stop_reply(&stop)
+            }
+            Some(b'k') => return Ok(false),
+            Some(b'D') => {
+                conn.send("OK")?;
+                return Ok(false);
+            }
+            Some(b'H') => "OK".into(),
+            _ if packet == "QStartNoAckMode" => {
+                //the OK itself still gets acked, so only switch over after sending it
+                conn.send("OK")?;
+                conn.no_ack = true;
+                return Ok(true);
+            }
+            _ => self.query(conn, packet),
+        };
+        conn.send(&reply)?;
+        return Ok(true);
+    }
+
+    /// q and v packets. Unknown packets get an empty reply, which tells GDB they aren't supported.
+    fn query<W: Write>(&mut self, conn: &mut Connection<W>, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+;vContSupported+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_xml();
+            let (offset, len) = match parse_addr_len(range) {
+                Some(range) => range,
+                None => return "E01".into(),
+            };
+            let chunk = xml.get(offset.min(xml.len())..(offset + len).min(xml.len())).unwrap_or("");
+            let more = offset + len < xml.len();
+            return format!("{}{}", if more { "m" } else { "l" }, chunk);
+        }
+        match packet {
+            "qAttached" => return "1".into(),
+            "qC" => return "QC1".into(),
+            "qfThreadInfo" => return "m1".into(),
+            "qsThreadInfo" => return "l".into(),
+            "vCont?" => return "vCont;c;C;s;S".into(),
+            "vMustReplyEmpty" => return String::new(),
+            _ => {}
+        }
+        if let Some(actions) = packet.strip_prefix("vCont;") {
+            //there is one thread, so the first action is the one that applies to it
+            let step = matches!(actions.as_bytes().first(), Some(b's' | b'S'));
+            let stop = self.resume(conn, step);
+            return self.stop_reply(&stop);
+        }
+        return String::new();
+    }
+
+    fn read_registers(&self) -> Vec<u8> {
+        let cpu = self.machine.cpu_state();
+        let mut regs = cpu.v.to_vec();
+        regs.extend_from_slice(&cpu.i.to_be_bytes());
+        regs.extend_from_slice(&cpu.pc.to_be_bytes());
+        regs.extend_from_slice(&[cpu.sp, cpu.dt, cpu.st]);
+        return regs;
+    }
+
+    fn write_register(&mut self, reg: usize, value: &[u8]) {
+        let cpu = self.machine.cpu_state_mut();
+        match reg {
+            0..=15 => cpu.v[reg] = value[0],
+            16 => cpu.i = u16::from_be_bytes([value[0], value[1]]),
+            17 => cpu.pc = u16::from_be_bytes([value[0], value[1]]),
+            18 => cpu.sp = value[0],
+            19 => cpu.dt = value[0],
+            _ => cpu.st = value[0],
+        }
+    }
+
+    /// Z packets: 0 and 1 are software and hardware breakpoints, 2 to 4 are write, read and access watchpoints.
+    fn insert_breakpoint(&mut self, args: &str) -> String {
+        let parsed = args.split_once(',').and_then(|(kind, rest)| {
+            let (addr, len) = parse_addr_len(rest)?;
+            Some((kind.parse::<u8>().ok()?, u16::try_from(addr).ok()?, len))
+        });
+        let (kind, addr, len) = match parsed {
+            Some(parsed) => parsed,
+            None => return "E01".into(),
+        };
+        let end = addr.saturating_add(len.saturating_sub(1) as u16);
+        let kinds = match kind {
+            0 | 1 => vec![BreakpointKind::Pc(addr)],
+            2 => vec![BreakpointKind::Write { start: addr, end }],
+            3 => vec![BreakpointKind::Read { start: addr, end }],
+            4 => vec![BreakpointKind::Read { start: addr, end }, BreakpointKind::Write { start: addr, end }],
+            _ => return String::new(),
+        };
+        for bp_kind in kinds {
+            //unconditional breakpoints can't fail to parse
+            if let Ok(id) = self.machine.breakpoints_mut().add(bp_kind, None) {
+                self.inserted.push((kind, addr, id));
+            }
+        }
+        return "OK".into();
+    }
+
+    fn remove_breakpoint(&mut self, args: &str) -> String {
+        let parsed = args.split_once(',').and_then(|(kind, rest)| {
+            let (addr, _) = parse_addr_len(rest)?;
+            Some((kind.parse::<u8>().ok()?, addr))
+        });
+        let (kind, addr) = match parsed {
+            Some(parsed) => parsed,
+            None => return "E01".into(),
+        };
+        let breakpoints = self.machine.breakpoints_mut();
+        self.inserted.retain(|&(k, a, id)| {
+            if k == kind && a as usize == addr {
+                breakpoints.remove(id);
+                return false;
+            }
+            return true;
+        });
+        return "OK".into();
+    }
+
+    /// Steps one instruction, or runs until a breakpoint, fault, exit or interrupt.
+    fn resume<W: Write>(&mut self, conn: &mut Connection<W>, single_step: bool) -> Stop {
+        let frame_time = Duration::from_secs_f64(1.0 / 60.0);
+        let mut next_frame = Instant::now() + frame_time;
+        let mut frame = self.machine.frame_count();
+        //the first cycle is unchecked, so that resuming from a breakpoint doesn't hit it again
+        let mut first = true;
+        loop {
+            if self.machine.has_exited() {
+                return Stop::Exited;
+            }
+            //single steps ignore breakpoints, like GDB expects
+            let stepped = if first || single_step {
+                self.machine.step_instruction().map(|ran| (ran, None))
+            } else {
+                self.machine.run_cycles(1).map(|hit| (true, hit))
+            };
+            first = false;
+            match stepped {
+                Ok((_, Some(hit))) => return Stop::Breakpoint(hit),
+                Ok((ran_instr, None)) => {
+                    if single_step && ran_instr {
+                        return Stop::Signal(SIGTRAP);
+                    }
+                }
+                Err(err) => {
+                    //leave the fault for GDB to look at, and let it try again after patching things up
+                    self.machine.clear_fault();
+                    return Stop::Signal(fault_signal(&err));
+                }
+            }
+            if self.machine.frame_count() != frame {
+                frame = self.machine.frame_count();
+                if conn.interrupted() {
+                    return Stop::Signal(SIGINT);
+                }
+                if self.realtime {
+                    thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+                    next_frame += frame_time;
+                }
+            }
+        }
+    }
+
+    fn stop_reply(&self, stop: &Stop) -> String {
+        match stop {
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::Exited => "W00".into(),
+            Stop::Breakpoint(hit) => {
+                let inserted = self.inserted.iter().find(|&&(_, _, id)| id == hit.id);
+                let reason = match inserted {
+                    Some((0, _, _)) => "swbreak:".to_string(),
+                    Some((1, _, _)) => "hwbreak:".to_string(),
+                    Some((2, addr, _)) => format!("watch:{:x}", addr),
+                    Some((3, addr, _)) => format!("rwatch:{:x}", addr),
+                    Some((_, addr, _)) => format!("awatch:{:x}", addr),
+                    //set through the machine rather than by GDB
+                    None => return format!("S{:02x}", SIGTRAP),
+                };
+                format!("T{:02x}{};", SIGTRAP, reason)
+            }
+        }
+    }
+}
+
+fn fault_signal(err: &EmulationError) -> u8 {
+    match err {
+        EmulationError::UnknownOpcode { .. } | EmulationError::ZeroOpcode { .. } => SIGILL,
+        _ => SIGSEGV,
+    }
+}
@@ -1,155 +1,284 @@
 #![allow(non_snake_case)]
 
-use rand::Rng;
-
-use crate::config::CHIP8_CONFIG;
+use crate::config::{IndexIncrement, InstructionSet};
 use crate::cpu::{CPUState, HaltStatus};
+use crate::decode::{self, Instruction};
+use crate::error::EmulationError;
 use crate::keyboard::Fx0AStatus;
 use crate::memory::Memory;
 
-pub const OUTER_FUNC_TABLE: [fn(&mut CPUState); 0x10] = [
-    op_0_innerlookup,
-    op_1nnn,
-    op_2nnn,
-    op_3xkk,
-    op_4xkk,
-    op_5xy0,
-    op_6xkk,
-    op_7xkk,
-    op_8_innerlookup,
-    op_9xy0,
-    op_Annn,
-    op_Bnnn,
-    op_Cxkk,
-    op_Dxyn,
-    op_E_innerlookup,
-    op_F_innerlookup,
-];
-
-fn op_0_innerlookup(cpu: &mut CPUState) {
-    match cpu.d_addr() {
-        0x00E0 => op_00E0(cpu),
-        0x00EE => op_00EE(cpu),
-        0x0000 => panic!(
-            "Tried to execute 0000! (Probably uninit memory) at addr {:X} {:X}",
-            cpu.pc,
-            cpu.get_opcode()
-        ),
-        _ => op_0nnn(cpu),
-    }
-}
-
-fn op_0nnn(cpu: &mut CPUState) {
+pub type OpResult = Result<(), EmulationError>;
+
+/// Executes a decoded instruction.
+///
+/// Instructions from a newer instruction set than the config allows are rejected, except for the 0nnn ones,
+/// which the original interpreter ran as SYS calls.
+pub fn execute(cpu: &mut CPUState, instr: Instruction) -> OpResult {
+    use Instruction::*;
+    if instr.instruction_set() > cpu.config.instruction_set {
+        if instr.encode() & 0xF000 == 0 {
+            return op_0nnn(cpu);
+        }
+        return Err(unknown_opcode(cpu));
+    }
+    match instr {
+        Sys(0) => Err(EmulationError::ZeroOpcode { pc: cpu.pc }),
+        Sys(_) => op_0nnn(cpu),
+        Cls => op_00E0(cpu),
+        Ret => op_00EE(cpu),
+        ScrollDown(n) => op_00Cn(cpu, n),
+        ScrollUp(n) => op_00Dn(cpu, n),
+        ScrollRight => op_00FB(cpu),
+        ScrollLeft => op_00FC(cpu),
+        Exit => op_00FD(cpu),
+        Lores => op_00FE(cpu),
+        Hires => op_00FF(cpu),
+        Jump(addr) => op_1nnn(cpu, addr),
+        Call(addr) => op_2nnn(cpu, addr),
+        SkipEqImm { x, kk } => op_3xkk(cpu, x.into(), kk),
+        SkipNeImm { x, kk } => op_4xkk(cpu, x.into(), kk),
+        SkipEqReg { x, y } => op_5xy0(cpu, x.into(), y.into()),
+        SaveRange { x, y } => op_5xy2(cpu, x.into(), y.into()),
+        LoadRange { x, y } => op_5xy3(cpu, x.into(), y.into()),
+        LoadImm { x, kk } => op_6xkk(cpu, x.into(), kk),
+        AddImm { x, kk } => op_7xkk(cpu, x.into(), kk),
+        Move { x, y } => op_8xy0(cpu, x.into(), y.into()),
+        Or { x, y } => op_8xy1(cpu, x.into(), y.into()),
+        And { x, y } => op_8xy2(cpu, x.into(), y.into()),
+        Xor { x, y } => op_8xy3(cpu, x.into(), y.into()),
+        Add { x, y } => op_8xy4(cpu, x.into(), y.into()),
+        Sub { x, y } => op_8xy5(cpu, x.into(), y.into()),
+        Shr { x, y } => op_8xy6(cpu, x.into(), y.into()),
+        SubN { x, y } => op_8xy7(cpu, x.into(), y.into()),
+        Shl { x, y } => op_8xyE(cpu, x.into(), y.into()),
+        SkipNeReg { x, y } => op_9xy0(cpu, x.into(), y.into()),
+        LoadI(addr) => op_Annn(cpu, addr),
+        JumpOffset(addr) => op_Bnnn(cpu, addr),
+        Rand { x, kk } => op_Cxkk(cpu, x.into(), kk),
+        Draw { x, y, n } => op_Dxyn(cpu, x.into(), y.into(), n),
+        SkipKey { x } => op_Ex9E(cpu, x.into()),
+        SkipNotKey { x } => op_ExA1(cpu, x.into()),
+        LoadILong(addr) => op_F000(cpu, addr),
+        Plane(mask) => op_Fn01(cpu, mask),
+        Audio => op_F002(cpu),
+        LoadDelay { x } => op_Fx07(cpu, x.into()),
+        WaitKey { .. } => op_Fx0A(cpu),
+        SetDelay { x } => op_Fx15(cpu, x.into()),
+        SetSound { x } => op_Fx18(cpu, x.into()),
+        AddI { x } => op_Fx1E(cpu, x.into()),
+        Font { x } => op_Fx29(cpu, x.into()),
+        BigFont { x } => op_Fx30(cpu, x.into()),
+        Bcd { x } => op_Fx33(cpu, x.into()),
+        Pitch { x } => op_Fx3A(cpu, x.into()),
+        Store { x } => op_Fx55(cpu, x.into()),
+        Load { x } => op_Fx65(cpu, x.into()),
+        SaveFlags { x } => op_Fx75(cpu, x.into()),
+        LoadFlags { x } => op_Fx85(cpu, x.into()),
+    }
+}
+
+fn unknown_opcode(cpu: &CPUState) -> EmulationError {
+    EmulationError::UnknownOpcode { pc: cpu.pc, opcode: cpu.get_opcode() }
+}
+
+fn out_of_bounds(cpu: &CPUState, addr: usize) -> EmulationError {
+    EmulationError::MemoryOutOfBounds { pc: cpu.pc, opcode: cpu.get_opcode(), addr }
+}
+
+fn op_0nnn(cpu: &mut CPUState) -> OpResult {
     //SYS
     //call to native machine code, unimplemented
     //panic!("SYS call to native machine attempted! {:X} at addr {:X}", cpu.get_opcode(), cpu.pc);
-    cpu.pc += 2;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_00E0(cpu: &mut CPUState) {
+fn op_00E0(cpu: &mut CPUState) -> OpResult {
     //CLS
     cpu.disp.clear();
-    cpu.pc += 2;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+///SCD n
+fn op_00Cn(cpu: &mut CPUState, n: u8) -> OpResult {
+    cpu.disp.scroll_down(n as usize);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+///SCU n
+fn op_00Dn(cpu: &mut CPUState, n: u8) -> OpResult {
+    cpu.disp.scroll_up(n as usize);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_00EE(cpu: &mut CPUState) {
+///SCR
+fn op_00FB(cpu: &mut CPUState) -> OpResult {
+    cpu.disp.scroll_right(4);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+///SCL
+fn op_00FC(cpu: &mut CPUState) -> OpResult {
+    cpu.disp.scroll_left(4);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+///EXIT
+fn op_00FD(cpu: &mut CPUState) -> OpResult {
+    cpu.halt_status = HaltStatus::Exited;
+    Ok(())
+}
+
+///LOW
+fn op_00FE(cpu: &mut CPUState) -> OpResult {
+    cpu.disp.set_hires(false);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+///HIGH
+fn op_00FF(cpu: &mut CPUState) -> OpResult {
+    cpu.disp.set_hires(true);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+fn op_00EE(cpu: &mut CPUState) -> OpResult {
     //RET
-    let addr = u16::from_be_bytes([
-        cpu.mem.read((cpu.sp - 1) as u16),
-        cpu.mem.read(cpu.sp as u16),
-    ]);
-    cpu.sp -= 2;
-    cpu.pc = addr + 2;
+    let addr = match cpu.pop_stack() {
+        Some(addr) => addr,
+        None => return Err(EmulationError::StackUnderflow { pc: cpu.pc, opcode: cpu.get_opcode() }),
+    };
+    cpu.pc = addr.wrapping_add(2);
+    Ok(())
 }
 
-fn op_1nnn(cpu: &mut CPUState) {
+fn op_1nnn(cpu: &mut CPUState, addr: u16) -> OpResult {
     //JMP
-    cpu.pc = cpu.d_addr();
+    cpu.pc = addr;
+    Ok(())
 }
 
-fn op_2nnn(cpu: &mut CPUState) {
+fn op_2nnn(cpu: &mut CPUState, addr: u16) -> OpResult {
     //CALL
-    cpu.sp += 2;
-    let bytes = cpu.pc.to_be_bytes();
-    cpu.mem.write((cpu.sp - 1) as u16, bytes[0]);
-    cpu.mem.write((cpu.sp) as u16, bytes[1]);
-    cpu.pc = cpu.d_addr();
-    //println!("{}", cpu.reg_states());
+    if !cpu.push_stack(cpu.pc) {
+        return Err(EmulationError::StackOverflow { pc: cpu.pc, opcode: cpu.get_opcode() });
+    }
+    cpu.pc = addr;
+    Ok(())
 }
 
-fn skip_next_instr_if(cpu: &mut CPUState, cond: fn(&CPUState) -> bool) {
-    if cond(cpu) {
-        cpu.pc += 4;
+fn skip_next_instr_if(cpu: &mut CPUState, cond: bool) -> OpResult {
+    if cond {
+        //XO-CHIP's F000 nnnn is 4 bytes long, so skipping it means skipping its operand too
+        let next_is_long = cpu.config.instruction_set >= InstructionSet::XoChip
+            && cpu.mem.read_opcode(cpu.pc as usize + 2) == Ok(0xF000);
+        cpu.pc = cpu.pc.wrapping_add(if next_is_long { 6 } else { 4 });
     } else {
-        cpu.pc += 2;
+        cpu.pc = cpu.pc.wrapping_add(2);
     }
+    Ok(())
 }
 
-fn op_3xkk(cpu: &mut CPUState) {
-    skip_next_instr_if(cpu, |cpu| cpu.v[cpu.d_x()] == cpu.d_kk())
+fn op_3xkk(cpu: &mut CPUState, x: usize, kk: u8) -> OpResult {
+    skip_next_instr_if(cpu, cpu.v[x] == kk)
 }
 
-fn op_4xkk(cpu: &mut CPUState) {
-    skip_next_instr_if(cpu, |cpu| cpu.v[cpu.d_x()] != cpu.d_kk())
+fn op_4xkk(cpu: &mut CPUState, x: usize, kk: u8) -> OpResult {
+    skip_next_instr_if(cpu, cpu.v[x] != kk)
 }
 
-fn op_5xy0(cpu: &mut CPUState) {
-    skip_next_instr_if(cpu, |cpu| cpu.v[cpu.d_x()] == cpu.v[cpu.d_y()])
+fn op_5xy0(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    skip_next_instr_if(cpu, cpu.v[x] == cpu.v[y])
 }
 
-fn op_6xkk(cpu: &mut CPUState) {
-    cpu.v[cpu.d_x()] = cpu.d_kk();
-    cpu.pc += 2;
+///Registers Vx to Vy in order, which counts down if x > y
+fn reg_range(x: usize, y: usize) -> Vec<usize> {
+    if x <= y {
+        (x..=y).collect()
+    } else {
+        (y..=x).rev().collect()
+    }
 }
 
-fn op_7xkk(cpu: &mut CPUState) {
-    cpu.v[cpu.d_x()] = cpu.v[cpu.d_x()].wrapping_add(cpu.d_kk());
-    cpu.pc += 2;
+fn op_5xy2(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    //save Vx..Vy to I, leaving I alone
+    let vals: Vec<u8> = reg_range(x, y).into_iter().map(|reg| cpu.v[reg]).collect();
+    cpu.mem.write_range(cpu.i as usize, &vals).map_err(|addr| out_of_bounds(cpu, addr))?;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_8_innerlookup(cpu: &mut CPUState) {
-    const INNER_FUNC_TABLE: [fn(&mut CPUState); 8] = [
-        op_8xy0, op_8xy1, op_8xy2, op_8xy3, op_8xy4, op_8xy5, op_8xy6, op_8xy7,
-    ];
-
-    match cpu.d_n() {
-        i @ 0..=7 => INNER_FUNC_TABLE[i as usize](cpu),
-        0xE => op_8xyE(cpu),
-        _ => panic!("Unknown opcode! {:X}", cpu.get_opcode()),
+fn op_5xy3(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    //load Vx..Vy from I, leaving I alone
+    let regs = reg_range(x, y);
+    let vals = cpu.mem.read_range(cpu.i as usize, regs.len()).map_err(|addr| out_of_bounds(cpu, addr))?;
+    for (reg, val) in regs.into_iter().zip(vals.iter()) {
+        cpu.v[reg] = *val;
     }
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+fn op_6xkk(cpu: &mut CPUState, x: usize, kk: u8) -> OpResult {
+    cpu.v[x] = kk;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_8xy0(cpu: &mut CPUState) {
-    cpu.v[cpu.d_x()] = cpu.v[cpu.d_y()];
-    cpu.pc += 2;
+fn op_7xkk(cpu: &mut CPUState, x: usize, kk: u8) -> OpResult {
+    cpu.v[x] = cpu.v[x].wrapping_add(kk);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_8xy1(cpu: &mut CPUState) {
-    let result = cpu.v[cpu.d_x()] | cpu.v[cpu.d_y()];
-    cpu.v[0xF] = 0;
-    cpu.v[cpu.d_x()] = result;
-    cpu.pc += 2;
+fn op_8xy0(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    cpu.v[x] = cpu.v[y];
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_8xy2(cpu: &mut CPUState) {
-    let result = cpu.v[cpu.d_x()] & cpu.v[cpu.d_y()];
-    cpu.v[0xF] = 0;
-    cpu.v[cpu.d_x()] = result;
-    cpu.pc += 2;
+fn op_8xy1(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    let result = cpu.v[x] | cpu.v[y];
+    if cpu.config.vf_reset_on_logic {
+        cpu.v[0xF] = 0;
+    }
+    cpu.v[x] = result;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+fn op_8xy2(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    let result = cpu.v[x] & cpu.v[y];
+    if cpu.config.vf_reset_on_logic {
+        cpu.v[0xF] = 0;
+    }
+    cpu.v[x] = result;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_8xy3(cpu: &mut CPUState) {
-    let result = cpu.v[cpu.d_x()] ^ cpu.v[cpu.d_y()];
-    cpu.v[0xF] = 0;
-    cpu.v[cpu.d_x()] = result;
-    cpu.pc += 2;
+fn op_8xy3(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    let result = cpu.v[x] ^ cpu.v[y];
+    if cpu.config.vf_reset_on_logic {
+        cpu.v[0xF] = 0;
+    }
+    cpu.v[x] = result;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_8xy4(cpu: &mut CPUState) {
-    let result = cpu.v[cpu.d_x()] as u16 + cpu.v[cpu.d_y()] as u16;
-    cpu.v[cpu.d_x()] = result as u8;
+fn op_8xy4(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    let result = cpu.v[x] as u16 + cpu.v[y] as u16;
+    cpu.v[x] = result as u8;
     cpu.v[0xF] = (result > 0xFF) as u8;
-    cpu.pc += 2;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
 fn sub_regs(cpu: &mut CPUState, x: usize, y: usize) -> u8 {
@@ -158,187 +287,550 @@ fn sub_regs(cpu: &mut CPUState, x: usize, y: usize) -> u8 {
     return result;
 }
 
-fn op_8xy5(cpu: &mut CPUState) {
-    cpu.v[cpu.d_x()] = sub_regs(cpu, cpu.d_x(), cpu.d_y());
-    cpu.pc += 2;
+fn op_8xy5(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    cpu.v[x] = sub_regs(cpu, x, y);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_8xy6(cpu: &mut CPUState) {
-    let reg_to_shift = if CHIP8_CONFIG.shifting_with_Vy {
-        cpu.v[cpu.d_y()]
+fn op_8xy6(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    let reg_to_shift = if cpu.config.shifting_with_Vy {
+        cpu.v[y]
     } else {
-        cpu.v[cpu.d_x()]
+        cpu.v[x]
     };
     let result = reg_to_shift >> 1;
-    cpu.v[cpu.d_x()] = result;
+    cpu.v[x] = result;
     cpu.v[0xF] = reg_to_shift & 1;
-    cpu.pc += 2;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_8xy7(cpu: &mut CPUState) {
-    cpu.v[cpu.d_x()] = sub_regs(cpu, cpu.d_y(), cpu.d_x());
-    cpu.pc += 2;
+fn op_8xy7(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    cpu.v[x] = sub_regs(cpu, y, x);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_8xyE(cpu: &mut CPUState) {
-    let reg_to_shift = if CHIP8_CONFIG.shifting_with_Vy {
-        cpu.v[cpu.d_y()]
+fn op_8xyE(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    let reg_to_shift = if cpu.config.shifting_with_Vy {
+        cpu.v[y]
     } else {
-        cpu.v[cpu.d_x()]
+        cpu.v[x]
     };
     let result = reg_to_shift << 1;
-    cpu.v[cpu.d_x()] = result;
+    cpu.v[x] = result;
     cpu.v[0xF] = (reg_to_shift & 0b10000000) >> 7;
-    cpu.pc += 2;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_9xy0(cpu: &mut CPUState) {
-    skip_next_instr_if(cpu, |cpu| cpu.v[cpu.d_x()] != cpu.v[cpu.d_y()])
+fn op_9xy0(cpu: &mut CPUState, x: usize, y: usize) -> OpResult {
+    skip_next_instr_if(cpu, cpu.v[x] != cpu.v[y])
 }
 
-fn op_Annn(cpu: &mut CPUState) {
-    cpu.i = cpu.d_addr();
-    cpu.pc += 2;
+fn op_Annn(cpu: &mut CPUState, addr: u16) -> OpResult {
+    cpu.i = addr;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_Bnnn(cpu: &mut CPUState) {
-    cpu.pc = cpu.d_addr().wrapping_add(cpu.v[0] as u16);
+fn op_Bnnn(cpu: &mut CPUState, addr: u16) -> OpResult {
+    let offset_reg = if cpu.config.jump_with_Vx { decode::x(addr) as usize } else { 0 };
+    cpu.pc = addr.wrapping_add(cpu.v[offset_reg] as u16);
+    Ok(())
 }
 
 ///RND Vx
-fn op_Cxkk(cpu: &mut CPUState) {
-    cpu.v[cpu.d_x()] = cpu.v[cpu.d_x()] & rand::thread_rng().gen_range(0..=0xFF);
-    cpu.pc += 2;
+fn op_Cxkk(cpu: &mut CPUState, x: usize, kk: u8) -> OpResult {
+    cpu.v[x] = cpu.rng.next_byte() & kk;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
 ///DRW Vx Vy n
-fn op_Dxyn(cpu: &mut CPUState) {
-    cpu.halt_status = HaltStatus::WaitingVblank;
+fn op_Dxyn(cpu: &mut CPUState, x: usize, y: usize, n: u8) -> OpResult {
+    if cpu.config.emulate_draw_vblank_delay {
+        //the VIP interpreter waits for the display interrupt before drawing, the draw itself runs on the next vblank
+        cpu.halt_status = HaltStatus::WaitingVblank;
+        return Ok(());
+    }
+    DRW(cpu, x, y, n)
+}
+
+pub fn DRW(cpu: &mut CPUState, x: usize, y: usize, n: u8) -> OpResult {
+    let schip = cpu.config.instruction_set >= InstructionSet::SuperChip;
+    //Dxy0 draws a 16x16 sprite on SUPER-CHIP and nothing on the original interpreter
+    let wide = schip && n == 0;
+    let sprite_len = if wide { 32 } else { n as usize } * cpu.disp.selected_plane_count();
+    let sprite_mem = cpu.mem.read_range(cpu.i as usize, sprite_len).map_err(|addr| out_of_bounds(cpu, addr))?;
+    let x = cpu.v[x] as usize;
+    let y = cpu.v[y] as usize;
+    let collided_rows = if wide {
+        cpu.disp.draw_wide(&sprite_mem, x, y, &cpu.config)
+    } else {
+        cpu.disp.draw(&sprite_mem, x, y, &cpu.config)
+    };
+    cpu.v[0xF] = if cpu.config.instruction_set == InstructionSet::SuperChip && cpu.disp.is_hires() {
+        //SUPER-CHIP 1.1 counts the rows that collided or were clipped off the bottom
+        let rows = if wide { 16 } else { n as usize };
+        let clipped_rows = if cpu.config.sprite_clipping {
+            (y % cpu.disp.height + rows).saturating_sub(cpu.disp.height)
+        } else {
+            0
+        };
+        (collided_rows + clipped_rows) as u8
+    } else {
+        (collided_rows > 0) as u8
+    };
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-pub fn DRW(cpu: &mut CPUState) {
-    let sprite_mem = &cpu.mem.slice()[cpu.i as usize..(cpu.i + cpu.d_n() as u16) as usize];
-    let collision = cpu.disp.draw(
-        sprite_mem,
-        cpu.v[cpu.d_x()] as usize,
-        cpu.v[cpu.d_y()] as usize,
-    );
-    cpu.v[0xF] = collision as u8;
-    cpu.pc += 2;
+fn op_Ex9E(cpu: &mut CPUState, x: usize) -> OpResult {
+    //skip if key pressed
+    let key = usize::from(cpu.v[x] & 0xF);
+    skip_next_instr_if(cpu, cpu.kbstate.key[key])
 }
 
-fn op_E_innerlookup(cpu: &mut CPUState) {
-    match cpu.d_kk() {
-        0x9E => op_Ex9E(cpu),
-        0xA1 => op_ExA1(cpu),
-        _ => panic!("Unknown opcode! {:X}", cpu.get_opcode()),
-    }
+fn op_ExA1(cpu: &mut CPUState, x: usize) -> OpResult {
+    //skip if key not pressed
+    let key = usize::from(cpu.v[x] & 0xF);
+    skip_next_instr_if(cpu, !cpu.kbstate.key[key])
 }
 
-fn op_Ex9E(cpu: &mut CPUState) {
-    //skip if key pressed
-    skip_next_instr_if(cpu, |cpu| {
-        let key: usize = cpu.v[cpu.d_x()].try_into().unwrap();
-        cpu.kbstate.key[key]
-    });
+fn op_F000(cpu: &mut CPUState, addr: u16) -> OpResult {
+    //load I with the 16 bit address in the next word
+    cpu.i = addr;
+    cpu.pc = cpu.pc.wrapping_add(4);
+    Ok(())
 }
 
-fn op_ExA1(cpu: &mut CPUState) {
-    //skip if key not pressed
-    skip_next_instr_if(cpu, |cpu| {
-        let key: usize = cpu.v[cpu.d_x()].try_into().unwrap();
-        !cpu.kbstate.key[key]
-    });
+fn op_Fn01(cpu: &mut CPUState, mask: u8) -> OpResult {
+    //select drawing planes
+    cpu.disp.select_planes(mask);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_F_innerlookup(cpu: &mut CPUState) {
-    match cpu.d_kk() {
-        0x07 => op_Fx07(cpu),
-        0x0A => op_Fx0A(cpu),
-        0x15 => op_Fx15(cpu),
-        0x18 => op_Fx18(cpu),
-        0x1E => op_Fx1E(cpu),
-        0x29 => op_Fx29(cpu),
-        0x33 => op_Fx33(cpu),
-        0x55 => op_Fx55(cpu),
-        0x65 => op_Fx65(cpu),
-        _ => panic!("Unknown opcode! {:X}", cpu.get_opcode()),
-    }
+fn op_F002(cpu: &mut CPUState) -> OpResult {
+    //load the 16 byte audio pattern at I
+    let pattern = cpu.mem.read_range(cpu.i as usize, cpu.audio_pattern.len()).map_err(|addr| out_of_bounds(cpu, addr))?;
+    cpu.audio_pattern.copy_from_slice(&pattern);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_Fx07(cpu: &mut CPUState) {
-    cpu.v[cpu.d_x()] = cpu.dt;
-    cpu.pc += 2;
+fn op_Fx3A(cpu: &mut CPUState, x: usize) -> OpResult {
+    cpu.pitch = cpu.v[x];
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_Fx0A(cpu: &mut CPUState) {
+fn op_Fx07(cpu: &mut CPUState, x: usize) -> OpResult {
+    cpu.v[x] = cpu.dt;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+fn op_Fx0A(cpu: &mut CPUState) -> OpResult {
     cpu.halt_status = HaltStatus::WaitingFx0A;
     cpu.kbstate.Fx0A = Fx0AStatus::WaitingForPress;
+    Ok(())
 }
 
 pub fn Fx0AHandler(cpu: &mut CPUState) -> bool {
     match cpu.kbstate.Fx0A {
-        Fx0AStatus::Inactive => {panic!("Shouldn't be reached")},
+        //halted on Fx0A without a wait in progress, e.g. a patched or loaded state: start waiting now
+        Fx0AStatus::Inactive => cpu.kbstate.Fx0A = Fx0AStatus::WaitingForPress,
         Fx0AStatus::WaitingForPress => {}
         Fx0AStatus::WaitingForRelease(_) => {}
         Fx0AStatus::JustReleased(key) => {
             cpu.kbstate.Fx0A = Fx0AStatus::Inactive;
-            cpu.v[cpu.d_x()] = key;
-            cpu.pc += 2;
+            cpu.v[decode::x(cpu.get_opcode()) as usize] = key;
+            cpu.pc = cpu.pc.wrapping_add(2);
             return true;
         }
     }
     return false;
 }
 
-fn op_Fx15(cpu: &mut CPUState) {
-    cpu.dt = cpu.v[cpu.d_x()];
-    cpu.pc += 2;
+fn op_Fx15(cpu: &mut CPUState, x: usize) -> OpResult {
+    cpu.dt = cpu.v[x];
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_Fx18(cpu: &mut CPUState) {
-    cpu.st = cpu.v[cpu.d_x()];
-    cpu.pc += 2;
+fn op_Fx18(cpu: &mut CPUState, x: usize) -> OpResult {
+    cpu.st = cpu.v[x];
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_Fx1E(cpu: &mut CPUState) {
-    cpu.i = cpu.i.wrapping_add(cpu.v[cpu.d_x()] as u16);
-    cpu.pc += 2;
+fn op_Fx1E(cpu: &mut CPUState, x: usize) -> OpResult {
+    cpu.i = cpu.i.wrapping_add(cpu.v[x] as u16);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_Fx29(cpu: &mut CPUState) {
+fn op_Fx29(cpu: &mut CPUState, x: usize) -> OpResult {
     //set I to location of Vx sprite
-    cpu.i = Memory::get_font_addr(cpu.d_x() as u8);
-    cpu.pc += 2;
+    cpu.i = Memory::get_font_addr(cpu.v[x] & 0xF);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+fn op_Fx30(cpu: &mut CPUState, x: usize) -> OpResult {
+    //set I to location of Vx big (8x10) sprite
+    cpu.i = Memory::get_big_font_addr(cpu.v[x] & 0xF);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_Fx33(cpu: &mut CPUState) {
+fn op_Fx33(cpu: &mut CPUState, x: usize) -> OpResult {
     //store Vx as BCD in I, I+1, I+2
-    let mut n = cpu.v[cpu.d_x()];
-    for i in (0..=2).rev() {
-        cpu.mem.write(cpu.i + i, n % 10);
-        n = n / 10;
+    let n = cpu.v[x];
+    let digits = [n / 100, n / 10 % 10, n % 10];
+    cpu.mem.write_range(cpu.i as usize, &digits).map_err(|addr| out_of_bounds(cpu, addr))?;
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+fn increment_i_after_load_store(cpu: &mut CPUState, x: usize) {
+    match cpu.config.load_store_increment {
+        IndexIncrement::ByXPlusOne => cpu.i = cpu.i.wrapping_add(x as u16 + 1),
+        IndexIncrement::ByX => cpu.i = cpu.i.wrapping_add(x as u16),
+        IndexIncrement::Unchanged => {}
     }
-    cpu.pc += 2;
 }
 
-fn op_Fx55(cpu: &mut CPUState) {
-    for i in 0..=cpu.d_x() {
-        let addr = cpu.i + (i as u16);
-        let val = cpu.v[i];
-        cpu.mem.write(addr, val)
-    }
-    cpu.i += cpu.d_x() as u16 + 1;
-    cpu.pc += 2;
+fn op_Fx55(cpu: &mut CPUState, x: usize) -> OpResult {
+    cpu.mem.write_range(cpu.i as usize, &cpu.v[..=x]).map_err(|addr| out_of_bounds(cpu, addr))?;
+    increment_i_after_load_store(cpu, x);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+fn op_Fx65(cpu: &mut CPUState, x: usize) -> OpResult {
+    let vals = cpu.mem.read_range(cpu.i as usize, x + 1).map_err(|addr| out_of_bounds(cpu, addr))?;
+    cpu.v[..=x].copy_from_slice(&vals);
+    increment_i_after_load_store(cpu, x);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
 }
 
-fn op_Fx65(cpu: &mut CPUState) {
-    for i in 0..=cpu.d_x() {
-        cpu.v[i] = {
-            let addr = cpu.i + (i as u16);
-            cpu.mem.read(addr)
+fn op_Fx75(cpu: &mut CPUState, x: usize) -> OpResult {
+    //save V0..Vx to the RPL user flags
+    cpu.rpl[..=x].copy_from_slice(&cpu.v[..=x]);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+fn op_Fx85(cpu: &mut CPUState, x: usize) -> OpResult {
+    //load V0..Vx from the RPL user flags
+    cpu.v[..=x].copy_from_slice(&cpu.rpl[..=x]);
+    cpu.pc = cpu.pc.wrapping_add(2);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Chip8Config, QuirkProfile};
+    use crate::display::DisplayData;
+    use crate::memory::{MEMSIZE, XO_MEMSIZE};
+
+    fn cpu(config: Chip8Config) -> CPUState {
+        let size = if config.instruction_set == InstructionSet::XoChip { XO_MEMSIZE } else { MEMSIZE };
+        let rng = config.rng.build(1);
+        return CPUState::new(Memory::with_prog(&[], size).unwrap(), DisplayData::new_64x32(), config, rng);
+    }
+
+    /// Runs `instr` on a fresh CPU with `config`, after `setup`.
+    fn run(config: Chip8Config, setup: impl FnOnce(&mut CPUState), instr: Instruction) -> CPUState {
+        let mut cpu = cpu(config);
+        setup(&mut cpu);
+        execute(&mut cpu, instr).unwrap();
+        return cpu;
+    }
+
+    fn with<F: FnOnce(&mut Chip8Config)>(change: F) -> Chip8Config {
+        let mut config = QuirkProfile::Modern.config();
+        change(&mut config);
+        return config;
+    }
+
+    #[test]
+    fn logic_ops_reset_vf_only_with_the_quirk() {
+        let setup = |cpu: &mut CPUState| {
+            cpu.v[0] = 0b1100;
+            cpu.v[1] = 0b1010;
+            cpu.v[0xF] = 5;
         };
+        for (instr, result) in [
+            (Instruction::Or { x: 0, y: 1 }, 0b1110),
+            (Instruction::And { x: 0, y: 1 }, 0b1000),
+            (Instruction::Xor { x: 0, y: 1 }, 0b0110),
+        ] {
+            let cpu = run(with(|c| c.vf_reset_on_logic = true), setup, instr);
+            assert_eq!((cpu.v[0], cpu.v[0xF]), (result, 0), "{}", instr);
+            let cpu = run(with(|c| c.vf_reset_on_logic = false), setup, instr);
+            assert_eq!((cpu.v[0], cpu.v[0xF]), (result, 5), "{}", instr);
+        }
+    }
+
+    #[test]
+    fn load_and_store_move_i_as_configured() {
+        let setup = |cpu: &mut CPUState| {
+            cpu.i = 0x300;
+            cpu.v[..3].copy_from_slice(&[7, 8, 9]);
+        };
+        let increments = [(IndexIncrement::ByXPlusOne, 0x303), (IndexIncrement::ByX, 0x302), (IndexIncrement::Unchanged, 0x300)];
+        for (increment, i) in increments {
+            let cpu = run(with(|c| c.load_store_increment = increment), setup, Instruction::Store { x: 2 });
+            assert_eq!(cpu.i, i, "{:?}", increment);
+            assert_eq!(cpu.mem.read_range(0x300, 4).unwrap()[..], [7, 8, 9, 0]);
+
+            let setup = |cpu: &mut CPUState| {
+                cpu.i = 0x300;
+                cpu.mem.write_range(0x300, &[4, 5, 6, 1]).unwrap();
+            };
+            let cpu = run(with(|c| c.load_store_increment = increment), setup, Instruction::Load { x: 2 });
+            assert_eq!(cpu.i, i, "{:?}", increment);
+            assert_eq!(cpu.v[..4], [4, 5, 6, 0]);
+        }
+    }
+
+    #[test]
+    fn jump_offset_uses_v0_or_vx() {
+        let setup = |cpu: &mut CPUState| {
+            cpu.v[0] = 0x10;
+            cpu.v[2] = 0x20;
+        };
+        let cpu = run(with(|c| c.jump_with_Vx = false), setup, Instruction::JumpOffset(0x230));
+        assert_eq!(cpu.pc, 0x240);
+        let cpu = run(with(|c| c.jump_with_Vx = true), setup, Instruction::JumpOffset(0x230));
+        assert_eq!(cpu.pc, 0x250);
+    }
+
+    #[test]
+    fn shifts_read_vx_or_vy() {
+        let setup = |cpu: &mut CPUState| {
+            cpu.v[0] = 0x81;
+            cpu.v[1] = 0x42;
+        };
+        let shr = Instruction::Shr { x: 0, y: 1 };
+        let shl = Instruction::Shl { x: 0, y: 1 };
+        let cpu = run(with(|c| c.shifting_with_Vy = true), setup, shr);
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0x21, 0));
+        let cpu = run(with(|c| c.shifting_with_Vy = false), setup, shr);
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0x40, 1));
+        let cpu = run(with(|c| c.shifting_with_Vy = true), setup, shl);
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0x84, 0));
+        let cpu = run(with(|c| c.shifting_with_Vy = false), setup, shl);
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0x02, 1));
+    }
+
+    #[test]
+    fn sprites_clip_or_wrap_at_the_edges() {
+        //a 2x2 block at the bottom right corner
+        let setup = |cpu: &mut CPUState| {
+            cpu.v[0] = 63;
+            cpu.v[1] = 31;
+            cpu.i = 0x300;
+            cpu.mem.write_range(0x300, &[0xC0, 0xC0]).unwrap();
+        };
+        let draw = Instruction::Draw { x: 0, y: 1, n: 2 };
+        let cpu = run(with(|c| c.sprite_clipping = true), setup, draw);
+        assert!(cpu.disp.get_pixel(63, 31));
+        assert!(!cpu.disp.get_pixel(0, 31) && !cpu.disp.get_pixel(63, 0) && !cpu.disp.get_pixel(0, 0));
+        let cpu = run(with(|c| c.sprite_clipping = false), setup, draw);
+        assert!(cpu.disp.get_pixel(63, 31) && cpu.disp.get_pixel(0, 31));
+        assert!(cpu.disp.get_pixel(63, 0) && cpu.disp.get_pixel(0, 0));
+    }
+
+    fn schip() -> Chip8Config {
+        QuirkProfile::SuperChip11.config()
+    }
+
+    /// A hires CPU with a single pixel lit at (x, y).
+    fn hires_with_pixel(x: u8, y: u8) -> CPUState {
+        let mut cpu = cpu(schip());
+        cpu.disp.set_hires(true);
+        cpu.v[0] = x;
+        cpu.v[1] = y;
+        cpu.i = 0x300;
+        cpu.mem.write(0x300, 0x80).unwrap();
+        execute(&mut cpu, Instruction::Draw { x: 0, y: 1, n: 1 }).unwrap();
+        return cpu;
+    }
+
+    #[test]
+    fn schip_scrolls_move_the_screen() {
+        for (instr, (x, y)) in [
+            (Instruction::ScrollDown(3), (10, 23)),
+            (Instruction::ScrollRight, (14, 20)),
+            (Instruction::ScrollLeft, (6, 20)),
+        ] {
+            let mut cpu = hires_with_pixel(10, 20);
+            execute(&mut cpu, instr).unwrap();
+            assert!(cpu.disp.get_pixel(x, y), "{}", instr);
+            assert!(!cpu.disp.get_pixel(10, 20), "{}", instr);
+            assert_eq!(cpu.pc, 0x204);
+        }
+    }
+
+    #[test]
+    fn schip_switches_resolution_and_exits() {
+        let mut cpu = cpu(schip());
+        execute(&mut cpu, Instruction::Hires).unwrap();
+        assert!(cpu.disp.is_hires());
+        assert_eq!((cpu.disp.width, cpu.disp.height), (128, 64));
+        execute(&mut cpu, Instruction::Lores).unwrap();
+        assert_eq!((cpu.disp.width, cpu.disp.height), (64, 32));
+        execute(&mut cpu, Instruction::Exit).unwrap();
+        assert_eq!((cpu.halt_status, cpu.pc), (HaltStatus::Exited, 0x204));
+    }
+
+    #[test]
+    fn schip_draws_16x16_sprites_and_counts_collided_rows() {
+        let mut cpu = hires_with_pixel(20, 20);
+        cpu.v[0] = 5;
+        cpu.v[1] = 5;
+        cpu.i = 0x300;
+        cpu.mem.write_range(0x300, &[0xFF; 32]).unwrap();
+        execute(&mut cpu, Instruction::Draw { x: 0, y: 1, n: 0 }).unwrap();
+        //the pixel at (20, 20) is inside the sprite, so one row collides
+        assert_eq!(cpu.v[0xF], 1);
+        assert!(cpu.disp.get_pixel(5, 5) && cpu.disp.get_pixel(20, 19) && !cpu.disp.get_pixel(20, 20));
+        assert!(!cpu.disp.get_pixel(21, 5) && !cpu.disp.get_pixel(5, 21));
+
+        //rows clipped off the bottom count too
+        cpu.v[1] = 60;
+        execute(&mut cpu, Instruction::Draw { x: 0, y: 1, n: 0 }).unwrap();
+        assert_eq!(cpu.v[0xF], 12);
+
+        //the original interpreter draws nothing for Dxy0
+        let setup = |cpu: &mut CPUState| cpu.mem.write_range(0, &[0xFF; 32]).unwrap();
+        let chip8 = run(with(|_| {}), setup, Instruction::Draw { x: 0, y: 0, n: 0 });
+        assert!(!chip8.disp.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn schip_big_font_and_flags() {
+        let setup = |cpu: &mut CPUState| cpu.v[3] = 0x1A;
+        let font = run(schip(), setup, Instruction::BigFont { x: 3 });
+        assert_eq!(font.i, Memory::get_big_font_addr(0xA));
+        //the big A
+        let big_a = [0x18, 0x3C, 0x66, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3];
+        assert_eq!(font.mem.read_range(font.i as usize, 10).unwrap()[..], big_a);
+
+        let mut cpu = cpu(schip());
+        cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+        execute(&mut cpu, Instruction::SaveFlags { x: 2 }).unwrap();
+        assert_eq!(cpu.rpl[..4], [1, 2, 3, 0]);
+        cpu.v = [0; 0x10];
+        execute(&mut cpu, Instruction::LoadFlags { x: 1 }).unwrap();
+        assert_eq!(cpu.v[..3], [1, 2, 0]);
+    }
+
+    #[test]
+    fn schip_opcodes_are_unknown_on_chip8() {
+        let mut cpu = cpu(QuirkProfile::Modern.config());
+        let err = execute(&mut cpu, Instruction::BigFont { x: 0 }).unwrap_err();
+        assert!(matches!(err, EmulationError::UnknownOpcode { pc: 0x200, .. }));
+        //00FE is a 0nnn SYS call there, which does nothing
+        execute(&mut cpu, Instruction::Lores).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    fn xo() -> CPUState {
+        cpu(QuirkProfile::XoChip.config())
+    }
+
+    #[test]
+    fn xo_long_loads_reach_all_64k() {
+        let mut cpu = xo();
+        //F000 FFF0, then LD V0-V1 [I] at the top of memory
+        cpu.mem.write_range(0x200, &[0xF0, 0x00, 0xFF, 0xF0, 0xF1, 0x65]).unwrap();
+        cpu.mem.write_range(0xFFF0, &[0xAB, 0xCD]).unwrap();
+        cpu.run_cycle().unwrap();
+        assert_eq!((cpu.i, cpu.pc), (0xFFF0, 0x204));
+        cpu.run_cycle().unwrap();
+        assert_eq!(cpu.v[..2], [0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn xo_skips_step_over_long_loads() {
+        let mut cpu = xo();
+        //SE V0 0, F000 1234, LD V1 1
+        cpu.mem.write_range(0x200, &[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x61, 0x01]).unwrap();
+        cpu.run_cycle().unwrap();
+        assert_eq!(cpu.pc, 0x206);
+        //the same skip without XO-CHIP only steps over one word
+        let mut schip = self::cpu(schip());
+        schip.mem.write_range(0x200, &[0x30, 0x00, 0xF0, 0x00]).unwrap();
+        schip.run_cycle().unwrap();
+        assert_eq!(schip.pc, 0x204);
+    }
+
+    #[test]
+    fn xo_saves_and_loads_register_ranges_in_either_order() {
+        let mut cpu = xo();
+        cpu.i = 0x300;
+        cpu.v[..5].copy_from_slice(&[10, 11, 12, 13, 14]);
+        execute(&mut cpu, Instruction::SaveRange { x: 1, y: 3 }).unwrap();
+        assert_eq!(cpu.mem.read_range(0x300, 4).unwrap()[..], [11, 12, 13, 0]);
+        execute(&mut cpu, Instruction::SaveRange { x: 4, y: 2 }).unwrap();
+        assert_eq!(cpu.mem.read_range(0x300, 4).unwrap()[..], [14, 13, 12, 0]);
+        assert_eq!(cpu.i, 0x300);
+
+        cpu.mem.write_range(0x300, &[1, 2, 3]).unwrap();
+        execute(&mut cpu, Instruction::LoadRange { x: 2, y: 0 }).unwrap();
+        assert_eq!(cpu.v[..5], [3, 2, 1, 13, 14]);
+        assert_eq!(cpu.i, 0x300);
+    }
+
+    #[test]
+    fn xo_draws_on_the_selected_planes() {
+        let mut cpu = xo();
+        cpu.i = 0x300;
+        //one row for plane 0, then one for plane 1
+        cpu.mem.write_range(0x300, &[0xC0, 0xA0]).unwrap();
+        execute(&mut cpu, Instruction::Plane(3)).unwrap();
+        execute(&mut cpu, Instruction::Draw { x: 0, y: 0, n: 1 }).unwrap();
+        let colors: Vec<u8> = (0..3).map(|x| cpu.disp.get_pixel_color(x, 0)).collect();
+        assert_eq!(colors, [3, 1, 2]);
+
+        execute(&mut cpu, Instruction::Plane(2)).unwrap();
+        execute(&mut cpu, Instruction::Cls).unwrap();
+        let colors: Vec<u8> = (0..3).map(|x| cpu.disp.get_pixel_color(x, 0)).collect();
+        assert_eq!(colors, [1, 1, 0]);
+    }
+
+    #[test]
+    fn xo_scrolls_up() {
+        let mut cpu = xo();
+        cpu.disp.set_hires(true);
+        cpu.v[..2].copy_from_slice(&[10, 20]);
+        cpu.i = 0x300;
+        cpu.mem.write(0x300, 0x80).unwrap();
+        execute(&mut cpu, Instruction::Draw { x: 0, y: 1, n: 1 }).unwrap();
+        execute(&mut cpu, Instruction::ScrollUp(5)).unwrap();
+        assert!(cpu.disp.get_pixel(10, 15) && !cpu.disp.get_pixel(10, 20));
+    }
+
+    #[test]
+    fn xo_loads_the_audio_pattern_and_pitch() {
+        let mut cpu = xo();
+        let pattern: Vec<u8> = (0..16).map(|n| n * 17).collect();
+        cpu.i = 0x300;
+        cpu.mem.write_range(0x300, &pattern).unwrap();
+        execute(&mut cpu, Instruction::Audio).unwrap();
+        assert_eq!(cpu.audio_pattern[..], pattern[..]);
+        assert_eq!(cpu.pitch, 64);
+        cpu.v[5] = 112;
+        execute(&mut cpu, Instruction::Pitch { x: 5 }).unwrap();
+        assert_eq!((cpu.pitch, cpu.pc), (112, 0x204));
     }
-    cpu.i += cpu.d_x() as u16 + 1;
-    cpu.pc += 2;
 }
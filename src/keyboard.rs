@@ -1,6 +1,8 @@
 #![allow(non_snake_case)]
 
-#[derive(Debug, Clone, Copy)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub struct KeyboardState {
     pub key: [bool; 0x10],
     pub Fx0A: Fx0AStatus,
@@ -34,8 +36,9 @@ impl KeyboardState {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub enum Fx0AStatus {
+    #[default]
     Inactive,
     WaitingForPress,
     WaitingForRelease(u8),
@@ -1,10 +1,23 @@
+pub mod audio;
+pub mod breakpoints;
 pub mod config;
 pub mod cpu;
+pub mod decode;
 pub mod disassembler;
 pub mod display;
+pub mod error;
+pub mod gdb;
 pub mod instructions;
 pub mod keyboard;
 pub mod machine;
 pub mod memory;
+pub mod movie;
+pub mod profiler;
+pub mod random;
+pub mod rewind;
+pub mod savestate;
+pub mod script;
+pub mod screenshot;
 pub mod timer;
+pub mod trace;
 mod cli_debug;
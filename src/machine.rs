@@ -1,70 +1,308 @@
 
+use std::io::{self, Read, Write};
 use std::time::Duration;
 
+use serde::Deserialize;
+
+use crate::audio::{AudioPattern, SoundState};
+use crate::breakpoints::{BreakpointHit, Breakpoints};
 use crate::cli_debug::debug_state;
-use crate::config::CHIP8_CONFIG;
-use crate::cpu::CPUState;
-use crate::display::DisplayData;
-use crate::memory::Memory;
+use crate::config::{Chip8Config, InstructionSet};
+use crate::cpu::{CPUState, HaltStatus};
+use crate::display::{DirtyRect, DisplayData};
+use crate::error::EmulationError;
+use crate::memory::{Memory, MEMSIZE, XO_MEMSIZE};
+use crate::movie::{self, Checkpoint, Desync, Movie, MovieError, MovieEvent, Playback, CHECKPOINT_INTERVAL_FRAMES};
+use crate::profiler::{ProfileReport, Profiler};
+use crate::random::RandomSource;
+use crate::rewind::RewindBuffer;
+use crate::savestate::{self, HeaderOnly, SaveState, SaveStateError, SaveStateHeader, FORMAT_VERSION};
 use crate::timer::Timer;
+use crate::trace::Tracer;
 
 const NUM_SAVESTATES: usize = 8;
-const UNINIT_SAVESTATE: Option<CPUState> = None;
+const UNINIT_SAVESTATE: Option<SlotState> = None;
+
+/// A quick save slot: the CPU and the counters the stepping API and movies go by.
+#[derive(Clone)]
+struct SlotState {
+    cpu: CPUState,
+    cycle_count: u64,
+    frame_count: u64,
+    frame_cycle: u32,
+}
+
+/// Bytes of memory a machine running `instruction_set` has.
+fn mem_size(instruction_set: InstructionSet) -> usize {
+    if instruction_set == InstructionSet::XoChip { XO_MEMSIZE } else { MEMSIZE }
+}
+
+pub type FaultTrap = Box<dyn FnMut(&EmulationError, &mut CPUState) -> FaultAction>;
+
+/// What the machine does after an instruction faults.
+pub enum FaultPolicy {
+    /// Stop executing. Every following `run` returns the fault until `clear_fault` is called.
+    Halt,
+    /// Step over the faulting instruction and keep going.
+    Skip,
+    /// Hand the fault to a callback (e.g. a debugger), which may inspect or patch the CPU and decides what happens next.
+    Trap(FaultTrap),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    Halt,
+    Skip,
+}
 
 pub struct Machine {
     cpu_state: CPUState,
     cpu_instr_timer: Timer,
     cpu_timer_regs_timer: Timer,
     vblank_timer: Timer,
-    saved_states: [Option<CPUState>; NUM_SAVESTATES],
+    saved_states: [Option<SlotState>; NUM_SAVESTATES],
+    fault_policy: FaultPolicy,
+    fault: Option<EmulationError>,
+    /// Cycles `run` owes from a call that stopped on a breakpoint
+    pending_cycles: u64,
+    cycle_count: u64,
+    frame_count: u64,
+    frame_cycle: u32,
+    rng_seed: u64,
+    rom_hash: u64,
+    rewind: RewindBuffer,
+    recording: Option<Movie>,
+    playback: Option<Playback>,
+    breakpoints: Breakpoints,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
 }
 
 impl Machine {
-    pub fn new(program: &[u8]) -> Machine {
-        let cpu_state = CPUState::new(Memory::with_prog(program), DisplayData::new_64x32());
-        let cpu_clock_freq = CHIP8_CONFIG.clock_speed_hz;
-        Machine {
+    pub fn new(program: &[u8], config: Chip8Config) -> Result<Machine, EmulationError> {
+        let cpu_clock_freq = config.clock_speed_hz;
+        let rng_seed = config.rng_seed.unwrap_or_else(rand::random);
+        let rng = config.rng.build(rng_seed);
+        let mem = Memory::with_prog(program, mem_size(config.instruction_set))?;
+        let rewind = RewindBuffer::new(config.rewind_frames, config.rewind_interval);
+        let cpu_state = CPUState::new(mem, DisplayData::new_64x32(), config, rng);
+        Ok(Machine {
             cpu_state,
             cpu_instr_timer: Timer::new(Duration::from_secs_f64(1.0 / cpu_clock_freq as f64)),
             cpu_timer_regs_timer: Timer::new(Duration::from_secs_f64(1.0 / 60.0)),
             vblank_timer: Timer::new(Duration::from_secs_f64(1.0 / 60.0)),
             saved_states: [UNINIT_SAVESTATE; NUM_SAVESTATES],
+            fault_policy: FaultPolicy::Halt,
+            fault: None,
+            pending_cycles: 0,
+            cycle_count: 0,
+            frame_count: 0,
+            frame_cycle: 0,
+            rng_seed,
+            rom_hash: savestate::rom_hash(program),
+            rewind,
+            recording: None,
+            playback: None,
+            breakpoints: Breakpoints::default(),
+            tracer: None,
+            profiler: None,
+        })
+    }
+
+    pub fn set_fault_policy(&mut self, policy: FaultPolicy) {
+        self.fault_policy = policy;
+    }
+
+    /// The fault the machine is halted on, if any.
+    pub fn fault(&self) -> Option<&EmulationError> {
+        self.fault.as_ref()
+    }
+
+    pub fn clear_fault(&mut self) {
+        self.fault = None;
+    }
+
+    /// Runs one CPU cycle, applying the fault policy if it faults.
+    fn run_cycle(&mut self) -> Result<bool, EmulationError> {
+        if let Some(fault) = &self.fault {
+            return Err(fault.clone());
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.before_cycle(&self.cpu_state);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.before_cycle(&self.cpu_state);
+        }
+        let err = match self.cpu_state.run_cycle() {
+            Ok(ran_instr) => {
+                if ran_instr {
+                    self.breakpoints.stepped();
+                }
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.after_cycle(&self.cpu_state);
+                }
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.after_cycle();
+                }
+                return Ok(ran_instr);
+            }
+            Err(err) => err,
+        };
+        if let Some(tracer) = &mut self.tracer {
+            tracer.fault();
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.fault();
+        }
+        let action = match &mut self.fault_policy {
+            FaultPolicy::Halt => FaultAction::Halt,
+            FaultPolicy::Skip => FaultAction::Skip,
+            FaultPolicy::Trap(callback) => callback(&err, &mut self.cpu_state),
+        };
+        match action {
+            FaultAction::Halt => {
+                self.fault = Some(err.clone());
+                return Err(err);
+            }
+            FaultAction::Skip => {
+                //an opcode that doesn't decode has no known size, so step over a single word
+                let size = self.cpu_state.fetch().map(|instr| instr.size()).unwrap_or(2);
+                self.cpu_state.halt_status = HaltStatus::NotHalted;
+                self.cpu_state.pc = self.cpu_state.pc.wrapping_add(size);
+                self.breakpoints.stepped();
+                return Ok(true);
+            }
         }
     }
 
-    pub fn run(&mut self) {
-        self.cpu_instr_timer.run(|| { self.cpu_state.run_cycle(); });
-        self.cpu_timer_regs_timer.run(|| {
-            if self.cpu_state.dt > 0 {
-                self.cpu_state.dt -= 1;
+    /// Checks the instruction about to run against the breakpoints.
+    fn check_breakpoints(&mut self) -> Option<BreakpointHit> {
+        //mid-instruction (e.g. a DRW waiting for vblank) the instruction was already checked when it started
+        if self.breakpoints.is_empty() || self.cpu_state.halt_status != HaltStatus::NotHalted || self.has_exited() {
+            return None;
+        }
+        //a fetch that faults is reported by run_cycle instead
+        let instr = self.cpu_state.fetch_cached().ok()?;
+        return self.breakpoints.check(&self.cpu_state, instr);
+    }
+
+    /// Runs the cycles due since the last call. Stops before an instruction that hits a breakpoint and
+    /// returns the hit; the next call runs that instruction without stopping on it again.
+    ///
+    /// Cycles that were due but didn't run because of a breakpoint are run by the next call.
+    pub fn run(&mut self) -> Result<Option<BreakpointHit>, EmulationError> {
+        let mut cycles = self.pending_cycles;
+        self.cpu_instr_timer.run(|| cycles += 1);
+        while cycles > 0 {
+            if let Some(hit) = self.check_breakpoints() {
+                self.pending_cycles = cycles;
+                self.run_timers();
+                return Ok(Some(hit));
             }
-            if self.cpu_state.st > 0 {
-                self.cpu_state.st -= 1;
+            cycles -= 1;
+            self.pending_cycles = cycles;
+            self.run_cycle()?;
+        }
+        self.run_timers();
+        return Ok(None);
+    }
+
+    /// Ticks the delay and sound timers and enters vblank as often as the wall clock says is due.
+    fn run_timers(&mut self) {
+        self.cpu_timer_regs_timer.run(|| self.cpu_state.tick_timers());
+        let frames = self.vblank_timer.run(|| self.cpu_state.enter_vblank());
+        for _ in 0..frames {
+            self.rewind.frame_ended(&self.cpu_state, (self.cycle_count, self.frame_count, self.frame_cycle));
+            if let Some(profiler) = &mut self.profiler {
+                profiler.frame_ended();
             }
-        });
-        self.vblank_timer.run(|| { 
-            self.cpu_state.enter_vblank();
-        });
+        }
     }
 
-    fn run_until_instr(&mut self) {
-        self.resume_timers();
-        loop {
-            let ran_instr = self.cpu_instr_timer.run_once_check_cond(|| { self.cpu_state.run_cycle() });
-            self.cpu_timer_regs_timer.run(|| {
-                if self.cpu_state.dt > 0 {
-                    self.cpu_state.dt -= 1;
-                }
-                if self.cpu_state.st > 0 {
-                    self.cpu_state.st -= 1;
+    /// Runs a single CPU cycle without looking at the wall clock.
+    ///
+    /// Every `instructions_per_frame` cycles the delay and sound timers tick and the display enters vblank,
+    /// so the same inputs always produce the same run, no matter how fast the host is.
+    pub fn step_instruction(&mut self) -> Result<bool, EmulationError> {
+        if let Some(playback) = &mut self.playback {
+            for event in playback.take_events(self.cycle_count) {
+                if event.pressed {
+                    self.cpu_state.kbstate.press_key(event.key);
+                } else {
+                    self.cpu_state.kbstate.release_key(event.key);
                 }
-            });
-            self.vblank_timer.run(|| { self.cpu_state.enter_vblank(); });
-            if ran_instr {
-                self.pause_timers();
-                break;
             }
         }
+        let ran_instr = self.run_cycle()?;
+        self.cycle_count += 1;
+        self.frame_cycle += 1;
+        if self.frame_cycle >= self.cpu_state.config.instructions_per_frame.max(1) {
+            self.end_frame();
+        }
+        return Ok(ran_instr);
+    }
+
+    /// Runs cycles until the end of the current 60 Hz frame, or until a breakpoint hits.
+    pub fn step_frame(&mut self) -> Result<Option<BreakpointHit>, EmulationError> {
+        let frame = self.frame_count;
+        while self.frame_count == frame {
+            if let Some(hit) = self.check_breakpoints() {
+                return Ok(Some(hit));
+            }
+            self.step_instruction()?;
+        }
+        return Ok(None);
+    }
+
+    /// Runs `n` cycles, or fewer if a breakpoint hits.
+    pub fn run_cycles(&mut self, n: u64) -> Result<Option<BreakpointHit>, EmulationError> {
+        for _ in 0..n {
+            if let Some(hit) = self.check_breakpoints() {
+                return Ok(Some(hit));
+            }
+            self.step_instruction()?;
+        }
+        return Ok(None);
+    }
+
+    fn end_frame(&mut self) {
+        self.cpu_state.tick_timers();
+        self.cpu_state.enter_vblank();
+        self.frame_cycle = 0;
+        self.frame_count += 1;
+        self.rewind.frame_ended(&self.cpu_state, (self.cycle_count, self.frame_count, self.frame_cycle));
+        if let Some(profiler) = &mut self.profiler {
+            profiler.frame_ended();
+        }
+        if let Some(movie) = &mut self.recording {
+            if self.frame_count.is_multiple_of(CHECKPOINT_INTERVAL_FRAMES) {
+                movie.checkpoints.push(Checkpoint { cycle: self.cycle_count, state_hash: movie::state_hash(&self.cpu_state) });
+            }
+        }
+        if let Some(playback) = &mut self.playback {
+            playback.check(self.cycle_count, &self.cpu_state);
+        }
+    }
+
+    /// Number of cycles run through the stepping API.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    /// Number of frames completed through the stepping API.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Steps until an instruction completes. A DRW waiting for vblank completes once the frame ends,
+    /// so one step always covers the whole instruction.
+    fn run_until_instr(&mut self) -> Result<(), EmulationError> {
+        while !self.has_exited() {
+            if self.step_instruction()? {
+                return Ok(());
+            }
+        }
+        return Ok(());
     }
 
     pub fn resume_timers(&mut self) {
@@ -79,48 +317,443 @@ impl Machine {
         self.vblank_timer.pause();
     }
 
-    pub fn run_step_debug(&mut self) -> String {
-        self.run_until_instr();
-        return debug_state(&self.cpu_state);
+    pub fn run_step_debug(&mut self) -> Result<String, EmulationError> {
+        self.run_until_instr()?;
+        return Ok(self.debug_state());
+    }
+
+    /// The next few instructions and the registers, as printed by `run_step_debug`.
+    pub fn debug_state(&self) -> String {
+        debug_state(&self.cpu_state)
     }
 
     pub fn save_current_state(&mut self, index: usize) -> Result<(), String> {
         if index >= NUM_SAVESTATES {
             return Err(format!("Savestate index is too high! {}, max {}", index, NUM_SAVESTATES-1));
         }
-        self.saved_states[index] = Some(self.cpu_state.clone());
+        self.saved_states[index] = Some(SlotState {
+            cpu: self.cpu_state.clone(),
+            cycle_count: self.cycle_count,
+            frame_count: self.frame_count,
+            frame_cycle: self.frame_cycle,
+        });
         return Ok(());
     }
 
+    /// Restores a quick save slot. See `after_state_jump` for what happens to a movie being recorded or played.
     pub fn load_state(&mut self, index: usize) -> Result<(), String> {
         let state_slot = self.saved_states.get(index)
             .ok_or(format!("Savestate index is too high! {}, max {}", index, NUM_SAVESTATES-1))?;
         let state = state_slot.as_ref().ok_or("No savestate in this slot")?;
-        self.cpu_state = state.clone();
+        self.cpu_state = state.cpu.clone();
+        self.cpu_state.disp.mark_all_dirty();
+        self.cycle_count = state.cycle_count;
+        self.frame_count = state.frame_count;
+        self.frame_cycle = state.frame_cycle;
+        self.rewind.clear();
+        self.after_state_jump();
+        return Ok(());
+    }
+
+    /// Keeps movies consistent after a save state is loaded. A recording continues from the loaded cycle,
+    /// like after a rewind. Playback ends, since the loaded state needn't be on the movie's timeline.
+    fn after_state_jump(&mut self) {
+        self.resync_recording();
+        self.playback = None;
+    }
+
+    /// Drops what was recorded at or after the current cycle, then records the keys that are held now
+    /// but not according to the events that are left, and the other way round.
+    fn resync_recording(&mut self) {
+        if let Some(movie) = &mut self.recording {
+            movie.truncate(self.cycle_count);
+            let recorded = movie.keys_before(self.cycle_count);
+            for (key, &pressed) in self.cpu_state.kbstate.key.iter().enumerate() {
+                if pressed != recorded[key] {
+                    movie.events.push(MovieEvent { cycle: self.cycle_count, key: key as u8, pressed });
+                }
+            }
+        }
+    }
+
+    /// Writes the whole machine as a versioned JSON save state, tagged with the ROM it is running.
+    pub fn save_state_to<W: Write>(&self, writer: W) -> Result<(), SaveStateError> {
+        let state = SaveState {
+            header: SaveStateHeader {
+                format_version: FORMAT_VERSION,
+                rom_hash: self.rom_hash,
+                config: self.cpu_state.config.clone(),
+            },
+            cpu: self.cpu_state.clone(),
+            rng_state: self.cpu_state.rng.state(),
+            rng_seed: self.rng_seed,
+            timers: [self.cpu_instr_timer.clone(), self.cpu_timer_regs_timer.clone(), self.vblank_timer.clone()],
+            cycle_count: self.cycle_count,
+            frame_count: self.frame_count,
+            frame_cycle: self.frame_cycle,
+        };
+        serde_json::to_writer(writer, &state)?;
+        return Ok(());
+    }
+
+    /// Restores a state written by `save_state_to`, including its config.
+    ///
+    /// States from another format version or another ROM, or describing a machine that can't exist, are refused,
+    /// and the machine is left untouched. The RND generator and the rewind history are rebuilt from the saved
+    /// config, replacing any generator set with `set_random_source`.
+    /// Movies are handled as in `load_state`.
+    pub fn load_state_from<R: Read>(&mut self, reader: R) -> Result<(), SaveStateError> {
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let HeaderOnly { header } = HeaderOnly::deserialize(&value)?;
+        if header.format_version != FORMAT_VERSION {
+            return Err(SaveStateError::UnsupportedVersion { found: header.format_version, supported: FORMAT_VERSION });
+        }
+        if header.rom_hash != self.rom_hash {
+            return Err(SaveStateError::RomMismatch { expected: self.rom_hash, found: header.rom_hash });
+        }
+        let mut state = SaveState::deserialize(value)?;
+        state.cpu.disp.validate().map_err(SaveStateError::Invalid)?;
+        let expected_size = mem_size(state.cpu.config.instruction_set);
+        if state.cpu.mem.size() != expected_size {
+            return Err(SaveStateError::Invalid(format!(
+                "Memory is {} bytes, expected {} for the saved instruction set", state.cpu.mem.size(), expected_size
+            )));
+        }
+        let mut rng = state.cpu.config.rng.build(state.rng_seed);
+        if !rng.set_state(&state.rng_state) {
+            return Err(SaveStateError::BadRngState);
+        }
+        state.cpu.rng = rng;
+        let [cpu_instr_timer, cpu_timer_regs_timer, vblank_timer] = state.timers;
+        self.cpu_state = state.cpu;
+        self.cpu_state.disp.mark_all_dirty();
+        self.cpu_instr_timer = cpu_instr_timer;
+        self.cpu_timer_regs_timer = cpu_timer_regs_timer;
+        self.vblank_timer = vblank_timer;
+        self.rng_seed = state.rng_seed;
+        self.cycle_count = state.cycle_count;
+        self.frame_count = state.frame_count;
+        self.frame_cycle = state.frame_cycle;
+        self.fault = None;
+        self.rewind = RewindBuffer::new(self.cpu_state.config.rewind_frames, self.cpu_state.config.rewind_interval);
+        self.after_state_jump();
+        return Ok(());
+    }
+
+    /// Goes back at least `frames` frames (or as far as the history reaches) and returns how many frames
+    /// were rewound. Calling it once per frame while a key is held gives hold-to-rewind.
+    ///
+    /// A recording is cut back to the rewound cycle and continues from the keys held now. Playback
+    /// continues from the rewound cycle with the keys the movie holds there.
+    pub fn rewind(&mut self, frames: u32) -> u32 {
+        match self.rewind.rewind(frames, &mut self.cpu_state) {
+            Some((rewound, (cycle_count, frame_count, frame_cycle))) => {
+                self.cycle_count = cycle_count;
+                self.frame_count = frame_count;
+                self.frame_cycle = frame_cycle;
+                self.fault = None;
+                self.resync_recording();
+                if let Some(playback) = &mut self.playback {
+                    self.cpu_state.kbstate.key = playback.seek(cycle_count);
+                }
+                return rewound;
+            }
+            None => return 0,
+        }
+    }
+
+    /// Frames of history `rewind` can currently go back through.
+    pub fn rewind_available(&self) -> u32 {
+        self.rewind.available_frames()
+    }
+
+    /// Starts recording key presses and releases into a movie. Movies start from power-on, so this fails
+    /// once the machine has run.
+    ///
+    /// Events are stamped with the cycle count, so only the stepping API (`step_frame` and friends)
+    /// records and plays back faithfully.
+    pub fn start_recording(&mut self) -> Result<(), String> {
+        if self.cycle_count != 0 {
+            return Err("Movies have to be recorded from power-on".to_string());
+        }
+        let mut config = self.cpu_state.config.clone();
+        config.rng_seed = Some(self.rng_seed);
+        self.recording = Some(Movie::new(self.rom_hash, config));
         return Ok(());
     }
 
+    /// Stops recording and returns the movie, or `None` if nothing was being recorded.
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        self.recording.take()
+    }
+
+    /// A machine that replays `movie` as it is stepped. Keys pressed on it directly are ignored.
+    pub fn play_movie(program: &[u8], movie: Movie) -> Result<Machine, MovieError> {
+        let hash = savestate::rom_hash(program);
+        if hash != movie.rom_hash {
+            return Err(MovieError::RomMismatch { expected: hash, found: movie.rom_hash });
+        }
+        movie.validate()?;
+        let mut machine = Machine::new(program, movie.config.clone())?;
+        machine.playback = Some(Playback::new(movie));
+        return Ok(machine);
+    }
+
+    /// The first point where playback stopped matching the recording.
+    pub fn movie_desync(&self) -> Option<Desync> {
+        self.playback.as_ref().and_then(|p| p.desync)
+    }
+
+    /// True once every event of the movie being played back has been fed in.
+    pub fn movie_finished(&self) -> bool {
+        self.playback.as_ref().is_some_and(|p| p.finished())
+    }
+
+    fn record_key(&mut self, key: u8, pressed: bool) {
+        if let Some(movie) = &mut self.recording {
+            movie.events.push(MovieEvent { cycle: self.cycle_count, key, pressed });
+        }
+    }
+
+    /// FNV-1a hash of the loaded ROM.
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    pub fn config(&self) -> &Chip8Config {
+        &self.cpu_state.config
+    }
+
+    /// The seed the RND generator was built from, even if the config left it up to the machine.
+    pub fn rng_seed(&self) -> u64 {
+        self.rng_seed
+    }
+
+    pub fn set_random_source(&mut self, rng: Box<dyn RandomSource>) {
+        self.cpu_state.rng = rng;
+    }
+
+    /// Logs every instruction run from now on. Cycles are numbered from the current `cycle_count`.
+    pub fn start_trace(&mut self, mut tracer: Tracer) {
+        tracer.set_cycle(self.cycle_count);
+        self.tracer = Some(tracer);
+    }
+
+    /// Stops tracing and flushes the sink. Returns the first error writing to it, if there was one.
+    pub fn stop_trace(&mut self) -> io::Result<()> {
+        match self.tracer.take() {
+            Some(tracer) => tracer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts counting where cycles go, discarding any earlier profile.
+    pub fn start_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn stop_profiling(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// The `top` hotspots and subroutines so far, or `None` when not profiling.
+    pub fn profile_report(&self, top: usize) -> Option<ProfileReport> {
+        self.profiler.as_ref().map(|profiler| profiler.report(&self.cpu_state.mem, top))
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    /// True if the instruction about to run would hit a breakpoint.
     pub fn debug_cond(&self) -> bool {
-        self.cpu_state.dt > 0
+        match self.cpu_state.fetch() {
+            Ok(instr) => self.breakpoints.find_hit(&self.cpu_state, instr).is_some(),
+            Err(_) => false,
+        }
     }
 
     pub fn press_key(&mut self, key: u8) {
+        if self.playback.is_some() {
+            return;
+        }
+        self.record_key(key, true);
         self.cpu_state.kbstate.press_key(key);
     }
 
     pub fn release_key(&mut self, key: u8) {
+        if self.playback.is_some() {
+            return;
+        }
+        self.record_key(key, false);
         self.cpu_state.kbstate.release_key(key);
     }
 
+    /// True once the program has run 00FD (EXIT).
+    pub fn has_exited(&self) -> bool {
+        matches!(self.cpu_state.halt_status, HaltStatus::Exited)
+    }
+
+    /// Registers, memory and the rest of the CPU, for debuggers and test harnesses.
+    pub fn cpu_state(&self) -> &CPUState {
+        &self.cpu_state
+    }
+
+    /// Lets a debugger poke registers and memory. Changes aren't recorded in movies.
+    pub fn cpu_state_mut(&mut self) -> &mut CPUState {
+        &mut self.cpu_state
+    }
+
     pub fn display_data(&self) -> &DisplayData {
         &self.cpu_state.disp
     }
 
+    /// The part of the screen that changed since the last call, or `None` if nothing did.
+    pub fn take_display_changes(&mut self) -> Option<DirtyRect> {
+        self.cpu_state.disp.take_changes()
+    }
+
+    /// True while the sound timer runs, the same as `sound_state().active`.
     pub fn should_make_sound(&self) -> bool {
-        self.cpu_state.st > 1
+        self.sound_state().active
+    }
+
+    /// What `audio::Synth` should play for the current frame.
+    pub fn sound_state(&self) -> SoundState {
+        let pattern = if self.cpu_state.config.instruction_set == InstructionSet::XoChip {
+            Some(AudioPattern { bits: self.cpu_state.audio_pattern, rate: self.audio_playback_rate() })
+        } else {
+            None
+        };
+        SoundState { active: self.cpu_state.st > 0, pattern }
+    }
+
+    /// The XO-CHIP 1-bit audio pattern, 128 samples played MSB first while the sound timer runs.
+    pub fn audio_pattern(&self) -> &[u8; 16] {
+        &self.cpu_state.audio_pattern
+    }
+
+    /// The XO-CHIP pitch register set by Fx3A. 64 is the default 4000 Hz playback rate.
+    pub fn audio_pitch(&self) -> u8 {
+        self.cpu_state.pitch
+    }
+
+    /// Samples per second the audio pattern plays at, derived from the pitch register.
+    pub fn audio_playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.cpu_state.pitch as f64 - 64.0) / 48.0)
+    }
+
+    /// Return addresses of the pending CALLs, outermost first.
+    pub fn call_stack(&self) -> Vec<u16> {
+        self.cpu_state.call_stack()
     }
 
     pub fn current_opcode(&self) -> u16 {
         self.cpu_state.get_opcode()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QuirkProfile;
+
+    /// 200: LD V0 3, 202: LD DT V0, 204: RND V1 FF, 206: LD F V1, 208: DRW V1 V1 5, 20A: ADD V2 1, 20C: JP 204
+    const ROM: [u8; 14] = [0x60, 0x03, 0xF0, 0x15, 0xC1, 0xFF, 0xF1, 0x29, 0xD1, 0x15, 0x72, 0x01, 0x12, 0x04];
+
+    fn machine() -> Machine {
+        let mut config = QuirkProfile::CosmacVip.config();
+        config.rng_seed = Some(7);
+        config.instructions_per_frame = 5;
+        return Machine::new(&ROM, config).unwrap();
+    }
+
+    fn snapshot(machine: &Machine) -> (Vec<u8>, u64, u64) {
+        let cpu = serde_json::to_vec(machine.cpu_state()).unwrap();
+        return (cpu, machine.cycle_count(), machine.frame_count());
+    }
+
+    #[test]
+    fn stepping_gives_the_same_run_every_time() {
+        let mut by_frame = machine();
+        for _ in 0..40 {
+            by_frame.step_frame().unwrap();
+        }
+        let mut by_cycles = machine();
+        by_cycles.run_cycles(200).unwrap();
+        let mut by_instruction = machine();
+        for _ in 0..200 {
+            by_instruction.step_instruction().unwrap();
+        }
+        assert_eq!(snapshot(&by_frame), snapshot(&by_cycles));
+        assert_eq!(snapshot(&by_frame), snapshot(&by_instruction));
+        assert_eq!((by_frame.cycle_count(), by_frame.frame_count()), (200, 40));
+        assert!(by_frame.cpu_state().v[2] > 0);
+    }
+
+    #[test]
+    fn timers_tick_once_per_frame() {
+        let mut machine = machine();
+        machine.run_cycles(2).unwrap();
+        assert_eq!(machine.cpu_state().dt, 3);
+        machine.step_frame().unwrap();
+        assert_eq!((machine.cpu_state().dt, machine.cycle_count()), (2, 5));
+        machine.step_frame().unwrap();
+        machine.step_frame().unwrap();
+        machine.step_frame().unwrap();
+        assert_eq!((machine.cpu_state().dt, machine.frame_count()), (0, 4));
+    }
+
+    /// 200: LD F V0, 202: DRW V0 V0 5, 204: LD V1 1
+    const DRAW_ROM: [u8; 6] = [0xF0, 0x29, 0xD0, 0x05, 0x61, 0x01];
+
+    fn draw_machine(vblank_delay: bool) -> Machine {
+        let config = Chip8Config {
+            emulate_draw_vblank_delay: vblank_delay,
+            instructions_per_frame: 10,
+            ..Chip8Config::default()
+        };
+        return Machine::new(&DRAW_ROM, config).unwrap();
+    }
+
+    #[test]
+    fn draws_wait_for_vblank_only_with_the_quirk() {
+        let mut machine = draw_machine(false);
+        machine.run_cycles(2).unwrap();
+        assert!(machine.display_data().get_pixel(0, 0));
+        assert_eq!(machine.cpu_state().pc, 0x204);
+
+        let mut machine = draw_machine(true);
+        machine.run_cycles(2).unwrap();
+        assert!(!machine.display_data().get_pixel(0, 0));
+        assert_eq!(machine.cpu_state().halt_status, HaltStatus::WaitingVblank);
+        //the draw happens on the first cycle after the frame ends
+        machine.run_cycles(8).unwrap();
+        assert!(!machine.display_data().get_pixel(0, 0));
+        machine.run_cycles(1).unwrap();
+        assert!(machine.display_data().get_pixel(0, 0));
+        assert_eq!((machine.cpu_state().pc, machine.cycle_count()), (0x204, 11));
+    }
+
+    #[test]
+    fn debug_steps_cover_a_whole_draw() {
+        for vblank_delay in [false, true] {
+            let mut machine = draw_machine(vblank_delay);
+            machine.run_step_debug().unwrap();
+            machine.run_step_debug().unwrap();
+            assert!(machine.display_data().get_pixel(0, 0), "vblank delay {}", vblank_delay);
+            assert_eq!(machine.cpu_state().pc, 0x204);
+            assert_eq!(machine.cpu_state().halt_status, HaltStatus::NotHalted);
+            machine.run_step_debug().unwrap();
+            assert_eq!(machine.cpu_state().v[1], 1);
+        }
+    }
+}
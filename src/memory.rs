@@ -1,7 +1,16 @@
-const MEMSIZE: usize = 0x1000;
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::AddressPolicy;
+use crate::decode::Instruction;
+use crate::error::EmulationError;
+
+pub const MEMSIZE: usize = 0x1000;
+pub const XO_MEMSIZE: usize = 0x10000;
 pub const PROG_START_ADDR: usize = 0x200;
 pub const STACK_START_ADDR: usize = 0x000;
-const STACK_SIZE: usize = 0x10 * 2;
+pub const STACK_SIZE: usize = 0x10 * 2;
 const FONT_START_ADDR: usize = STACK_START_ADDR + STACK_SIZE;
 const FONT_LETTER_SIZE: usize = 5;
 const FONT_DATA: [u8; FONT_LETTER_SIZE * 0x10] = [
@@ -38,58 +47,333 @@ const FONT_DATA: [u8; FONT_LETTER_SIZE * 0x10] = [
     //F
     0xF0, 0x80, 0xF0, 0x80, 0x80,
 ];
+const BIG_FONT_START_ADDR: usize = FONT_START_ADDR + FONT_DATA.len();
+const BIG_FONT_LETTER_SIZE: usize = 10;
+const BIG_FONT_DATA: [u8; BIG_FONT_LETTER_SIZE * 0x10] = [
+    //0
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C,
+    //1
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C,
+    //2
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF,
+    //3
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C,
+    //4
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06,
+    //5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C,
+    //6
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C,
+    //7
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60,
+    //8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C,
+    //9
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C,
+    //A
+    0x18, 0x3C, 0x66, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3,
+    //B
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC,
+    //C
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C,
+    //D
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC,
+    //E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF,
+    //F
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0,
+];
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SavedMemory")]
 pub struct Memory {
-    mem: [u8; MEMSIZE],
+    mem: Vec<u8>,
+    /// Instructions already decoded at each address. Writing to any byte an entry was decoded from drops it,
+    /// so self-modifying code still sees its changes.
+    #[serde(skip)]
+    decode_cache: Vec<Option<Instruction>>,
+    policy: AddressPolicy,
+}
+
+/// What a save state keeps of `Memory`. The decode cache is rebuilt as the program runs.
+#[derive(Deserialize)]
+struct SavedMemory {
+    mem: Vec<u8>,
+    policy: AddressPolicy,
+}
+
+impl From<SavedMemory> for Memory {
+    fn from(saved: SavedMemory) -> Memory {
+        Memory {
+            decode_cache: vec![None; saved.mem.len()],
+            mem: saved.mem,
+            policy: saved.policy,
+        }
+    }
 }
 
 impl Memory {
-    pub fn with_prog(program: &[u8]) -> Memory {
-        let mut mem = Memory { mem: [0; MEMSIZE] };
+    /// Memory of `size` bytes with the fonts and `program` loaded. `size` is `MEMSIZE`, or `XO_MEMSIZE` for XO-CHIP.
+    pub fn with_prog(program: &[u8], size: usize) -> Result<Memory, EmulationError> {
+        let mut mem = Memory {
+            mem: vec![0; size],
+            decode_cache: vec![None; size],
+            policy: AddressPolicy::Fault,
+        };
         mem.load_fonts();
-        mem.load_program_default(program);
-        return mem;
+        mem.load_program_default(program)?;
+        return Ok(mem);
+    }
+
+    pub fn address_policy(&self) -> AddressPolicy {
+        self.policy
+    }
+
+    pub fn set_address_policy(&mut self, policy: AddressPolicy) {
+        self.policy = policy;
+    }
+
+    /// Maps `addr` into memory according to the address policy. Fails with `addr` if the access should fault.
+    pub fn resolve(&self, addr: usize) -> Result<usize, usize> {
+        let size = self.mem.len();
+        if addr < size {
+            return Ok(addr);
+        }
+        match self.policy {
+            AddressPolicy::Wrap => Ok(addr % size),
+            AddressPolicy::Clamp => Ok(size - 1),
+            AddressPolicy::Fault => Err(addr),
+        }
+    }
+
+    pub fn read(&self, addr: usize) -> Result<u8, usize> {
+        return Ok(self.mem[self.resolve(addr)?]);
+    }
+
+    pub fn read_opcode(&self, addr: usize) -> Result<u16, usize> {
+        let next = addr.checked_add(1).ok_or(addr)?;
+        return Ok(u16::from_be_bytes([self.read(addr)?, self.read(next)?]));
+    }
+
+    /// `len` bytes starting at `addr`, borrowed straight from memory unless the address policy moved some of them.
+    pub fn read_range(&self, addr: usize, len: usize) -> Result<Cow<'_, [u8]>, usize> {
+        let end = addr.checked_add(len).ok_or(addr)?;
+        if end <= self.mem.len() {
+            return Ok(Cow::Borrowed(&self.mem[addr..end]));
+        }
+        let bytes = (addr..end).map(|a| self.read(a)).collect::<Result<Vec<u8>, usize>>()?;
+        return Ok(Cow::Owned(bytes));
     }
 
-    pub fn read(&self, addr: u16) -> u8 {
-        self.mem[addr as usize]
+    pub fn write(&mut self, addr: usize, val: u8) -> Result<(), usize> {
+        let addr = self.resolve(addr)?;
+        self.write_resolved(addr, val);
+        return Ok(());
     }
 
-    pub fn read_opcode(&self, addr: u16) -> u16 {
-        u16::from_be_bytes([self.read(addr), self.read(addr+1)])
+    /// Writes `data` starting at `addr`. Nothing is written if any of the addresses would fault.
+    pub fn write_range(&mut self, addr: usize, data: &[u8]) -> Result<(), usize> {
+        let end = addr.checked_add(data.len()).ok_or(addr)?;
+        let addrs = (addr..end).map(|a| self.resolve(a)).collect::<Result<Vec<usize>, usize>>()?;
+        for (addr, &val) in addrs.into_iter().zip(data) {
+            self.write_resolved(addr, val);
+        }
+        return Ok(());
+    }
+
+    fn write_resolved(&mut self, addr: usize, val: u8) {
+        self.mem[addr] = val;
+        //the longest instruction (F000 nnnn) is 4 bytes, so a write can change instructions starting up to 3 bytes back
+        let first = addr.saturating_sub(3);
+        self.decode_cache[first..=addr].iter_mut().for_each(|e| *e = None);
     }
 
-    pub fn write(&mut self, addr: u16, val: u8) {
-        self.mem[addr as usize] = val
+    pub fn cached_instruction(&self, addr: u16) -> Option<Instruction> {
+        self.decode_cache.get(addr as usize).copied().flatten()
+    }
+
+    pub fn cache_instruction(&mut self, addr: u16, instr: Instruction) {
+        if let Some(entry) = self.decode_cache.get_mut(addr as usize) {
+            *entry = Some(instr);
+        }
     }
 
     pub fn slice(&self) -> &[u8] {
         &self.mem
     }
 
-    fn load_program(&mut self, program: &[u8], start: usize) {
-        if program.len() > MEMSIZE - start {
-            panic!(
-                "Program is too big to fit in memory! Size: {} Space: {} ({} - {})",
-                program.len(),
-                MEMSIZE - start,
-                MEMSIZE,
-                start
-            );
+    /// Overwrites all of memory with `bytes`, which must be the same size, e.g. from a snapshot.
+    pub fn restore(&mut self, bytes: &[u8]) {
+        self.mem.copy_from_slice(bytes);
+        self.decode_cache.iter_mut().for_each(|e| *e = None);
+    }
+
+    pub fn size(&self) -> usize {
+        self.mem.len()
+    }
+
+    fn load_program(&mut self, program: &[u8], start: usize) -> Result<(), EmulationError> {
+        let space = self.mem.len() - start;
+        if program.len() > space {
+            return Err(EmulationError::RomTooLarge {
+                size: program.len(),
+                max: space,
+            });
         }
         self.mem[start..start + program.len()].copy_from_slice(program);
+        return Ok(());
     }
 
-    fn load_program_default(&mut self, program: &[u8]) {
-        self.load_program(program, PROG_START_ADDR);
+    fn load_program_default(&mut self, program: &[u8]) -> Result<(), EmulationError> {
+        self.load_program(program, PROG_START_ADDR)
     }
 
     fn load_fonts(&mut self) {
         self.mem[FONT_START_ADDR..FONT_START_ADDR + FONT_DATA.len()].copy_from_slice(&FONT_DATA);
+        self.mem[BIG_FONT_START_ADDR..BIG_FONT_START_ADDR + BIG_FONT_DATA.len()].copy_from_slice(&BIG_FONT_DATA);
     }
 
     pub fn get_font_addr(num: u8) -> u16 {
         (FONT_START_ADDR + (num as usize * FONT_LETTER_SIZE)) as u16
     }
+
+    pub fn get_big_font_addr(num: u8) -> u16 {
+        (BIG_FONT_START_ADDR + (num as usize * BIG_FONT_LETTER_SIZE)) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AddressPolicy, Chip8Config, QuirkProfile};
+    use crate::error::EmulationError;
+    use crate::cpu::CPUState;
+    use crate::display::DisplayData;
+
+    fn cpu(program: &[u8]) -> CPUState {
+        let config = QuirkProfile::XoChip.config();
+        let rng = config.rng.build(1);
+        return CPUState::new(Memory::with_prog(program, XO_MEMSIZE).unwrap(), DisplayData::new_64x32(), config, rng);
+    }
+
+    /// Runs the instruction at `pc` and returns V0.
+    fn run_at(cpu: &mut CPUState, pc: u16) -> u8 {
+        cpu.pc = pc;
+        cpu.run_cycle().unwrap();
+        return cpu.v[0];
+    }
+
+    #[test]
+    fn writes_drop_cached_instructions_they_change() {
+        //LD V0 11
+        let mut cpu = cpu(&[0x60, 0x11]);
+        assert_eq!(run_at(&mut cpu, 0x200), 0x11);
+        assert!(cpu.mem.cached_instruction(0x200).is_some());
+
+        //a byte before the instruction doesn't change it
+        cpu.mem.write(0x1FF, 0x70).unwrap();
+        assert!(cpu.mem.cached_instruction(0x200).is_some());
+        //either of its own bytes does
+        cpu.mem.write(0x201, 0x22).unwrap();
+        assert_eq!(run_at(&mut cpu, 0x200), 0x22);
+        cpu.mem.write(0x200, 0x70).unwrap();
+        assert_eq!(run_at(&mut cpu, 0x200), 0x44);
+
+        //an instruction decoded at an odd address, 1FF: ADD V0 70, changes with the byte after it
+        assert_eq!(run_at(&mut cpu, 0x1FF), 0xB4);
+        cpu.mem.write(0x200, 0x01).unwrap();
+        assert_eq!(run_at(&mut cpu, 0x1FF), 0xB5);
+    }
+
+    #[test]
+    fn writes_to_a_long_load_operand_drop_it() {
+        //LD I 1234
+        let mut cpu = cpu(&[0xF0, 0x00, 0x12, 0x34]);
+        run_at(&mut cpu, 0x200);
+        assert_eq!(cpu.i, 0x1234);
+        cpu.mem.write_range(0x202, &[0x56, 0x78]).unwrap();
+        run_at(&mut cpu, 0x200);
+        assert_eq!(cpu.i, 0x5678);
+        cpu.mem.write(0x203, 0x9A).unwrap();
+        run_at(&mut cpu, 0x200);
+        assert_eq!(cpu.i, 0x569A);
+    }
+
+    #[test]
+    fn programs_see_their_own_writes() {
+        //200: LD I 208, 202: LD V0 61, 204: LD V1 77, 206: LD [I] V0-V1, 208: LD V1 0, 20A: JP 20A
+        let program = [0xA2, 0x08, 0x60, 0x61, 0x61, 0x77, 0xF1, 0x55, 0x61, 0x00, 0x12, 0x0A];
+        let mut cpu = cpu(&program);
+        //decode 208 once, then let the program rewrite it to LD V1 77
+        run_at(&mut cpu, 0x208);
+        cpu.pc = 0x200;
+        for _ in 0..5 {
+            cpu.run_cycle().unwrap();
+        }
+        assert_eq!((cpu.v[1], cpu.pc), (0x77, 0x20A));
+    }
+
+    /// 4K of memory with 0xFF0 + n at address 0xFF0 + n, up to the top.
+    fn edge_memory(policy: AddressPolicy) -> Memory {
+        let mut mem = Memory::with_prog(&[], MEMSIZE).unwrap();
+        mem.set_address_policy(policy);
+        for addr in 0xFF0..MEMSIZE {
+            mem.write(addr, addr as u8).unwrap();
+        }
+        return mem;
+    }
+
+    #[test]
+    fn wrap_continues_at_the_start_of_memory() {
+        let mut mem = edge_memory(AddressPolicy::Wrap);
+        assert_eq!(mem.read(MEMSIZE), Ok(mem.slice()[0]));
+        assert_eq!(mem.read_opcode(0xFFF), Ok(u16::from_be_bytes([0xFF, mem.slice()[0]])));
+        mem.write_range(0xFFE, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(mem.slice()[0xFFE..], [1, 2]);
+        assert_eq!(mem.slice()[..2], [3, 4]);
+    }
+
+    #[test]
+    fn clamp_sticks_to_the_last_byte() {
+        let mut mem = edge_memory(AddressPolicy::Clamp);
+        assert_eq!(mem.read(MEMSIZE + 5), Ok(0xFF));
+        assert_eq!(mem.read_range(0xFFE, 4).unwrap()[..], [0xFE, 0xFF, 0xFF, 0xFF]);
+        mem.write_range(0xFFE, &[1, 2, 3]).unwrap();
+        assert_eq!(mem.slice()[0xFFE..], [1, 3]);
+    }
+
+    #[test]
+    fn fault_refuses_the_whole_access() {
+        let mut mem = edge_memory(AddressPolicy::Fault);
+        assert_eq!(mem.read(0xFFF), Ok(0xFF));
+        assert_eq!(mem.read(MEMSIZE), Err(MEMSIZE));
+        assert_eq!(mem.read_opcode(0xFFF), Err(MEMSIZE));
+        assert_eq!(mem.read_range(0xFFE, 3), Err(MEMSIZE));
+        assert_eq!(mem.write_range(0xFFE, &[1, 2, 3]), Err(MEMSIZE));
+        //nothing was written
+        assert_eq!(mem.slice()[0xFFE..], [0xFE, 0xFF]);
+        assert_eq!(mem.read_range(usize::MAX, 2), Err(usize::MAX));
+    }
+
+    fn cpu_at_the_edge(policy: AddressPolicy) -> CPUState {
+        let config = Chip8Config { address_policy: policy, ..QuirkProfile::Modern.config() };
+        let rng = config.rng.build(1);
+        //LD V0 5 at the start of memory
+        let mut mem = Memory::with_prog(&[], MEMSIZE).unwrap();
+        mem.write_range(0, &[0x60, 0x05]).unwrap();
+        let mut cpu = CPUState::new(mem, DisplayData::new_64x32(), config, rng);
+        cpu.pc = MEMSIZE as u16;
+        return cpu;
+    }
+
+    #[test]
+    fn pc_past_the_end_follows_the_policy() {
+        let mut cpu = cpu_at_the_edge(AddressPolicy::Wrap);
+        cpu.run_cycle().unwrap();
+        assert_eq!((cpu.v[0], cpu.pc), (5, 2));
+
+        let mut cpu = cpu_at_the_edge(AddressPolicy::Fault);
+        let err = cpu.run_cycle().unwrap_err();
+        assert_eq!(err, EmulationError::MemoryOutOfBounds { pc: MEMSIZE as u16, opcode: 0, addr: MEMSIZE });
+    }
 }
@@ -0,0 +1,211 @@
+use std::{fmt, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Chip8Config;
+use crate::cpu::CPUState;
+use crate::error::EmulationError;
+use crate::savestate::{fnv1a, FNV_OFFSET_BASIS};
+
+/// Bumped whenever the layout of `Movie` or what `state_hash` covers changes. Movies with a different version are refused.
+pub const MOVIE_FORMAT_VERSION: u32 = 2;
+/// Frames between the state hashes a recording stores for desync checks.
+pub const CHECKPOINT_INTERVAL_FRAMES: u64 = 60;
+
+/// Key presses and releases recorded from power-on, with everything needed to replay them exactly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Movie {
+    pub format_version: u32,
+    pub rom_hash: u64,
+    /// The recording machine's config, with `rng_seed` filled in
+    pub config: Chip8Config,
+    pub events: Vec<MovieEvent>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+/// A key changing state before cycle `cycle` runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MovieEvent {
+    pub cycle: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// Hash of the machine state after `cycle` cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub cycle: u64,
+    pub state_hash: u64,
+}
+
+/// The first checkpoint playback didn't reproduce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Desync {
+    pub cycle: u64,
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl Movie {
+    pub fn new(rom_hash: u64, config: Chip8Config) -> Movie {
+        Movie {
+            format_version: MOVIE_FORMAT_VERSION,
+            rom_hash,
+            config,
+            events: Vec::new(),
+            checkpoints: Vec::new(),
+        }
+    }
+
+    pub fn write_to<W: io::Write>(&self, writer: W) -> Result<(), MovieError> {
+        serde_json::to_writer(writer, self)?;
+        return Ok(());
+    }
+
+    pub fn read_from<R: io::Read>(reader: R) -> Result<Movie, MovieError> {
+        let movie: Movie = serde_json::from_reader(reader)?;
+        if movie.format_version != MOVIE_FORMAT_VERSION {
+            return Err(MovieError::UnsupportedVersion { found: movie.format_version, supported: MOVIE_FORMAT_VERSION });
+        }
+        movie.validate()?;
+        return Ok(movie);
+    }
+
+    /// Checks for events a machine can't play back, like a key past F.
+    pub fn validate(&self) -> Result<(), MovieError> {
+        match self.events.iter().find(|e| e.key > 0xF) {
+            Some(event) => Err(MovieError::BadKey { cycle: event.cycle, key: event.key }),
+            None => Ok(()),
+        }
+    }
+
+    /// Drops everything recorded at or after `cycle`, e.g. after rewinding during a recording.
+    pub fn truncate(&mut self, cycle: u64) {
+        self.events.retain(|e| e.cycle < cycle);
+        self.checkpoints.retain(|c| c.cycle < cycle);
+    }
+
+    /// The keys held when cycle `cycle` starts, before that cycle's own events.
+    pub fn keys_before(&self, cycle: u64) -> [bool; 0x10] {
+        let mut keys = [false; 0x10];
+        for event in self.events.iter().take_while(|e| e.cycle < cycle) {
+            keys[event.key as usize] = event.pressed;
+        }
+        return keys;
+    }
+}
+
+/// Hash of the CPU state compared against a movie's checkpoints: everything a save state keeps of it, plus the
+/// RND state.
+pub fn state_hash(cpu: &CPUState) -> u64 {
+    //hashing the save state form means a field added to CPUState is covered without touching this
+    let saved = serde_json::to_vec(cpu).expect("CPUState always serializes");
+    let hash = fnv1a(FNV_OFFSET_BASIS, &saved);
+    return fnv1a(hash, &cpu.rng.state());
+}
+
+/// Where a machine is in a movie it is playing back.
+#[derive(Debug, Clone)]
+pub(crate) struct Playback {
+    pub movie: Movie,
+    pub next_event: usize,
+    pub next_checkpoint: usize,
+    pub desync: Option<Desync>,
+}
+
+impl Playback {
+    pub fn new(movie: Movie) -> Playback {
+        Playback { movie, next_event: 0, next_checkpoint: 0, desync: None }
+    }
+
+    /// Events due before cycle `cycle` runs.
+    pub fn take_events(&mut self, cycle: u64) -> &[MovieEvent] {
+        let start = self.next_event;
+        while self.movie.events.get(self.next_event).is_some_and(|e| e.cycle <= cycle) {
+            self.next_event += 1;
+        }
+        &self.movie.events[start..self.next_event]
+    }
+
+    /// Checks the state after `cycle` cycles against the recording, if it has a checkpoint there.
+    pub fn check(&mut self, cycle: u64, cpu: &CPUState) {
+        while let Some(&checkpoint) = self.movie.checkpoints.get(self.next_checkpoint) {
+            if checkpoint.cycle > cycle {
+                return;
+            }
+            self.next_checkpoint += 1;
+            if checkpoint.cycle == cycle && self.desync.is_none() {
+                let found = state_hash(cpu);
+                if found != checkpoint.state_hash {
+                    self.desync = Some(Desync { cycle, expected: checkpoint.state_hash, found });
+                }
+            }
+        }
+    }
+
+    /// Moves the cursors back or forward so playback continues from cycle `cycle`, and returns the keys held there.
+    /// A desync found after that point is forgotten, as it hasn't happened yet.
+    pub fn seek(&mut self, cycle: u64) -> [bool; 0x10] {
+        self.next_event = self.movie.events.partition_point(|e| e.cycle < cycle);
+        self.next_checkpoint = self.movie.checkpoints.partition_point(|c| c.cycle <= cycle);
+        if self.desync.is_some_and(|d| d.cycle > cycle) {
+            self.desync = None;
+        }
+        return self.movie.keys_before(cycle);
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next_event >= self.movie.events.len()
+    }
+}
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    /// The data isn't a movie, or is a damaged one
+    Format(serde_json::Error),
+    UnsupportedVersion { found: u32, supported: u32 },
+    /// The movie was recorded with a different ROM
+    RomMismatch { expected: u64, found: u64 },
+    /// An event presses or releases a key that doesn't exist
+    BadKey { cycle: u64, key: u8 },
+    Emulation(EmulationError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Io(err) => write!(f, "Couldn't read or write movie: {}", err),
+            MovieError::Format(err) => write!(f, "Movie is malformed: {}", err),
+            MovieError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "Movie format version {} isn't supported (expected {})",
+                found, supported
+            ),
+            MovieError::RomMismatch { expected, found } => write!(
+                f,
+                "Movie was recorded with a different ROM (hash {:016X}, loaded ROM is {:016X})",
+                found, expected
+            ),
+            MovieError::BadKey { cycle, key } => write!(f, "Movie has an event for key {} at cycle {}, keys go up to F", key, cycle),
+            MovieError::Emulation(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<serde_json::Error> for MovieError {
+    fn from(err: serde_json::Error) -> MovieError {
+        if err.is_io() {
+            return MovieError::Io(err.into());
+        }
+        MovieError::Format(err)
+    }
+}
+
+impl From<EmulationError> for MovieError {
+    fn from(err: EmulationError) -> MovieError {
+        MovieError::Emulation(err)
+    }
+}
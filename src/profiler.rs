@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cpu::{CPUState, HaltStatus};
+use crate::decode::Instruction;
+use crate::disassembler::disassemble_memory_at;
+use crate::memory::Memory;
+
+/// Cycles spent in a subroutine, keyed by its address in `Profiler`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubroutineStats {
+    pub calls: u64,
+    /// Cycles from each CALL to its RET, including nested calls
+    pub inclusive_cycles: u64,
+    /// Cycles spent in the subroutine itself, not counting nested calls
+    pub self_cycles: u64,
+}
+
+/// Counts where the CPU spends its cycles. Attach one with `Machine::start_profiling`.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    /// Instructions started at each address
+    executions: Vec<u64>,
+    /// Cycles spent at each address, including waits
+    cycles: Vec<u64>,
+    classes: HashMap<&'static str, u64>,
+    subroutines: HashMap<u16, SubroutineStats>,
+    /// Subroutines entered and not yet returned from, with the total cycle count when they were called
+    call_stack: Vec<(u16, u64)>,
+    total_cycles: u64,
+    instructions: u64,
+    vblank_wait_cycles: u64,
+    key_wait_cycles: u64,
+    frame_instructions: u32,
+    instructions_per_frame: Vec<u32>,
+    /// The instruction started this cycle, to follow CALLs and RETs once it finishes
+    pending: Option<Instruction>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler {
+            executions: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+            classes: HashMap::new(),
+            subroutines: HashMap::new(),
+            call_stack: Vec::new(),
+            total_cycles: 0,
+            instructions: 0,
+            vblank_wait_cycles: 0,
+            key_wait_cycles: 0,
+            frame_instructions: 0,
+            instructions_per_frame: Vec::new(),
+            pending: None,
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub(crate) fn before_cycle(&mut self, cpu: &CPUState) {
+        self.total_cycles += 1;
+        self.cycles[cpu.pc as usize] += 1;
+        if let Some(&(addr, _)) = self.call_stack.last() {
+            self.subroutines.entry(addr).or_default().self_cycles += 1;
+        }
+        match cpu.halt_status {
+            HaltStatus::WaitingVblank => self.vblank_wait_cycles += 1,
+            HaltStatus::WaitingFx0A => self.key_wait_cycles += 1,
+            HaltStatus::NotHalted => {
+                //a fetch that faults never runs, so there is nothing to count
+                if let Ok(instr) = cpu.fetch() {
+                    self.executions[cpu.pc as usize] += 1;
+                    self.instructions += 1;
+                    self.frame_instructions += 1;
+                    *self.classes.entry(instr.class()).or_default() += 1;
+                    self.pending = Some(instr);
+                }
+            }
+            HaltStatus::ExecutingDRW | HaltStatus::Exited => {}
+        }
+    }
+
+    pub(crate) fn after_cycle(&mut self) {
+        match self.pending.take() {
+            Some(Instruction::Call(addr)) => {
+                self.subroutines.entry(addr).or_default().calls += 1;
+                self.call_stack.push((addr, self.total_cycles));
+            }
+            Some(Instruction::Ret) => {
+                //a RET with nothing on the stack returns from a call made before profiling started
+                if let Some((addr, called_at)) = self.call_stack.pop() {
+                    self.subroutines.entry(addr).or_default().inclusive_cycles += self.total_cycles - called_at;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn fault(&mut self) {
+        self.pending = None;
+    }
+
+    pub(crate) fn frame_ended(&mut self) {
+        self.instructions_per_frame.push(self.frame_instructions);
+        self.frame_instructions = 0;
+    }
+
+    /// Instructions started in each completed frame, oldest first.
+    pub fn instructions_per_frame(&self) -> &[u32] {
+        &self.instructions_per_frame
+    }
+
+    pub fn subroutine(&self, addr: u16) -> Option<SubroutineStats> {
+        self.subroutines.get(&addr).copied()
+    }
+
+    /// Ranks the `top` busiest addresses and subroutines, disassembling from `mem`.
+    pub fn report(&self, mem: &Memory, top: usize) -> ProfileReport {
+        let mut hotspots: Vec<Hotspot> = (0..self.cycles.len())
+            .filter(|&addr| self.cycles[addr] > 0)
+            .map(|addr| Hotspot {
+                addr: addr as u16,
+                executions: self.executions[addr],
+                cycles: self.cycles[addr],
+                disassembly: disassemble_memory_at(mem, addr).0,
+            })
+            .collect();
+        hotspots.sort_by_key(|hotspot| std::cmp::Reverse(hotspot.cycles));
+        hotspots.truncate(top);
+
+        let mut opcode_classes: Vec<(&'static str, u64)> = self.classes.iter().map(|(&class, &n)| (class, n)).collect();
+        opcode_classes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        let mut open_calls = self.subroutines.clone();
+        //count the calls that haven't returned yet up to now
+        for &(addr, called_at) in &self.call_stack {
+            open_calls.entry(addr).or_default().inclusive_cycles += self.total_cycles - called_at;
+        }
+        let mut subroutines: Vec<(u16, SubroutineStats)> = open_calls.into_iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive_cycles.cmp(&a.1.inclusive_cycles).then(a.0.cmp(&b.0)));
+        subroutines.truncate(top);
+
+        let frames = &self.instructions_per_frame;
+        let frame_stats = FrameStats {
+            frames: frames.len(),
+            min: frames.iter().copied().min().unwrap_or(0),
+            max: frames.iter().copied().max().unwrap_or(0),
+            mean: frames.iter().map(|&n| n as f64).sum::<f64>() / frames.len().max(1) as f64,
+        };
+
+        return ProfileReport {
+            total_cycles: self.total_cycles,
+            instructions: self.instructions,
+            vblank_wait_cycles: self.vblank_wait_cycles,
+            key_wait_cycles: self.key_wait_cycles,
+            hotspots,
+            opcode_classes,
+            subroutines,
+            frames: frame_stats,
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hotspot {
+    pub addr: u16,
+    pub executions: u64,
+    pub cycles: u64,
+    /// "opcode | instruction", as currently in memory
+    pub disassembly: String,
+}
+
+/// Instructions started per frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub frames: usize,
+    pub min: u32,
+    pub max: u32,
+    pub mean: f64,
+}
+
+/// Where the cycles went, from `Profiler::report`. Displays as a plain text table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileReport {
+    pub total_cycles: u64,
+    pub instructions: u64,
+    /// Cycles spent waiting for vblank before a DRW
+    pub vblank_wait_cycles: u64,
+    /// Cycles spent in Fx0A waiting for a key
+    pub key_wait_cycles: u64,
+    /// Busiest addresses first
+    pub hotspots: Vec<Hotspot>,
+    /// Executions per opcode class, like "Dxyn", most executed first
+    pub opcode_classes: Vec<(&'static str, u64)>,
+    /// Subroutines by address, most inclusive cycles first
+    pub subroutines: Vec<(u16, SubroutineStats)>,
+    pub frames: FrameStats,
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |cycles: u64| cycles as f64 * 100.0 / self.total_cycles.max(1) as f64;
+        writeln!(f, "{} cycles, {} instructions", self.total_cycles, self.instructions)?;
+        writeln!(f, "waiting for vblank: {} cycles ({:.1}%)", self.vblank_wait_cycles, percent(self.vblank_wait_cycles))?;
+        writeln!(f, "waiting for a key:  {} cycles ({:.1}%)", self.key_wait_cycles, percent(self.key_wait_cycles))?;
+        writeln!(
+            f,
+            "instructions per frame: {:.1} mean, {} min, {} max over {} frames",
+            self.frames.mean, self.frames.min, self.frames.max, self.frames.frames
+        )?;
+
+        writeln!(f, "\nhotspots:\n  addr     cycles       %       runs  instruction")?;
+        for hotspot in &self.hotspots {
+            writeln!(
+                f,
+                "  {:04X} {:>10} {:>6.1}% {:>10}  {}",
+                hotspot.addr, hotspot.cycles, percent(hotspot.cycles), hotspot.executions, hotspot.disassembly
+            )?;
+        }
+
+        writeln!(f, "\nsubroutines:\n  addr      calls  inclusive       %       self")?;
+        for (addr, stats) in &self.subroutines {
+            writeln!(
+                f,
+                "  {:04X} {:>10} {:>10} {:>6.1}% {:>10}",
+                addr, stats.calls, stats.inclusive_cycles, percent(stats.inclusive_cycles), stats.self_cycles
+            )?;
+        }
+
+        writeln!(f, "\nopcode classes:")?;
+        for (class, n) in &self.opcode_classes {
+            writeln!(f, "  {} {:>10}", class, n)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QuirkProfile;
+    use crate::machine::Machine;
+
+    /// 200: CALL 206, 202: JP 202, 206: CALL 20C, 208: RET, 20C: LD V0 1, 20E: RET
+    const NESTED: [u8; 16] = [0x22, 0x06, 0x12, 0x02, 0, 0, 0x22, 0x0C, 0x00, 0xEE, 0, 0, 0x60, 0x01, 0x00, 0xEE];
+
+    fn machine(program: &[u8]) -> Machine {
+        return Machine::new(program, QuirkProfile::Modern.config()).unwrap();
+    }
+
+    #[test]
+    fn nested_calls_split_inclusive_and_self_cycles() {
+        let mut machine = machine(&NESTED);
+        machine.start_profiling();
+        machine.run_cycles(8).unwrap();
+        let profiler = machine.profiler().unwrap();
+        //206 runs CALL 20C and RET itself, around the 2 cycles of 20C
+        assert_eq!(profiler.subroutine(0x206), Some(SubroutineStats { calls: 1, inclusive_cycles: 4, self_cycles: 2 }));
+        assert_eq!(profiler.subroutine(0x20C), Some(SubroutineStats { calls: 1, inclusive_cycles: 2, self_cycles: 2 }));
+
+        let report = machine.profile_report(10).unwrap();
+        assert_eq!((report.total_cycles, report.instructions), (8, 8));
+        assert_eq!(report.subroutines.iter().map(|(addr, _)| *addr).collect::<Vec<u16>>(), [0x206, 0x20C]);
+        assert_eq!(report.hotspots[0].addr, 0x202);
+        assert_eq!(report.hotspots[0].cycles, 3);
+    }
+
+    #[test]
+    fn calls_still_running_count_up_to_now() {
+        let mut machine = machine(&NESTED);
+        machine.start_profiling();
+        machine.run_cycles(3).unwrap();
+        assert_eq!(machine.profiler().unwrap().subroutine(0x206).unwrap().inclusive_cycles, 0);
+        let report = machine.profile_report(10).unwrap();
+        let stats: HashMap<u16, SubroutineStats> = report.subroutines.into_iter().collect();
+        assert_eq!(stats[&0x206].inclusive_cycles, 2);
+        assert_eq!(stats[&0x20C].inclusive_cycles, 1);
+    }
+
+    #[test]
+    fn returns_from_before_profiling_are_ignored() {
+        //200: CALL 204, 202: JP 202, 204: RET
+        let mut machine = machine(&[0x22, 0x04, 0x12, 0x02, 0x00, 0xEE]);
+        machine.run_cycles(1).unwrap();
+        machine.start_profiling();
+        machine.run_cycles(3).unwrap();
+        let profiler = machine.profiler().unwrap();
+        assert_eq!(profiler.subroutine(0x204), None);
+        let report = machine.profile_report(10).unwrap();
+        assert!(report.subroutines.is_empty());
+        assert_eq!(report.instructions, 3);
+    }
+}
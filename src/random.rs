@@ -0,0 +1,241 @@
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+/// Source of the bytes returned by RND (Cxkk).
+///
+/// Generators live in the machine state, so they are cloned into save states along with everything else.
+pub trait RandomSource: Debug {
+    fn next_byte(&mut self) -> u8;
+
+    fn box_clone(&self) -> Box<dyn RandomSource>;
+
+    /// The generator's internal state, in whatever layout the generator likes.
+    fn state(&self) -> Vec<u8>;
+
+    /// Restores a state returned by `state`. Returns false if `state` isn't one this generator produced.
+    fn set_state(&mut self, state: &[u8]) -> bool;
+}
+
+impl Clone for Box<dyn RandomSource> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum RngKind {
+    #[default]
+    XorShift,
+    CosmacVip,
+}
+
+impl RngKind {
+    pub fn build(self, seed: u64) -> Box<dyn RandomSource> {
+        match self {
+            RngKind::XorShift => Box::new(XorShiftRng::new(seed)),
+            RngKind::CosmacVip => Box::new(VipRng::new(seed)),
+        }
+    }
+}
+
+/// xorshift64* generator. Small, fast, and its whole state is one word.
+#[derive(Debug, Clone)]
+pub struct XorShiftRng {
+    state: u64,
+}
+
+impl XorShiftRng {
+    pub fn new(seed: u64) -> XorShiftRng {
+        //xorshift gets stuck on 0, so mix the seed and make sure at least one bit is set
+        XorShiftRng { state: splitmix64(seed) | 1 }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        return self.state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+    }
+}
+
+impl RandomSource for XorShiftRng {
+    fn next_byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn box_clone(&self) -> Box<dyn RandomSource> {
+        Box::new(self.clone())
+    }
+
+    fn state(&self) -> Vec<u8> {
+        self.state.to_le_bytes().to_vec()
+    }
+
+    fn set_state(&mut self, state: &[u8]) -> bool {
+        match <[u8; 8]>::try_from(state) {
+            Ok(bytes) if u64::from_le_bytes(bytes) != 0 => {
+                self.state = u64::from_le_bytes(bytes);
+                return true;
+            }
+            _ => return false,
+        }
+    }
+}
+
+pub const VIP_PAGE_SIZE: usize = 0x100;
+
+/// Generator modelled on the COSMAC VIP interpreter's RND routine.
+///
+/// The VIP has no RNG hardware. Its interpreter walks a pointer through a page of its own code and adds the
+/// byte it finds there to the previous result, so the sequence depends entirely on that page. Pass one to
+/// `with_page` (e.g. from a dump of the interpreter) to reproduce a specific machine; `new` fills the page
+/// from the seed instead.
+#[derive(Debug, Clone)]
+pub struct VipRng {
+    page: Vec<u8>,
+    ptr: u8,
+    last: u8,
+}
+
+impl VipRng {
+    pub fn new(seed: u64) -> VipRng {
+        let mut page = Vec::with_capacity(VIP_PAGE_SIZE);
+        let mut state = seed;
+        while page.len() < VIP_PAGE_SIZE {
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            page.extend_from_slice(&splitmix64(state).to_le_bytes());
+        }
+        VipRng::with_page(&page, (seed >> 8) as u8, seed as u8)
+    }
+
+    /// `page` is the 256 byte code page the routine reads from. `ptr` and `last` are the routine's starting
+    /// pointer and previous result.
+    pub fn with_page(page: &[u8], ptr: u8, last: u8) -> VipRng {
+        let mut full_page = vec![0; VIP_PAGE_SIZE];
+        let len = page.len().min(VIP_PAGE_SIZE);
+        full_page[..len].copy_from_slice(&page[..len]);
+        VipRng { page: full_page, ptr, last }
+    }
+}
+
+impl RandomSource for VipRng {
+    fn next_byte(&mut self) -> u8 {
+        self.ptr = self.ptr.wrapping_add(1);
+        self.last = self.page[self.ptr as usize].wrapping_add(self.last);
+        return self.last;
+    }
+
+    fn box_clone(&self) -> Box<dyn RandomSource> {
+        Box::new(self.clone())
+    }
+
+    fn state(&self) -> Vec<u8> {
+        let mut state = vec![self.ptr, self.last];
+        state.extend_from_slice(&self.page);
+        return state;
+    }
+
+    fn set_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != 2 + VIP_PAGE_SIZE {
+            return false;
+        }
+        self.ptr = state[0];
+        self.last = state[1];
+        self.page.copy_from_slice(&state[2..]);
+        return true;
+    }
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    return z ^ (z >> 31);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QuirkProfile;
+    use crate::machine::Machine;
+
+    fn bytes(rng: &mut dyn RandomSource, n: usize) -> Vec<u8> {
+        (0..n).map(|_| rng.next_byte()).collect()
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_sequence() {
+        for kind in [RngKind::XorShift, RngKind::CosmacVip] {
+            let first = bytes(kind.build(42).as_mut(), 64);
+            assert_eq!(first, bytes(kind.build(42).as_mut(), 64), "{:?}", kind);
+            assert_ne!(first, bytes(kind.build(43).as_mut(), 64), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn restoring_a_state_continues_the_sequence() {
+        for kind in [RngKind::XorShift, RngKind::CosmacVip] {
+            let mut rng = kind.build(7);
+            bytes(rng.as_mut(), 10);
+            let state = rng.state();
+            let expected = bytes(rng.as_mut(), 300);
+
+            //a generator from another seed picks up where the saved one was
+            let mut restored = kind.build(8);
+            assert!(restored.set_state(&state));
+            assert_eq!(bytes(restored.as_mut(), 300), expected, "{:?}", kind);
+            assert!(!restored.set_state(&state[1..]), "{:?}", kind);
+        }
+        assert!(!XorShiftRng::new(1).set_state(&[0; 8]));
+    }
+
+    #[test]
+    fn vip_rng_adds_the_next_page_byte_to_the_last_result() {
+        let page: Vec<u8> = (0..=255).collect();
+        let mut rng = VipRng::with_page(&page, 0xFE, 0x10);
+        //page[0xFF], then the pointer wraps to page[0x00], page[0x01], ...
+        assert_eq!(bytes(&mut rng, 4), [0x0F, 0x0F, 0x10, 0x12]);
+        //a short page is padded with zeros
+        let mut rng = VipRng::with_page(&[5], 0xFF, 1);
+        assert_eq!(bytes(&mut rng, 3), [6, 6, 6]);
+    }
+
+    /// Always returns the same byte.
+    #[derive(Debug, Clone)]
+    struct Constant(u8);
+
+    impl RandomSource for Constant {
+        fn next_byte(&mut self) -> u8 {
+            self.0
+        }
+
+        fn box_clone(&self) -> Box<dyn RandomSource> {
+            Box::new(self.clone())
+        }
+
+        fn state(&self) -> Vec<u8> {
+            vec![self.0]
+        }
+
+        fn set_state(&mut self, state: &[u8]) -> bool {
+            self.0 = state[0];
+            return true;
+        }
+    }
+
+    #[test]
+    fn rnd_masks_with_kk_not_vx() {
+        //LD V0 F0, RND V0 3C
+        let mut machine = Machine::new(&[0x60, 0xF0, 0xC0, 0x3C], QuirkProfile::Modern.config()).unwrap();
+        machine.set_random_source(Box::new(Constant(0xA5)));
+        machine.run_cycles(2).unwrap();
+        assert_eq!(machine.cpu_state().v[0], 0xA5 & 0x3C);
+    }
+
+    #[test]
+    fn the_vip_profile_uses_the_vip_generator() {
+        assert_eq!(QuirkProfile::CosmacVip.config().rng, RngKind::CosmacVip);
+        assert_eq!(QuirkProfile::Modern.config().rng, RngKind::XorShift);
+    }
+}
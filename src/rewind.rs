@@ -0,0 +1,398 @@
+use std::collections::VecDeque;
+
+use crate::cpu::{CPUState, HaltStatus};
+use crate::keyboard::Fx0AStatus;
+
+/// Everything in `CPUState` except memory and the display, which are stored as deltas.
+#[derive(Debug, Clone)]
+struct Registers {
+    pc: u16,
+    i: u16,
+    v: [u8; 0x10],
+    sp: u8,
+    stack: Vec<u16>,
+    dt: u8,
+    st: u8,
+    rpl: [u8; 0x10],
+    audio_pattern: [u8; 0x10],
+    pitch: u8,
+    fx0a: Fx0AStatus,
+    halt_status: HaltStatus,
+    rng_state: Vec<u8>,
+    disp_width: usize,
+    disp_height: usize,
+    plane_mask: u8,
+}
+
+impl Registers {
+    fn capture(cpu: &CPUState) -> Registers {
+        Registers {
+            pc: cpu.pc,
+            i: cpu.i,
+            v: cpu.v,
+            sp: cpu.sp,
+            stack: cpu.stack.clone(),
+            dt: cpu.dt,
+            st: cpu.st,
+            rpl: cpu.rpl,
+            audio_pattern: cpu.audio_pattern,
+            pitch: cpu.pitch,
+            fx0a: cpu.kbstate.Fx0A,
+            halt_status: cpu.halt_status,
+            rng_state: cpu.rng.state(),
+            disp_width: cpu.disp.width,
+            disp_height: cpu.disp.height,
+            plane_mask: cpu.disp.plane_mask(),
+        }
+    }
+
+    fn restore(&self, cpu: &mut CPUState) {
+        cpu.pc = self.pc;
+        cpu.i = self.i;
+        cpu.v = self.v;
+        cpu.sp = self.sp;
+        cpu.stack = self.stack.clone();
+        cpu.dt = self.dt;
+        cpu.st = self.st;
+        cpu.rpl = self.rpl;
+        cpu.audio_pattern = self.audio_pattern;
+        cpu.pitch = self.pitch;
+        //keys keep whatever the player is holding right now
+        cpu.kbstate.Fx0A = self.fx0a;
+        cpu.halt_status = self.halt_status;
+        cpu.rng.set_state(&self.rng_state);
+        cpu.disp.select_planes(self.plane_mask);
+    }
+}
+
+/// XOR of a buffer against the next newer one, run-length encoded.
+///
+/// Encoded as repeated (run of unchanged bytes, number of changed bytes, changed bytes), with both counts as
+/// LEB128 varints. Most bytes don't change from one snapshot to the next, so this is usually tiny.
+#[derive(Debug, Clone, Default)]
+struct Delta {
+    len: usize,
+    rle: Vec<u8>,
+}
+
+impl Delta {
+    /// The delta that turns `newer` back into `older`.
+    fn between(older: &[u8], newer: &[u8]) -> Delta {
+        let xor_at = |i: usize| older[i] ^ newer.get(i).copied().unwrap_or(0);
+        let mut rle = Vec::new();
+        let mut i = 0;
+        while i < older.len() {
+            let unchanged_start = i;
+            while i < older.len() && xor_at(i) == 0 {
+                i += 1;
+            }
+            let changed_start = i;
+            while i < older.len() && xor_at(i) != 0 {
+                i += 1;
+            }
+            if changed_start == i {
+                break;
+            }
+            write_varint(&mut rle, changed_start - unchanged_start);
+            write_varint(&mut rle, i - changed_start);
+            rle.extend((changed_start..i).map(xor_at));
+        }
+        Delta { len: older.len(), rle }
+    }
+
+    fn apply(&self, buf: &mut Vec<u8>) {
+        buf.resize(self.len, 0);
+        let mut pos = 0;
+        let mut rle = &self.rle[..];
+        while !rle.is_empty() {
+            pos += read_varint(&mut rle);
+            let changed = read_varint(&mut rle);
+            for (byte, x) in buf[pos..pos + changed].iter_mut().zip(&rle[..changed]) {
+                *byte ^= x;
+            }
+            rle = &rle[changed..];
+            pos += changed;
+        }
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[0];
+        *data = &data[1..];
+        n |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Snapshot {
+    regs: Registers,
+    cycle_count: u64,
+    frame_count: u64,
+    frame_cycle: u32,
+    /// Empty for the newest snapshot, which is kept in full in `RewindBuffer`
+    mem_delta: Delta,
+    disp_delta: Delta,
+}
+
+/// Machine counters saved and restored along with the CPU, as (cycle count, frame count, cycle within frame).
+pub type FrameCounters = (u64, u64, u32);
+
+/// Ring buffer of snapshots taken every `interval` frames, oldest dropped first.
+///
+/// Only the newest snapshot's memory and display are stored whole. Every older one is a reverse delta
+/// against the snapshot after it, so rewinding walks back from the newest one.
+#[derive(Debug, Clone)]
+pub struct RewindBuffer {
+    snapshots: VecDeque<Snapshot>,
+    capacity: usize,
+    interval: u32,
+    frames_since_capture: u32,
+    newest_mem: Vec<u8>,
+    newest_disp: Vec<u8>,
+}
+
+impl RewindBuffer {
+    /// Keeps at least `history_frames` frames of history once full, snapshotting every `interval` frames.
+    /// A `history_frames` of 0 turns rewinding off.
+    pub fn new(history_frames: u32, interval: u32) -> RewindBuffer {
+        let interval = interval.max(1);
+        RewindBuffer {
+            snapshots: VecDeque::new(),
+            //the newest snapshot only covers the frames since it was taken, so keep one more to reach back far enough
+            capacity: if history_frames == 0 { 0 } else { history_frames.div_ceil(interval) as usize + 1 },
+            interval,
+            frames_since_capture: 0,
+            newest_mem: Vec::new(),
+            newest_disp: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.frames_since_capture = 0;
+        self.newest_mem = Vec::new();
+        self.newest_disp = Vec::new();
+    }
+
+    /// Frames that can currently be rewound.
+    pub fn available_frames(&self) -> u32 {
+        match self.snapshots.len() {
+            0 => 0,
+            n => (n as u32 - 1) * self.interval + self.frames_since_capture,
+        }
+    }
+
+    /// Counts a finished frame, taking a snapshot if one is due.
+    pub fn frame_ended(&mut self, cpu: &CPUState, counters: FrameCounters) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.frames_since_capture += 1;
+        if self.frames_since_capture >= self.interval || self.snapshots.is_empty() {
+            self.capture(cpu, counters);
+        }
+    }
+
+    fn capture(&mut self, cpu: &CPUState, (cycle_count, frame_count, frame_cycle): FrameCounters) {
+        let mem = cpu.mem.slice();
+        let disp = &cpu.disp.pixels();
+        if let Some(prev) = self.snapshots.back_mut() {
+            prev.mem_delta = Delta::between(&self.newest_mem, mem);
+            prev.disp_delta = Delta::between(&self.newest_disp, disp);
+        }
+        self.newest_mem.clear();
+        self.newest_mem.extend_from_slice(mem);
+        self.newest_disp.clear();
+        self.newest_disp.extend_from_slice(disp);
+        self.snapshots.push_back(Snapshot {
+            regs: Registers::capture(cpu),
+            cycle_count,
+            frame_count,
+            frame_cycle,
+            mem_delta: Delta::default(),
+            disp_delta: Delta::default(),
+        });
+        if self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.frames_since_capture = 0;
+    }
+
+    /// Goes back at least `frames` frames, or as far as the history reaches, restoring `cpu`.
+    ///
+    /// Snapshots newer than the one restored are dropped. Returns the frames actually rewound
+    /// and the counters saved with the snapshot, or `None` if there is no history.
+    pub fn rewind(&mut self, frames: u32, cpu: &mut CPUState) -> Option<(u32, FrameCounters)> {
+        if self.snapshots.is_empty() {
+            return None;
+        }
+        let mut rewound = self.frames_since_capture;
+        let mut back = 0;
+        while rewound < frames.max(1) && back + 1 < self.snapshots.len() {
+            rewound += self.interval;
+            back += 1;
+        }
+        for _ in 0..back {
+            self.snapshots.pop_back();
+            let snapshot = self.snapshots.back().unwrap();
+            snapshot.mem_delta.apply(&mut self.newest_mem);
+            snapshot.disp_delta.apply(&mut self.newest_disp);
+        }
+        let snapshot = self.snapshots.back_mut().unwrap();
+        snapshot.mem_delta = Delta::default();
+        snapshot.disp_delta = Delta::default();
+        snapshot.regs.restore(cpu);
+        cpu.mem.restore(&self.newest_mem);
+        cpu.disp.set_pixels(snapshot.regs.disp_width, snapshot.regs.disp_height, self.newest_disp.clone());
+        self.frames_since_capture = 0;
+        return Some((rewound, (snapshot.cycle_count, snapshot.frame_count, snapshot.frame_cycle)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Chip8Config;
+    use crate::display::DisplayData;
+    use crate::memory::{Memory, MEMSIZE};
+
+    fn round_trip(older: &[u8], newer: &[u8]) {
+        let delta = Delta::between(older, newer);
+        let mut buf = newer.to_vec();
+        delta.apply(&mut buf);
+        assert_eq!(buf, older);
+    }
+
+    #[test]
+    fn deltas_round_trip_at_equal_lengths() {
+        let older: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        round_trip(&older, &older);
+        round_trip(&older, &[0xFF; 4096]);
+        round_trip(&older, &[0; 4096]);
+        round_trip(&[], &[]);
+
+        //changes at both ends, and long unchanged and changed runs that need multi-byte varints
+        let mut newer = older.clone();
+        newer[0] ^= 0x80;
+        newer[300..600].iter_mut().for_each(|b| *b ^= 0x5A);
+        newer[4095] ^= 1;
+        round_trip(&older, &newer);
+        assert!(Delta::between(&older, &older).rle.is_empty());
+    }
+
+    #[test]
+    fn deltas_round_trip_between_lengths() {
+        //a lores screen is 64x32 pixels, a hires one 128x64, each pixel a color from 0 to 3
+        let lores: Vec<u8> = (0..64 * 32).map(|i| (i % 4 == 0) as u8).collect();
+        let hires: Vec<u8> = (0..128 * 64).map(|i| (i % 3) as u8).collect();
+        round_trip(&lores, &hires);
+        round_trip(&hires, &lores);
+        round_trip(&[0; 64 * 32], &hires);
+        round_trip(&hires, &[0; 64 * 32]);
+        round_trip(&hires, &[]);
+        round_trip(&[], &hires);
+    }
+
+    fn cpu() -> CPUState {
+        let config = Chip8Config::default();
+        let rng = config.rng.build(1);
+        CPUState::new(Memory::with_prog(&[], MEMSIZE).unwrap(), DisplayData::new_64x32(), config, rng)
+    }
+
+    /// Runs `frames` frames on `cpu`, marking each one in V0, memory and the display.
+    fn run_frames(buffer: &mut RewindBuffer, cpu: &mut CPUState, frames: std::ops::Range<u64>) {
+        for frame in frames {
+            cpu.v[0] = frame as u8;
+            cpu.mem.write(0x300 + frame as usize, frame as u8).unwrap();
+            if frame == 10 {
+                cpu.disp.set_hires(true);
+            }
+            cpu.disp.draw(&[0x80], frame as usize, 0, &cpu.config.clone());
+            buffer.frame_ended(cpu, (frame * 100, frame + 1, 0));
+        }
+    }
+
+    fn expect_frame(cpu: &CPUState, frame: u64) {
+        assert_eq!(cpu.v[0], frame as u8);
+        for f in 0..20 {
+            assert_eq!(cpu.mem.read(0x300 + f).unwrap(), if f as u64 <= frame { f as u8 } else { 0 });
+        }
+        assert_eq!(cpu.disp.is_hires(), frame >= 10);
+        assert!(cpu.disp.get_pixel(frame as usize, 0));
+    }
+
+    #[test]
+    fn rewinding_lands_on_snapshots_at_the_interval() {
+        let mut buffer = RewindBuffer::new(40, 4);
+        let mut cpu = cpu();
+        //snapshots after frames 0, 4, 8, 12 and 16, then two more frames
+        run_frames(&mut buffer, &mut cpu, 0..19);
+        assert_eq!(buffer.available_frames(), 4 * 4 + 2);
+
+        //still within the frames since the newest snapshot
+        assert_eq!(buffer.rewind(2, &mut cpu), Some((2, (1600, 17, 0))));
+        expect_frame(&cpu, 16);
+        assert_eq!(buffer.available_frames(), 16);
+
+        //exactly one interval back, then one frame past it
+        assert_eq!(buffer.rewind(4, &mut cpu), Some((4, (1200, 13, 0))));
+        expect_frame(&cpu, 12);
+        assert_eq!(buffer.rewind(5, &mut cpu), Some((8, (400, 5, 0))));
+        expect_frame(&cpu, 4);
+
+        //back across the switch to hires, from a snapshot after frame 12 to the one after frame 8
+        run_frames(&mut buffer, &mut cpu, 5..14);
+        assert_eq!(buffer.rewind(5, &mut cpu), Some((5, (800, 9, 0))));
+        expect_frame(&cpu, 8);
+
+        //more than the history holds stops at the oldest snapshot
+        assert_eq!(buffer.rewind(100, &mut cpu), Some((8, (0, 1, 0))));
+        expect_frame(&cpu, 0);
+        assert_eq!(buffer.available_frames(), 0);
+    }
+
+    #[test]
+    fn history_is_capped() {
+        let mut buffer = RewindBuffer::new(8, 4);
+        let mut cpu = cpu();
+        run_frames(&mut buffer, &mut cpu, 0..17);
+        assert_eq!(buffer.available_frames(), 8);
+        assert_eq!(buffer.rewind(100, &mut cpu), Some((8, (800, 9, 0))));
+        expect_frame(&cpu, 8);
+
+        assert_eq!(RewindBuffer::new(0, 4).rewind(1, &mut cpu), None);
+    }
+
+    #[test]
+    fn a_full_buffer_reaches_back_as_far_as_asked() {
+        for (history, interval) in [(8, 4), (10, 4), (1, 1), (60, 1), (7, 3), (3, 8)] {
+            let mut buffer = RewindBuffer::new(history, interval);
+            let mut cpu = cpu();
+            for frame in 0..200 {
+                buffer.frame_ended(&cpu, (frame, frame + 1, 0));
+                if frame >= (history + 2 * interval) as u64 {
+                    let available = buffer.available_frames();
+                    assert!(available >= history, "{} frames every {}: only {} after frame {}", history, interval, available, frame);
+                    assert!(available < history + 2 * interval);
+                }
+            }
+            let (rewound, _) = buffer.rewind(history, &mut cpu).unwrap();
+            assert!(rewound >= history);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{fmt, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Chip8Config;
+use crate::cpu::CPUState;
+use crate::timer::Timer;
+
+/// Bumped whenever the layout of `SaveState` changes. States with a different version are refused.
+pub const FORMAT_VERSION: u32 = 2;
+
+pub const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+
+/// Feeds `data` into a 64 bit FNV-1a hash. Start from `FNV_OFFSET_BASIS`.
+pub fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01B3);
+    }
+    return hash;
+}
+
+/// Tells which ROM a save state or movie was made for.
+pub fn rom_hash(program: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET_BASIS, program)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaveStateHeader {
+    pub format_version: u32,
+    pub rom_hash: u64,
+    pub config: Chip8Config,
+}
+
+/// Everything `Machine` needs to pick up where it left off, written as JSON.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SaveState {
+    pub header: SaveStateHeader,
+    pub cpu: CPUState,
+    /// `RandomSource::state` of the RND generator
+    pub rng_state: Vec<u8>,
+    pub rng_seed: u64,
+    pub timers: [Timer; 3],
+    pub cycle_count: u64,
+    pub frame_count: u64,
+    pub frame_cycle: u32,
+}
+
+/// Just the header, read before the rest so a state from another version fails with a clear error.
+#[derive(Deserialize)]
+pub(crate) struct HeaderOnly {
+    pub header: SaveStateHeader,
+}
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    /// The data isn't a save state, or is a damaged one
+    Format(serde_json::Error),
+    UnsupportedVersion { found: u32, supported: u32 },
+    /// The state was saved while running a different ROM
+    RomMismatch { expected: u64, found: u64 },
+    /// The saved RND state doesn't fit the generator the saved config asks for
+    BadRngState,
+    /// The state parsed, but describes a machine that can't exist, like a 200 pixel wide display
+    Invalid(String),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::Io(err) => write!(f, "Couldn't read or write save state: {}", err),
+            SaveStateError::Format(err) => write!(f, "Save state is malformed: {}", err),
+            SaveStateError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "Save state format version {} isn't supported (expected {})",
+                found, supported
+            ),
+            SaveStateError::RomMismatch { expected, found } => write!(
+                f,
+                "Save state is for a different ROM (hash {:016X}, loaded ROM is {:016X})",
+                found, expected
+            ),
+            SaveStateError::BadRngState => write!(f, "Save state has an invalid RND generator state"),
+            SaveStateError::Invalid(reason) => write!(f, "Save state is invalid: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+impl From<io::Error> for SaveStateError {
+    fn from(err: io::Error) -> SaveStateError {
+        SaveStateError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SaveStateError {
+    fn from(err: serde_json::Error) -> SaveStateError {
+        if err.is_io() {
+            return SaveStateError::Io(err.into());
+        }
+        SaveStateError::Format(err)
+    }
+}
@@ -0,0 +1,274 @@
+use std::io::{self, Write};
+
+use crate::display::DisplayData;
+
+pub type Rgb = [u8; 3];
+
+/// Colors for pixel color indices 0 to 3. Plain CHIP-8 and SUPER-CHIP only use the first two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [Rgb; 4],
+}
+
+impl Palette {
+    /// `off` for unlit pixels and `on` for lit ones, whichever planes they are lit on.
+    pub fn two_color(off: Rgb, on: Rgb) -> Palette {
+        Palette { colors: [off, on, on, on] }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette { colors: [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF], [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55]] }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenshotOptions {
+    /// Output pixels per display pixel, in each direction
+    pub scale: usize,
+    pub palette: Palette,
+}
+
+impl Default for ScreenshotOptions {
+    fn default() -> ScreenshotOptions {
+        ScreenshotOptions { scale: 1, palette: Palette::default() }
+    }
+}
+
+/// The display's color indices, scaled up, one row at a time.
+fn scaled_rows(disp: &DisplayData, scale: usize) -> impl Iterator<Item = Vec<u8>> + '_ {
+    (0..disp.height * scale).map(move |y| {
+        (0..disp.width * scale).map(|x| disp.get_pixel_color(x / scale, y / scale)).collect()
+    })
+}
+
+/// Binary PBM (P4). Lit pixels are black, as PBM has no palette.
+pub fn write_pbm<W: Write>(disp: &DisplayData, options: &ScreenshotOptions, mut out: W) -> io::Result<()> {
+    let scale = options.scale.max(1);
+    write!(out, "P4\n{} {}\n", disp.width * scale, disp.height * scale)?;
+    for row in scaled_rows(disp, scale) {
+        let packed: Vec<u8> = row
+            .chunks(8)
+            .map(|bits| bits.iter().enumerate().fold(0, |byte, (i, &c)| byte | (((c != 0) as u8) << (7 - i))))
+            .collect();
+        out.write_all(&packed)?;
+    }
+    return Ok(());
+}
+
+/// Indexed-color PNG, compressed with stored (uncompressed) deflate blocks so no zlib is needed.
+pub fn write_png<W: Write>(disp: &DisplayData, options: &ScreenshotOptions, mut out: W) -> io::Result<()> {
+    let scale = options.scale.max(1);
+    let (width, height) = (disp.width * scale, disp.height * scale);
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    //8 bit depth, color type 3 (palette), default compression, filter and no interlace
+    ihdr.extend_from_slice(&[8, 3, 0, 0, 0]);
+
+    let plte: Vec<u8> = options.palette.colors.concat();
+
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in scaled_rows(disp, scale) {
+        //filter type 0 (none) for every row
+        raw.push(0);
+        raw.extend_from_slice(&row);
+    }
+
+    out.write_all(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'])?;
+    write_png_chunk(&mut out, b"IHDR", &ihdr)?;
+    write_png_chunk(&mut out, b"PLTE", &plte)?;
+    write_png_chunk(&mut out, b"IDAT", &zlib_stored(&raw))?;
+    write_png_chunk(&mut out, b"IEND", &[])?;
+    return Ok(());
+}
+
+fn write_png_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(&[&kind[..], data].concat());
+    out.write_all(&crc.to_be_bytes())?;
+    return Ok(());
+}
+
+/// Wraps `data` in a zlib stream of stored deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xFFFF;
+    //deflate with a 32K window, no preset dictionary, header checksum making it a multiple of 31
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(last as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    return out;
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    return !crc;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    return (b << 16) | a;
+}
+
+/// SVG with one rect per horizontal run of same-colored pixels over a background of color 0.
+pub fn write_svg<W: Write>(disp: &DisplayData, options: &ScreenshotOptions, mut out: W) -> io::Result<()> {
+    let scale = options.scale.max(1);
+    let hex = |c: Rgb| format!("#{:02X}{:02X}{:02X}", c[0], c[1], c[2]);
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}" shape-rendering="crispEdges">"#,
+        disp.width * scale, disp.height * scale, disp.width, disp.height
+    )?;
+    writeln!(out, r#"<rect width="{}" height="{}" fill="{}"/>"#, disp.width, disp.height, hex(options.palette.colors[0]))?;
+    for y in 0..disp.height {
+        let mut x = 0;
+        while x < disp.width {
+            let color = disp.get_pixel_color(x, y);
+            let start = x;
+            while x < disp.width && disp.get_pixel_color(x, y) == color {
+                x += 1;
+            }
+            if color != 0 {
+                writeln!(
+                    out,
+                    r#"<rect x="{}" y="{}" width="{}" height="1" fill="{}"/>"#,
+                    start, y, x - start, hex(options.palette.colors[color as usize & 3])
+                )?;
+            }
+        }
+    }
+    writeln!(out, "</svg>")?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Chip8Config;
+
+    #[test]
+    fn checksums_match_known_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(&[0xFF; 6000]), adler32_slow(&[0xFF; 6000]));
+    }
+
+    /// Adler-32 without taking the modulo until the end, to check the one reducing every byte.
+    fn adler32_slow(data: &[u8]) -> u32 {
+        let (mut a, mut b) = (1u64, 0u64);
+        for &byte in data {
+            a += byte as u64;
+            b += a;
+        }
+        return (((b % 65521) << 16) | (a % 65521)) as u32;
+    }
+
+    /// Splits a PNG into its chunks, checking the signature and every CRC.
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n']);
+        let mut rest = &png[8..];
+        let mut chunks = Vec::new();
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = rest[4..8].try_into().unwrap();
+            let data = rest[8..8 + len].to_vec();
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&rest[4..8 + len]), "CRC of {:?}", std::str::from_utf8(&kind));
+            chunks.push((kind, data));
+            rest = &rest[12 + len..];
+        }
+        return chunks;
+    }
+
+    /// Unpacks a zlib stream made of stored blocks, returning the data and the number of blocks.
+    fn inflate_stored(zlib: &[u8]) -> (Vec<u8>, usize) {
+        assert_eq!(zlib[0] & 0x0F, 8, "deflate");
+        assert_eq!((zlib[0] as u16 * 256 + zlib[1] as u16) % 31, 0, "header checksum");
+        let mut rest = &zlib[2..];
+        let mut data = Vec::new();
+        let mut blocks = 0;
+        loop {
+            let header = rest[0];
+            assert_eq!(header >> 1, 0, "stored block");
+            let len = u16::from_le_bytes([rest[1], rest[2]]);
+            let nlen = u16::from_le_bytes([rest[3], rest[4]]);
+            assert_eq!(nlen, !len);
+            data.extend_from_slice(&rest[5..5 + len as usize]);
+            rest = &rest[5 + len as usize..];
+            blocks += 1;
+            if header & 1 != 0 {
+                break;
+            }
+        }
+        assert_eq!(rest, adler32(&data).to_be_bytes());
+        return (data, blocks);
+    }
+
+    #[test]
+    fn large_pngs_split_into_stored_blocks() {
+        let mut disp = DisplayData::new_64x32();
+        disp.set_hires(true);
+        disp.select_planes(3);
+        let config = Chip8Config::default();
+        for i in 0..16 {
+            let sprite = [i as u8 * 17, 0xA5, !(i as u8), 0x3C, 0x81, i as u8, 0xFF, 0x18];
+            disp.draw(&[&sprite[..], &sprite[4..], &sprite[..4]].concat(), i * 8, i * 4, &config);
+        }
+        let scale = 6;
+        let mut png = Vec::new();
+        write_png(&disp, &ScreenshotOptions { scale, palette: Palette::default() }, &mut png).unwrap();
+
+        let chunks = chunks(&png);
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, [b"IHDR", b"PLTE", b"IDAT", b"IEND"]);
+        assert_eq!(&png[png.len() - 4..], &[0xAE, 0x42, 0x60, 0x82]);
+
+        let (width, height) = (128 * scale, 64 * scale);
+        assert_eq!(chunks[0].1[..8], [(width as u32).to_be_bytes(), (height as u32).to_be_bytes()].concat());
+        let (raw, blocks) = inflate_stored(&chunks[2].1);
+        assert!(raw.len() > 0x10000);
+        assert_eq!(blocks, raw.len().div_ceil(0xFFFF));
+        for (y, row) in raw.chunks_exact(width + 1).enumerate() {
+            assert_eq!(row[0], 0, "filter type of row {}", y);
+            for (x, &color) in row[1..].iter().enumerate() {
+                assert_eq!(color, disp.get_pixel_color(x / scale, y / scale), "pixel ({}, {})", x, y);
+            }
+        }
+        assert_eq!(raw.len(), (width + 1) * height);
+    }
+
+    #[test]
+    fn empty_data_still_makes_a_final_block() {
+        assert_eq!(inflate_stored(&zlib_stored(&[])), (Vec::new(), 1));
+        let exact = vec![7; 0xFFFF];
+        assert_eq!(inflate_stored(&zlib_stored(&exact)), (exact, 1));
+    }
+}
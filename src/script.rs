@@ -0,0 +1,259 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::config::Chip8Config;
+
+/// One line of a `chip8-headless` test script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    RunFrames(u64),
+    RunCycles(u64),
+    /// Holds the key down for a number of frames, then lets go
+    PressKeyFor { key: u8, frames: u64 },
+    PressKey(u8),
+    ReleaseKey(u8),
+    AssertPixel { x: usize, y: usize, set: bool },
+    /// Compares the display with a snapshot file, relative to the script
+    AssertDisplay(PathBuf),
+    AssertValue { value: Value, equal: bool, expected: u64 },
+    DumpMemory { start: usize, len: usize },
+}
+
+/// A register or memory byte a script can check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+    Mem(usize),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::V(reg) => write!(f, "V{:X}", reg),
+            Value::I => write!(f, "I"),
+            Value::Pc => write!(f, "PC"),
+            Value::Sp => write!(f, "SP"),
+            Value::Dt => write!(f, "DT"),
+            Value::St => write!(f, "ST"),
+            Value::Mem(addr) => write!(f, "mem[{:#X}]", addr),
+        }
+    }
+}
+
+/// Command line options of `chip8-headless`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub config: Chip8Config,
+    pub update_snapshots: bool,
+    /// Where to log every instruction run, if anywhere
+    pub trace_path: Option<PathBuf>,
+    pub rom_path: PathBuf,
+    pub script_path: PathBuf,
+}
+
+impl Options {
+    /// Parses the arguments after the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut config = Chip8Config::default();
+        let mut update_snapshots = false;
+        let mut trace_path = None;
+        let mut paths = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--profile" => {
+                    let name = args.next().ok_or("--profile needs a name")?;
+                    config.apply_profile(name.parse()?);
+                }
+                "--config" => {
+                    let path = args.next().ok_or("--config needs a file")?;
+                    config = Chip8Config::from_file(&path)?;
+                }
+                "--update-snapshots" => update_snapshots = true,
+                "--trace" => trace_path = Some(PathBuf::from(args.next().ok_or("--trace needs a file")?)),
+                _ => paths.push(PathBuf::from(arg)),
+            }
+        }
+        let [rom_path, script_path]: [PathBuf; 2] = paths.try_into().map_err(|_| "expected a ROM and a script")?;
+        return Ok(Options { config, update_snapshots, trace_path, rom_path, script_path });
+    }
+}
+
+/// Parses a whole script into its steps and their line numbers, counting from 1. Fails with the line number
+/// and error of the first line that doesn't parse.
+pub fn parse_script(script: &str) -> Result<Vec<(usize, Step)>, (usize, String)> {
+    let mut steps = Vec::new();
+    for (line_no, line) in script.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(step)) => steps.push((line_no + 1, step)),
+            Ok(None) => {}
+            Err(err) => return Err((line_no + 1, err)),
+        }
+    }
+    return Ok(steps);
+}
+
+/// Parses one script line. Blank lines and comments give `None`.
+pub fn parse_line(line: &str) -> Result<Option<Step>, String> {
+    let line = line.split('#').next().unwrap_or("").trim();
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let step = match words.as_slice() {
+        [] => return Ok(None),
+        ["run", n, "frames" | "frame"] => Step::RunFrames(parse_num(n)?),
+        ["run", n, "cycles" | "cycle"] => Step::RunCycles(parse_num(n)?),
+        ["press", "key", key, "for", n, "frames" | "frame"] => {
+            Step::PressKeyFor { key: parse_key(key)?, frames: parse_num(n)? }
+        }
+        ["press", "key", key] => Step::PressKey(parse_key(key)?),
+        ["release", "key", key] => Step::ReleaseKey(parse_key(key)?),
+        ["assert", "pixel", rest @ ..] => {
+            //the coordinates may be written with or without spaces after the comma
+            let joined = rest.join("");
+            let (coords, set) = match joined.strip_suffix("set") {
+                Some(coords) => (coords, true),
+                None => (joined.strip_suffix("clear").ok_or("expected 'set' or 'clear'")?, false),
+            };
+            let (x, y) = coords
+                .trim_start_matches('(')
+                .trim_end_matches(')')
+                .split_once(',')
+                .ok_or("expected pixel coordinates like (x,y)")?;
+            Step::AssertPixel { x: parse_num(x)? as usize, y: parse_num(y)? as usize, set }
+        }
+        ["assert", "display", "matches", file] => Step::AssertDisplay(PathBuf::from(file)),
+        ["assert", lhs, op @ ("==" | "!="), rhs] => {
+            Step::AssertValue { value: parse_value(lhs)?, equal: *op == "==", expected: parse_num(rhs)? }
+        }
+        ["dump", "memory", start, len] => {
+            Step::DumpMemory { start: parse_num(start)? as usize, len: parse_num(len)? as usize }
+        }
+        _ => return Err(format!("don't know how to '{}'", line)),
+    };
+    return Ok(Some(step));
+}
+
+fn parse_num(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    return parsed.map_err(|_| format!("expected a number, got '{}'", s));
+}
+
+fn parse_key(s: &str) -> Result<u8, String> {
+    match u8::from_str_radix(s, 16) {
+        Ok(key) if key < 0x10 => Ok(key),
+        _ => Err(format!("expected a key from 0 to F, got '{}'", s)),
+    }
+}
+
+fn parse_value(name: &str) -> Result<Value, String> {
+    let upper = name.to_ascii_uppercase();
+    if let Some(addr) = upper.strip_prefix("MEM[").and_then(|rest| rest.strip_suffix(']')) {
+        return Ok(Value::Mem(parse_num(addr)? as usize));
+    }
+    let value = match upper.as_str() {
+        "I" => Value::I,
+        "PC" => Value::Pc,
+        "SP" => Value::Sp,
+        "DT" => Value::Dt,
+        "ST" => Value::St,
+        reg if reg.len() == 2 && reg.starts_with('V') => Value::V(parse_key(&reg[1..])?),
+        _ => return Err(format!("unknown register '{}'", name)),
+    };
+    return Ok(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{IndexIncrement, QuirkProfile};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn scripts_parse_into_numbered_steps() {
+        let script = "\
+# boot the game
+run 120 frames
+run 1 cycle   # one more
+press key a for 0x3 frames
+
+press key 5
+release key F
+assert pixel (10,4) set
+assert pixel (10, 5) clear
+assert V3 == 0x20
+assert i != 0
+assert mem[0x300] == 7
+assert display matches snapshots/title.txt
+dump memory 0x200 32
+";
+        let steps = parse_script(script).unwrap();
+        assert_eq!(steps, [
+            (2, Step::RunFrames(120)),
+            (3, Step::RunCycles(1)),
+            (4, Step::PressKeyFor { key: 0xA, frames: 3 }),
+            (6, Step::PressKey(5)),
+            (7, Step::ReleaseKey(0xF)),
+            (8, Step::AssertPixel { x: 10, y: 4, set: true }),
+            (9, Step::AssertPixel { x: 10, y: 5, set: false }),
+            (10, Step::AssertValue { value: Value::V(3), equal: true, expected: 0x20 }),
+            (11, Step::AssertValue { value: Value::I, equal: false, expected: 0 }),
+            (12, Step::AssertValue { value: Value::Mem(0x300), equal: true, expected: 7 }),
+            (13, Step::AssertDisplay(PathBuf::from("snapshots/title.txt"))),
+            (14, Step::DumpMemory { start: 0x200, len: 32 }),
+        ]);
+        assert_eq!(Value::V(0xA).to_string(), "VA");
+        assert_eq!(Value::Mem(0x300).to_string(), "mem[0x300]");
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        for line in [
+            "run frames",
+            "run 10 seconds",
+            "run -1 frames",
+            "press key 10",
+            "press key G for 2 frames",
+            "release",
+            "assert pixel (10,4) lit",
+            "assert pixel (10) set",
+            "assert VG == 1",
+            "assert V3 = 1",
+            "assert V3 == twenty",
+            "assert mem[x] == 1",
+            "dump memory 0x200",
+            "jump 0x200",
+        ] {
+            assert!(parse_line(line).is_err(), "{:?}", line);
+        }
+        let (line_no, _) = parse_script("run 1 frame\n\nrun 1 fortnight\n").unwrap_err();
+        assert_eq!(line_no, 3);
+    }
+
+    #[test]
+    fn options_parse() {
+        let line = "--profile chip48 --trace out.log --update-snapshots game.ch8 test.txt";
+        let options = Options::parse(args(line)).unwrap();
+        assert_eq!(options.config, QuirkProfile::Chip48.config());
+        assert_eq!(options.config.load_store_increment, IndexIncrement::ByX);
+        assert!(options.update_snapshots);
+        assert_eq!(options.trace_path, Some(PathBuf::from("out.log")));
+        assert_eq!((options.rom_path, options.script_path), (PathBuf::from("game.ch8"), PathBuf::from("test.txt")));
+
+        let options = Options::parse(args("game.ch8 test.txt")).unwrap();
+        assert_eq!((options.trace_path, options.update_snapshots), (None, false));
+
+        for line in ["game.ch8", "a b c", "--trace", "game.ch8 test.txt --trace", "--profile gameboy a b"] {
+            assert!(Options::parse(args(line)).is_err(), "{:?}", line);
+        }
+    }
+}
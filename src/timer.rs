@@ -1,8 +1,12 @@
 use std::time::{Duration, Instant};
 
+use serde::{Deserialize, Serialize};
 
+/// Only the phase (`curr_t`) and period are saved, a restored timer starts counting from when it was loaded.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Timer {
     curr_t: Duration,
+    #[serde(skip, default = "Instant::now")]
     last_run: Instant,
     trigger_dur: Duration
 }
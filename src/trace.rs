@@ -0,0 +1,302 @@
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use crate::cpu::{CPUState, HaltStatus};
+use crate::decode::OpcodePattern;
+
+/// Layout of a trace line, as a template with `{field}` or `{field:width}` placeholders:
+///
+/// - `cycle`: the cycle the instruction started on
+/// - `pc`, `opcode`: 4 hex digits each
+/// - `asm`: the disassembled instruction
+/// - `regs`, `regs_after`: V0-VF and I before and after the instruction, like `V0=00 ... VF=00 I=0000`
+/// - `changes`: just the registers that changed, like `V3=20 I=0310`
+///
+/// A width pads the field with spaces to line up the columns after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFormat {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Cycle,
+    Pc,
+    Opcode,
+    Asm,
+    Regs,
+    RegsAfter,
+    Changes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Field(Field, usize),
+}
+
+impl TraceFormat {
+    pub fn new(template: &str) -> Result<TraceFormat, String> {
+        let mut pieces = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                pieces.push(Piece::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| format!("Unclosed '{{' in trace format '{}'", template))?;
+            let placeholder = &rest[start + 1..start + end];
+            let (name, width) = placeholder.split_once(':').unwrap_or((placeholder, "0"));
+            let width = width.parse().map_err(|_| format!("Bad width in trace format placeholder '{{{}}}'", placeholder))?;
+            let field = match name {
+                "cycle" => Field::Cycle,
+                "pc" => Field::Pc,
+                "opcode" => Field::Opcode,
+                "asm" => Field::Asm,
+                "regs" => Field::Regs,
+                "regs_after" => Field::RegsAfter,
+                "changes" => Field::Changes,
+                _ => return Err(format!("Unknown trace format field '{}'", name)),
+            };
+            pieces.push(Piece::Field(field, width));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Text(rest.to_string()));
+        }
+        return Ok(TraceFormat { pieces });
+    }
+}
+
+impl Default for TraceFormat {
+    fn default() -> TraceFormat {
+        TraceFormat::new("{cycle:8} {pc} {opcode} {asm:14} {regs} -> {regs_after}").unwrap()
+    }
+}
+
+/// Which instructions get traced. Empty lists don't filter, so the default traces everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Only trace instructions at these addresses
+    pub addresses: Vec<RangeInclusive<u16>>,
+    /// Only trace opcodes matching one of these, e.g. "Dxyn" for every DRW
+    pub opcodes: Vec<OpcodePattern>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: u16, opcode: u16) -> bool {
+        (self.addresses.is_empty() || self.addresses.iter().any(|range| range.contains(&pc)))
+            && (self.opcodes.is_empty() || self.opcodes.iter().any(|pattern| pattern.matches(opcode)))
+    }
+}
+
+/// V0-VF then I.
+type Registers = ([u8; 16], u16);
+
+/// An instruction that started but hasn't finished yet, e.g. a DRW waiting for vblank.
+struct Pending {
+    cycle: u64,
+    pc: u16,
+    opcode: u16,
+    asm: String,
+    regs: Registers,
+}
+
+/// Writes a line per executed instruction to a sink. Attach one with `Machine::start_trace`.
+pub struct Tracer {
+    sink: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    cycle: u64,
+    pending: Option<Pending>,
+    /// The first write that failed. Tracing stops after it.
+    error: Option<io::Error>,
+}
+
+fn fmt_regs((v, i): &Registers) -> String {
+    let mut s = String::new();
+    for (reg, value) in v.iter().enumerate() {
+        s += &format!("V{:X}={:02X} ", reg, value);
+    }
+    s += &format!("I={:04X}", i);
+    return s;
+}
+
+fn fmt_changes((v, i): &Registers, (v_after, i_after): &Registers) -> String {
+    let mut changes: Vec<String> = (0..16)
+        .filter(|&reg| v[reg] != v_after[reg])
+        .map(|reg| format!("V{:X}={:02X}", reg, v_after[reg]))
+        .collect();
+    if i != i_after {
+        changes.push(format!("I={:04X}", i_after));
+    }
+    return changes.join(" ");
+}
+
+impl Tracer {
+    pub fn new(sink: Box<dyn Write>, format: TraceFormat, filter: TraceFilter) -> Tracer {
+        Tracer { sink, format, filter, cycle: 0, pending: None, error: None }
+    }
+
+    pub(crate) fn set_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+
+    /// Called before each cycle. Remembers the instruction if one is starting and it passes the filter.
+    pub(crate) fn before_cycle(&mut self, cpu: &CPUState) {
+        let cycle = self.cycle;
+        self.cycle += 1;
+        if self.error.is_some() || cpu.halt_status != HaltStatus::NotHalted {
+            return;
+        }
+        self.pending = None;
+        let opcode = cpu.get_opcode();
+        if !self.filter.matches(cpu.pc, opcode) {
+            return;
+        }
+        let asm = match cpu.fetch() {
+            Ok(instr) => instr.to_string(),
+            Err(_) => "UNK".to_string(),
+        };
+        self.pending = Some(Pending { cycle, pc: cpu.pc, opcode, asm, regs: (cpu.v, cpu.i) });
+    }
+
+    /// Called after each cycle that didn't fault. Writes the line once the instruction has finished.
+    pub(crate) fn after_cycle(&mut self, cpu: &CPUState) {
+        if !matches!(cpu.halt_status, HaltStatus::NotHalted | HaltStatus::Exited) {
+            return;
+        }
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let after = (cpu.v, cpu.i);
+        let mut line = String::new();
+        for piece in &self.format.pieces {
+            let (field, width) = match piece {
+                Piece::Text(text) => {
+                    line += text;
+                    continue;
+                }
+                Piece::Field(field, width) => (*field, *width),
+            };
+            let value = match field {
+                Field::Cycle => pending.cycle.to_string(),
+                Field::Pc => format!("{:04X}", pending.pc),
+                Field::Opcode => format!("{:04X}", pending.opcode),
+                Field::Asm => pending.asm.clone(),
+                Field::Regs => fmt_regs(&pending.regs),
+                Field::RegsAfter => fmt_regs(&after),
+                Field::Changes => fmt_changes(&pending.regs, &after),
+            };
+            line += &format!("{:width$}", value, width = width);
+        }
+        if let Err(err) = writeln!(self.sink, "{}", line.trim_end()) {
+            self.error = Some(err);
+        }
+    }
+
+    /// A faulting instruction never finishes, so it isn't traced.
+    pub(crate) fn fault(&mut self) {
+        self.pending = None;
+    }
+
+    /// Flushes the sink, returning the first error writing to it.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        return self.sink.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::config::Chip8Config;
+    use crate::machine::Machine;
+
+    /// A sink the test can read back after the tracer has been handed to the machine.
+    #[derive(Clone, Default)]
+    struct SharedSink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs `cycles` cycles of `program` with a tracer and returns the trace lines.
+    fn trace(program: &[u8], config: Chip8Config, format: &str, filter: TraceFilter, cycles: u64) -> Vec<String> {
+        let sink = SharedSink::default();
+        let mut machine = Machine::new(program, config).unwrap();
+        machine.start_trace(Tracer::new(Box::new(sink.clone()), TraceFormat::new(format).unwrap(), filter));
+        machine.run_cycles(cycles).unwrap();
+        machine.stop_trace().unwrap();
+        let text = String::from_utf8(sink.0.borrow().clone()).unwrap();
+        return text.lines().map(String::from).collect();
+    }
+
+    #[test]
+    fn formats_parse_fields_widths_and_text() {
+        let format = TraceFormat::new("at {pc}: {asm:12}|{changes}").unwrap();
+        assert_eq!(format.pieces, [
+            Piece::Text("at ".to_string()),
+            Piece::Field(Field::Pc, 0),
+            Piece::Text(": ".to_string()),
+            Piece::Field(Field::Asm, 12),
+            Piece::Text("|".to_string()),
+            Piece::Field(Field::Changes, 0),
+        ]);
+        assert_eq!(TraceFormat::new("").unwrap().pieces, []);
+        assert_eq!(TraceFormat::new("{cycle}").unwrap().pieces, [Piece::Field(Field::Cycle, 0)]);
+    }
+
+    #[test]
+    fn bad_formats_are_errors() {
+        let err = TraceFormat::new("{pc} {asm").unwrap_err();
+        assert!(err.contains("Unclosed"), "{}", err);
+        let err = TraceFormat::new("{pc} {flags}").unwrap_err();
+        assert!(err.contains("Unknown trace format field 'flags'"), "{}", err);
+        let err = TraceFormat::new("{asm:wide}").unwrap_err();
+        assert!(err.contains("Bad width"), "{}", err);
+    }
+
+    #[test]
+    fn lines_follow_the_format() {
+        //200: LD V3 20, 202: LD I 310, 204: JP 204
+        let program = [0x63, 0x20, 0xA3, 0x10, 0x12, 0x04];
+        let format = "{cycle} {pc} {opcode} {asm:10}|{changes}";
+        let lines = trace(&program, Chip8Config::default(), format, TraceFilter::default(), 3);
+        assert_eq!(lines, [
+            "0 0200 6320 LD V3 20  |V3=20",
+            "1 0202 A310 LD I 310  |I=0310",
+            "2 0204 1204 JP 204    |",
+        ]);
+
+        let opcodes = vec![OpcodePattern::parse("1nnn").unwrap()];
+        let filter = TraceFilter { addresses: vec![0x202..=0x204], opcodes };
+        assert_eq!(trace(&program, Chip8Config::default(), "{pc}", filter, 3), ["0204"]);
+    }
+
+    #[test]
+    fn a_draw_waiting_for_vblank_is_one_line() {
+        //200: DRW V0 V0 1, 202: LD V1 1
+        let program = [0xD0, 0x01, 0x61, 0x01];
+        let config = Chip8Config {
+            emulate_draw_vblank_delay: true,
+            instructions_per_frame: 4,
+            ..Chip8Config::default()
+        };
+        let lines = trace(&program, config, "{cycle} {pc} {asm}", TraceFilter::default(), 6);
+        //the DRW starts on cycle 0 and finishes on cycle 4, after the frame ends
+        assert_eq!(lines, ["0 0200 DRW V0 V0 1", "5 0202 LD V1 1"]);
+    }
+}
@@ -0,0 +1,35 @@
+use emu_chip8_core::{
+    breakpoints::BreakpointKind,
+    config::QuirkProfile,
+    machine::{FaultAction, FaultPolicy, Machine},
+};
+
+/// 200: LD I FFFF, 204: LD V0-V2 [I] (reads past the end of memory), 206: JP 204
+const ROM: [u8; 8] = [0xF0, 0x00, 0xFF, 0xFF, 0xF2, 0x65, 0x12, 0x04];
+
+fn machine() -> Machine {
+    return Machine::new(&ROM, QuirkProfile::XoChip.config()).unwrap();
+}
+
+#[test]
+fn skipping_a_fault_clears_the_breakpoint_resume() {
+    let mut machine = machine();
+    machine.set_fault_policy(FaultPolicy::Skip);
+    machine.breakpoints_mut().add(BreakpointKind::Pc(0x204), None).unwrap();
+    assert_eq!(machine.run_cycles(10).unwrap().map(|hit| hit.pc), Some(0x204));
+    //the skipped instruction counts as run, so the breakpoint hits again on the next pass
+    assert_eq!(machine.run_cycles(10).unwrap().map(|hit| hit.pc), Some(0x204));
+    assert_eq!(machine.cycle_count(), 3);
+}
+
+#[test]
+fn skipping_steps_over_the_whole_instruction() {
+    let mut machine = machine();
+    //move back onto the 4 byte LD I before skipping
+    machine.set_fault_policy(FaultPolicy::Trap(Box::new(|_, cpu| {
+        cpu.pc = 0x200;
+        return FaultAction::Skip;
+    })));
+    machine.run_cycles(2).unwrap();
+    assert_eq!(machine.cpu_state().pc, 0x204);
+}
@@ -0,0 +1,92 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use emu_chip8_core::{config::QuirkProfile, gdb::GdbStub, machine::Machine};
+
+/// 200: LD I 300, 202: LD V0 5, 204: CALL 210, 206: ADD V1 1, 208: JP 204, 210: RET
+const ROM: [u8; 18] = [0xA3, 0x00, 0x60, 0x05, 0x22, 0x10, 0x71, 0x01, 0x12, 0x04, 0, 0, 0, 0, 0, 0, 0x00, 0xEE];
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte).unwrap();
+        return byte[0];
+    }
+
+    /// Sends a packet and returns the stub's reply, acking both ways.
+    fn send(&mut self, data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+        assert_eq!(self.read_byte(), b'+');
+        assert_eq!(self.read_byte(), b'$');
+        let mut reply = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => reply.push(byte),
+            }
+        }
+        let checksum = [self.read_byte(), self.read_byte()];
+        let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+        assert_eq!(checksum, reply.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+        self.stream.write_all(b"+").unwrap();
+        return String::from_utf8(reply).unwrap();
+    }
+}
+
+fn connect() -> (Client, thread::JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let machine = Machine::new(&ROM, QuirkProfile::Modern.config()).unwrap();
+        let mut stub = GdbStub::new(machine);
+        stub.set_realtime(false);
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        stub.serve(stream.try_clone().unwrap(), stream).unwrap();
+        return stub.machine().cpu_state().mem.read_range(0x300, 2).unwrap().to_vec();
+    });
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_nodelay(true).unwrap();
+    return (Client { stream }, server);
+}
+
+#[test]
+fn scripted_session() {
+    let (mut client, server) = connect();
+
+    assert!(client.send("qSupported:swbreak+").contains("swbreak+"));
+    assert!(client.send("qXfer:features:read:target.xml:0,1000").contains("<reg name=\"pc\" bitsize=\"16\""));
+
+    //V0-VF, then I and PC big-endian, then SP, DT and ST
+    let regs = client.send("g");
+    assert_eq!(regs, format!("{}00000200000000", "00".repeat(16)));
+    let new_regs = format!("0a{}12340200000000", "00".repeat(15));
+    assert_eq!(client.send(&format!("G{}", new_regs)), "OK");
+    assert_eq!(client.send("g"), new_regs);
+    assert_eq!(client.send("p10"), "1234");
+
+    assert_eq!(client.send("m200,4"), "a3006005");
+    assert_eq!(client.send("M300,2:beef"), "OK");
+    assert_eq!(client.send("m300,2"), "beef");
+    assert_eq!(client.send("mfffffffffffffff0,20"), "E01");
+    assert_eq!(client.send("m0,100000"), "E01");
+    assert_eq!(client.send("M10000,1:00"), "E01");
+
+    assert_eq!(client.send("Z0,210,2"), "OK");
+    assert_eq!(client.send("c"), "T05swbreak:;");
+    assert_eq!(client.send("p11"), "0210");
+    assert_eq!(client.send("z0,210,2"), "OK");
+
+    assert_eq!(client.send("s"), "S05");
+    assert_eq!(client.send("p11"), "0206");
+
+    write!(client.stream, "$k#6b").unwrap();
+    assert_eq!(client.read_byte(), b'+');
+    assert_eq!(server.join().unwrap(), vec![0xBE, 0xEF]);
+}
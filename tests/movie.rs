@@ -0,0 +1,135 @@
+use emu_chip8_core::{
+    config::QuirkProfile,
+    cpu::HaltStatus,
+    keyboard::Fx0AStatus,
+    machine::Machine,
+    movie::{self, Movie, MovieError, MovieEvent},
+};
+
+/// 200: LD V0 5, 202: SKNP V0, 204: ADD V1 1, 206: JP 202
+const ROM: [u8; 8] = [0x60, 0x05, 0xE0, 0xA1, 0x71, 0x01, 0x12, 0x02];
+
+fn machine() -> Machine {
+    let mut config = QuirkProfile::Modern.config();
+    config.rng_seed = Some(1);
+    config.rewind_frames = 300;
+    config.rewind_interval = 1;
+    return Machine::new(&ROM, config).unwrap();
+}
+
+fn step_frames(machine: &mut Machine, frames: u64) {
+    for _ in 0..frames {
+        machine.step_frame().unwrap();
+    }
+}
+
+#[test]
+fn rewind_while_recording_rerecords_held_keys() {
+    let mut machine = machine();
+    machine.start_recording().unwrap();
+    step_frames(&mut machine, 2);
+    machine.press_key(3);
+    step_frames(&mut machine, 6);
+    machine.press_key(5);
+    step_frames(&mut machine, 2);
+
+    //back before key 5 was pressed, with both keys still held
+    assert!(machine.rewind(4) >= 4);
+    let cycle = machine.cycle_count();
+    step_frames(&mut machine, 1);
+    let movie = machine.stop_recording().unwrap();
+    assert_eq!(movie.events.len(), 2);
+    assert!(movie.events[0].cycle < cycle && movie.events[0].key == 3 && movie.events[0].pressed);
+    assert_eq!(movie.events[1], MovieEvent { cycle, key: 5, pressed: true });
+    assert_eq!(movie.keys_before(cycle + 1), {
+        let mut keys = [false; 16];
+        keys[3] = true;
+        keys[5] = true;
+        keys
+    });
+}
+
+#[test]
+fn rewind_during_playback_follows_the_movie() {
+    let mut recorder = machine();
+    recorder.start_recording().unwrap();
+    step_frames(&mut recorder, 30);
+    recorder.press_key(5);
+    step_frames(&mut recorder, 60);
+    recorder.release_key(5);
+    step_frames(&mut recorder, 60);
+    let movie = recorder.stop_recording().unwrap();
+    assert!(movie.checkpoints.len() >= 2);
+
+    let mut player = Machine::play_movie(&ROM, movie).unwrap();
+    step_frames(&mut player, 100);
+    assert!(!player.cpu_state().kbstate.key[5]);
+    //back into the stretch where the movie holds key 5
+    assert!(player.rewind(40) >= 40);
+    assert!(player.cpu_state().kbstate.key[5]);
+    let remaining = 150 - player.frame_count();
+    step_frames(&mut player, remaining);
+    assert!(player.movie_finished());
+    assert_eq!(player.movie_desync(), None);
+    assert_eq!(player.cpu_state().v[1], recorder.cpu_state().v[1]);
+}
+
+#[test]
+fn loading_a_slot_restores_counters_and_ends_playback() {
+    let mut recorder = machine();
+    recorder.start_recording().unwrap();
+    recorder.press_key(5);
+    step_frames(&mut recorder, 10);
+    let movie = recorder.stop_recording().unwrap();
+
+    let mut player = Machine::play_movie(&ROM, movie).unwrap();
+    step_frames(&mut player, 3);
+    player.save_current_state(0).unwrap();
+    let (cycle, frame) = (player.cycle_count(), player.frame_count());
+    step_frames(&mut player, 4);
+    player.load_state(0).unwrap();
+    assert_eq!((player.cycle_count(), player.frame_count()), (cycle, frame));
+    //with playback over, keys pressed directly go through
+    player.press_key(3);
+    assert!(player.cpu_state().kbstate.key[3]);
+}
+
+#[test]
+fn movies_with_keys_past_f_are_refused() {
+    let mut recorder = machine();
+    recorder.start_recording().unwrap();
+    recorder.press_key(0xF);
+    step_frames(&mut recorder, 1);
+    let mut movie = recorder.stop_recording().unwrap();
+    let mut json = Vec::new();
+    movie.write_to(&mut json).unwrap();
+    assert!(Movie::read_from(json.as_slice()).is_ok());
+
+    movie.events.push(MovieEvent { cycle: 20, key: 16, pressed: true });
+    let mut json = Vec::new();
+    movie.write_to(&mut json).unwrap();
+    assert!(matches!(Movie::read_from(json.as_slice()), Err(MovieError::BadKey { cycle: 20, key: 16 })));
+    assert!(matches!(Machine::play_movie(&ROM, movie), Err(MovieError::BadKey { .. })));
+}
+
+#[test]
+fn state_hash_covers_the_whole_cpu() {
+    let machine = machine();
+    let base = movie::state_hash(machine.cpu_state());
+    let changes: [fn(&mut Machine); 8] = [
+        |m| m.cpu_state_mut().stack.push(0x202),
+        |m| m.cpu_state_mut().rpl[3] = 1,
+        |m| m.cpu_state_mut().audio_pattern[0] = 0x80,
+        |m| m.cpu_state_mut().pitch = 10,
+        |m| m.cpu_state_mut().kbstate.key[2] = true,
+        |m| m.cpu_state_mut().kbstate.Fx0A = Fx0AStatus::WaitingForPress,
+        |m| m.cpu_state_mut().disp.select_planes(3),
+        |m| m.cpu_state_mut().halt_status = HaltStatus::WaitingVblank,
+    ];
+    for (i, change) in changes.iter().enumerate() {
+        let mut changed = self::machine();
+        change(&mut changed);
+        assert_ne!(movie::state_hash(changed.cpu_state()), base, "change {}", i);
+    }
+    assert_eq!(movie::state_hash(self::machine().cpu_state()), base);
+}
@@ -0,0 +1,53 @@
+use emu_chip8_core::{config::QuirkProfile, machine::Machine, savestate::SaveStateError};
+
+/// 200: ADD V1 1, 202: JP 200
+const ROM: [u8; 4] = [0x71, 0x01, 0x12, 0x00];
+
+fn saved(machine: &Machine) -> serde_json::Value {
+    let mut buf = Vec::new();
+    machine.save_state_to(&mut buf).unwrap();
+    return serde_json::from_slice(&buf).unwrap();
+}
+
+fn load(machine: &mut Machine, state: &serde_json::Value) -> Result<(), SaveStateError> {
+    machine.load_state_from(serde_json::to_vec(state).unwrap().as_slice())
+}
+
+#[test]
+fn impossible_states_are_refused() {
+    let mut machine = Machine::new(&ROM, QuirkProfile::Modern.config()).unwrap();
+    machine.step_frame().unwrap();
+    let state = saved(&machine);
+
+    let mut wide = state.clone();
+    wide["cpu"]["disp"]["width"] = 200.into();
+    assert!(matches!(load(&mut machine, &wide), Err(SaveStateError::Invalid(_))));
+
+    let mut short_plane = state.clone();
+    short_plane["cpu"]["disp"]["planes"][1].as_array_mut().unwrap().pop();
+    assert!(matches!(load(&mut machine, &short_plane), Err(SaveStateError::Invalid(_))));
+
+    let mut small_mem = state.clone();
+    small_mem["cpu"]["mem"]["mem"].as_array_mut().unwrap().truncate(0x800);
+    assert!(matches!(load(&mut machine, &small_mem), Err(SaveStateError::Invalid(_))));
+
+    load(&mut machine, &state).unwrap();
+}
+
+#[test]
+fn loading_rebuilds_the_rewind_history_from_the_saved_config() {
+    let mut config = QuirkProfile::Modern.config();
+    config.rewind_frames = 10;
+    config.rewind_interval = 1;
+    let mut with_rewind = Machine::new(&ROM, config).unwrap();
+    let state = saved(&with_rewind);
+
+    let mut machine = Machine::new(&ROM, QuirkProfile::Modern.config()).unwrap();
+    load(&mut machine, &state).unwrap();
+    for _ in 0..5 {
+        machine.step_frame().unwrap();
+        with_rewind.step_frame().unwrap();
+    }
+    assert_eq!(machine.rewind_available(), with_rewind.rewind_available());
+    assert!(machine.rewind(2) >= 2);
+}